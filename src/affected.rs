@@ -0,0 +1,75 @@
+use std::path::{Path, PathBuf};
+
+use crate::content::HashIgnore;
+use crate::manifest::LoadOptions;
+use crate::types::*;
+use crate::vcs::Vcs;
+
+/// Dirs of components containing any path changed since `since`.
+pub fn changed_components(
+    path: &Path,
+    load: &LoadOptions,
+    since: &str,
+    merge_base: bool,
+) -> anyhow::Result<Vec<String>> {
+    changed_components_between(path, load, since, "HEAD", merge_base)
+}
+
+/// Dirs of components containing any path changed from `base` to `head`.
+pub fn changed_components_between(
+    path: &Path,
+    load: &LoadOptions,
+    base: &str,
+    head: &str,
+    merge_base: bool,
+) -> anyhow::Result<Vec<String>> {
+    let vcs = Vcs::discover(path)?;
+    let changed = vcs.changed_paths(base, head, merge_base)?;
+    Ok(components_for_paths(
+        path,
+        &load.load_components(path)?,
+        &changed,
+    ))
+}
+
+/// Components to act on, topologically sorted: the transitive dependents of
+/// `roots` and of anything changed since `since`, or everything when neither
+/// is given.
+pub fn affected_components(
+    path: &Path,
+    load: &LoadOptions,
+    roots: &[&str],
+    since: Option<&str>,
+    merge_base: bool,
+) -> anyhow::Result<Vec<Component>> {
+    let components = load.load_components(path)?;
+    if roots.is_empty() && since.is_none() {
+        return Ok(toposort_components(components)?);
+    }
+    let mut dirs: Vec<String> = roots.iter().map(|r| (*r).to_owned()).collect();
+    if let Some(since) = since {
+        dirs.extend(changed_components(path, load, since, merge_base)?);
+    }
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+    Ok(transitive_dependents(components, &dirs, true)?)
+}
+
+fn components_for_paths(path: &Path, components: &[Component], changed: &[PathBuf]) -> Vec<String> {
+    components
+        .iter()
+        .filter(|c| {
+            let dir = path.join(&c.dir);
+            let ignored = HashIgnore::new(&c.ignore);
+            let extra: Vec<PathBuf> = c.external_paths().iter().map(|e| path.join(e)).collect();
+            // the dir.starts_with cases cover submodules, which change as a
+            // single path
+            changed.iter().any(|p| match p.strip_prefix(&dir) {
+                Ok(rel) => !ignored.ignores(&path_to_slash(rel)),
+                Err(_) => {
+                    dir.starts_with(p) || extra.iter().any(|e| p.starts_with(e) || e.starts_with(p))
+                }
+            })
+        })
+        .map(|c| c.dir.clone())
+        .collect()
+}
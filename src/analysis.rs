@@ -0,0 +1,255 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::manifest::LoadOptions;
+use crate::types::*;
+
+pub const RANK_BY: &[&str] = &["dependents", "dependencies"];
+
+/// Print components by the size of their transitive dependent (or
+/// dependency) set, largest first: the rebuild blast radius of each.
+pub fn run_rank(path: &Path, load: &LoadOptions, by: &str) -> anyhow::Result<()> {
+    let components = load.load_components(path)?;
+    let closures = match by {
+        "dependents" => dependent_closures(&components)?,
+        "dependencies" => dependency_closures(&components)?,
+        _ => panic!("unknown ranking {}", by),
+    };
+    let mut ranked: Vec<(usize, &str)> = components
+        .iter()
+        .map(|c| (closures[&c.dir].len(), c.dir.as_str()))
+        .collect();
+    ranked.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    for (count, dir) in ranked {
+        println!("{}\t{}", count, dir);
+    }
+    Ok(())
+}
+
+/// Print the dependencies of `root` reachable along at least `min_paths`
+/// distinct dependency paths, most paths first. Counts saturate rather
+/// than overflow on very dense graphs.
+pub fn run_diamonds(
+    path: &Path,
+    load: &LoadOptions,
+    root: &str,
+    min_paths: u64,
+) -> anyhow::Result<()> {
+    let mut sorted = toposort_components(load.load_components(path)?)?;
+    if !sorted.iter().any(|c| c.dir == root) {
+        return Err(CustomError::MissingComponentError(vec![root.to_owned()]).into());
+    }
+    // dependents come before their dependencies when reversed
+    sorted.reverse();
+    let mut paths: HashMap<&str, u64> = HashMap::new();
+    paths.insert(root, 1);
+    for comp in sorted.iter() {
+        let n = match paths.get(comp.dir.as_str()) {
+            Some(n) => *n,
+            None => continue,
+        };
+        for dep in comp.dependencies.iter() {
+            let count = paths.entry(dep.as_str()).or_insert(0);
+            *count = count.saturating_add(n);
+        }
+    }
+    let mut report: Vec<(u64, &str)> = paths
+        .into_iter()
+        .filter(|(dir, n)| *dir != root && *n >= min_paths)
+        .map(|(dir, n)| (n, dir))
+        .collect();
+    report.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    for (count, dir) in report {
+        println!("{}\t{}", count, dir);
+    }
+    Ok(())
+}
+
+/// Print every group of components that depend on each other in a cycle,
+/// with the dependency edges inside the group, rather than stopping at the
+/// first cycle like `toposort` does. Prints nothing for an acyclic graph.
+pub fn run_analyze_cycles(path: &Path, load: &LoadOptions) -> anyhow::Result<()> {
+    let components = load.load_components(path)?;
+    let index: HashMap<&str, usize> = components
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.dir.as_str(), i))
+        .collect();
+    // dependencies on missing components can't be part of a cycle
+    let edges: Vec<Vec<usize>> = components
+        .iter()
+        .map(|c| {
+            let mut deps: Vec<usize> = c
+                .dependencies
+                .iter()
+                .filter_map(|d| index.get(d.as_str()).copied())
+                .collect();
+            deps.sort_unstable();
+            deps.dedup();
+            deps
+        })
+        .collect();
+    let mut groups: Vec<Vec<usize>> = strongly_connected(&edges)
+        .into_iter()
+        .filter(|g| g.len() > 1 || edges[g[0]].contains(&g[0]))
+        .collect();
+    for group in groups.iter_mut() {
+        group.sort_by(|a, b| components[*a].dir.cmp(&components[*b].dir));
+    }
+    groups.sort_by(|a, b| components[a[0]].dir.cmp(&components[b[0]].dir));
+    for (n, group) in groups.iter().enumerate() {
+        let members: Vec<&str> = group.iter().map(|i| components[*i].dir.as_str()).collect();
+        println!("cycle {}: {}", n + 1, members.join(" "));
+        for from in group.iter() {
+            let mut targets: Vec<&str> = edges[*from]
+                .iter()
+                .filter(|to| group.contains(to))
+                .map(|to| components[*to].dir.as_str())
+                .collect();
+            targets.sort_unstable();
+            for to in targets {
+                println!("  {} -> {}", components[*from].dir, to);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Tarjan's algorithm over an adjacency list, returning each strongly
+/// connected component as a list of node indices.
+fn strongly_connected(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    struct State<'a> {
+        edges: &'a [Vec<usize>],
+        next: usize,
+        index: Vec<Option<usize>>,
+        lowlink: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        groups: Vec<Vec<usize>>,
+    }
+
+    fn visit(s: &mut State, v: usize) {
+        s.index[v] = Some(s.next);
+        s.lowlink[v] = s.next;
+        s.next += 1;
+        s.stack.push(v);
+        s.on_stack[v] = true;
+        for &w in s.edges[v].iter() {
+            match s.index[w] {
+                None => {
+                    visit(s, w);
+                    s.lowlink[v] = s.lowlink[v].min(s.lowlink[w]);
+                }
+                Some(i) if s.on_stack[w] => s.lowlink[v] = s.lowlink[v].min(i),
+                Some(_) => (),
+            }
+        }
+        if Some(s.lowlink[v]) == s.index[v] {
+            let mut group = Vec::new();
+            while let Some(w) = s.stack.pop() {
+                s.on_stack[w] = false;
+                group.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            s.groups.push(group);
+        }
+    }
+
+    let mut state = State {
+        edges,
+        next: 0,
+        index: vec![None; edges.len()],
+        lowlink: vec![0; edges.len()],
+        on_stack: vec![false; edges.len()],
+        stack: Vec::new(),
+        groups: Vec::new(),
+    };
+    for v in 0..edges.len() {
+        if state.index[v].is_none() {
+            visit(&mut state, v);
+        }
+    }
+    state.groups
+}
+
+/// Print every dependency path from `from` down to `to`, one per line,
+/// stopping after `limit` paths if given.
+pub fn run_paths(
+    path: &Path,
+    load: &LoadOptions,
+    from: &str,
+    to: &str,
+    limit: Option<usize>,
+) -> anyhow::Result<()> {
+    let components = load.load_components(path)?;
+    let deps: HashMap<&str, &Vec<String>> = components
+        .iter()
+        .map(|c| (c.dir.as_str(), &c.dependencies))
+        .collect();
+    let missing: Vec<String> = [from, to]
+        .iter()
+        .filter(|d| !deps.contains_key(*d))
+        .map(|d| (*d).to_owned())
+        .collect();
+    if !missing.is_empty() {
+        return Err(CustomError::MissingComponentError(missing).into());
+    }
+    // only step to components that lead to `to`, so every branch taken
+    // ends in a path rather than exploring the whole graph below `from`
+    let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+    for comp in components.iter() {
+        for dep in comp.dependencies.iter() {
+            dependents.entry(dep).or_default().push(&comp.dir);
+        }
+    }
+    let mut reaches: HashSet<&str> = HashSet::new();
+    let mut queue = vec![to];
+    while let Some(dir) = queue.pop() {
+        if reaches.insert(dir) {
+            queue.extend(dependents.get(dir).into_iter().flatten());
+        }
+    }
+    let mut route = vec![from];
+    let mut remaining = limit.unwrap_or(usize::MAX);
+    print_paths(&deps, &reaches, to, &mut route, &mut remaining);
+    Ok(())
+}
+
+fn print_paths<'a>(
+    deps: &HashMap<&'a str, &'a Vec<String>>,
+    reaches: &HashSet<&str>,
+    to: &str,
+    route: &mut Vec<&'a str>,
+    remaining: &mut usize,
+) {
+    let current = *route.last().unwrap();
+    if current == to {
+        println!("{}", route.join(" -> "));
+        *remaining -= 1;
+        return;
+    }
+    let mut next: Vec<&str> = match deps.get(current) {
+        Some(d) => d
+            .iter()
+            .map(String::as_str)
+            .filter(|d| reaches.contains(d))
+            .collect(),
+        None => return,
+    };
+    next.sort_unstable();
+    next.dedup();
+    for dep in next {
+        if *remaining == 0 {
+            return;
+        }
+        // a cycle can't be part of a path, and toposort reports those anyway
+        if route.contains(&dep) {
+            continue;
+        }
+        route.push(dep);
+        print_paths(deps, reaches, to, route, remaining);
+        route.pop();
+    }
+}
@@ -0,0 +1,46 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::executor::{annotate_component, CommandRegistry};
+use crate::hasher::{hash_components, HashOptions};
+use crate::types::*;
+
+/// Time each phase of hashing `iterations` times and print the mean,
+/// fastest and slowest run of each in milliseconds: loading the manifest,
+/// sorting it, hashing (which loads and sorts again, then runs git for
+/// every component) and evaluating the props registered in `reg`.
+pub fn run_bench(
+    path: &Path,
+    iterations: usize,
+    options: &HashOptions,
+    reg: &CommandRegistry,
+) -> anyhow::Result<()> {
+    let phases = ["load", "toposort", "hash", "props"];
+    let mut times: Vec<Vec<Duration>> = vec![Vec::new(); phases.len()];
+    for i in 0..iterations {
+        log::info!("Iteration {} of {}", i + 1, iterations);
+        let start = Instant::now();
+        let components = options.load.load_components(path)?;
+        times[0].push(start.elapsed());
+        let start = Instant::now();
+        toposort_components(components)?;
+        times[1].push(start.elapsed());
+        let start = Instant::now();
+        let mut hashed = hash_components(path, options, |_| Ok(()))?;
+        times[2].push(start.elapsed());
+        let start = Instant::now();
+        for comp in hashed.iter_mut() {
+            annotate_component(reg, comp)?;
+        }
+        times[3].push(start.elapsed());
+    }
+    let ms = |d: &Duration| d.as_secs_f64() * 1000.0;
+    println!("phase\tmean\tmin\tmax");
+    for (phase, runs) in phases.iter().zip(times.iter()) {
+        let mean = runs.iter().map(ms).sum::<f64>() / runs.len() as f64;
+        let min = runs.iter().map(ms).fold(f64::INFINITY, f64::min);
+        let max = runs.iter().map(ms).fold(0.0, f64::max);
+        println!("{}\t{:.2}\t{:.2}\t{:.2}", phase, mean, min, max);
+    }
+    Ok(())
+}
@@ -0,0 +1,402 @@
+use handlebars::{Handlebars, TemplateRenderError};
+use serde_json::json;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{create_dir_all, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::ci::{plan_levels, CiOptions, Job};
+use crate::executor::CommandRegistry;
+use crate::helpers::register_helpers;
+use crate::lock::lock;
+use crate::metrics;
+use crate::pool::for_each_parallel;
+use crate::registry::{ImageRef, Registry};
+use crate::state::BuildState;
+use crate::types::*;
+
+pub struct BuildOptions<'a> {
+    /// Which components to build and the command for each.
+    pub plan: CiOptions<'a>,
+    pub jobs: usize,
+    /// Write each component's output to `<log_dir>/<slug>.log` instead of
+    /// the console.
+    pub log_dir: Option<&'a Path>,
+    /// Carry on with components that don't depend on a failed one.
+    pub keep_going: bool,
+    /// Print what would be run instead of running it.
+    pub dry_run: bool,
+    pub hooks: Hooks<'a>,
+    /// File recording each component's build duration, for `estimate`.
+    pub state: Option<&'a Path>,
+    /// Wait for another run's lock on `state` rather than failing.
+    pub wait: bool,
+    /// Run only what the manifest allowlists, see `Hermetic`.
+    pub hermetic: bool,
+    /// Template for an image reference; components whose image is already
+    /// in the registry aren't built.
+    pub skip_if_image_exists: Option<&'a str>,
+}
+
+/// Templates run before and after every component's own `pre_build` and
+/// `post_build`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Hooks<'a> {
+    pub pre_build: Option<&'a str>,
+    pub post_build: Option<&'a str>,
+}
+
+/// Renders the steps for a job: global pre_build, the component's
+/// pre_build, the build command, the component's post_build and the global
+/// post_build, skipping any that aren't set.
+struct Steps<'a> {
+    handlebars: Handlebars<'a>,
+}
+
+impl<'a> Steps<'a> {
+    fn new(hooks: &Hooks, skip_if_image_exists: Option<&str>) -> Result<Steps<'a>, CustomError> {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_escape_fn(handlebars::no_escape);
+        register_helpers(&mut handlebars);
+        for (name, template) in [
+            ("pre_build", hooks.pre_build),
+            ("post_build", hooks.post_build),
+            ("skip_if_image_exists", skip_if_image_exists),
+        ] {
+            if let Some(template) = template {
+                handlebars
+                    .register_template_string(name, template)
+                    .map_err(|e| CustomError::TemplateError {
+                        prop_name: name.to_owned(),
+                        error: Box::new(e),
+                    })?;
+            }
+        }
+        Ok(Steps { handlebars })
+    }
+
+    /// The rendered `--skip-if-image-exists` reference if it exists.
+    fn existing_image(&self, registry: &Registry, job: &Job) -> anyhow::Result<Option<String>> {
+        let name = "skip_if_image_exists";
+        if !self.handlebars.has_template(name) {
+            return Ok(None);
+        }
+        let image = self.handlebars.render(name, &job.component).map_err(|e| {
+            CustomError::TemplateRenderError {
+                cmd_name: format!("{} of {}", name, job.component.dir),
+                error: Box::new(e),
+            }
+        })?;
+        if registry.exists(&ImageRef::parse(&image)?)? {
+            Ok(Some(image))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn render(&self, job: &Job) -> anyhow::Result<Vec<String>> {
+        let comp = &job.component;
+        let render_error = |name: &str, e| CustomError::TemplateRenderError {
+            cmd_name: format!("{} of {}", name, comp.dir),
+            error: Box::new(e),
+        };
+        let global = |name: &str| -> Result<Option<String>, CustomError> {
+            if !self.handlebars.has_template(name) {
+                return Ok(None);
+            }
+            self.handlebars
+                .render(name, comp)
+                .map(Some)
+                .map_err(|e| render_error(name, e))
+        };
+        let own = |name: &str, template: &Option<String>| -> anyhow::Result<Option<String>> {
+            let template = match template {
+                Some(t) => t,
+                None => return Ok(None),
+            };
+            match self.handlebars.render_template(template, comp) {
+                Ok(rendered) => Ok(Some(rendered)),
+                Err(TemplateRenderError::TemplateError(e)) => Err(CustomError::TemplateError {
+                    prop_name: format!("{} of {}", name, comp.dir),
+                    error: Box::new(e),
+                }
+                .into()),
+                Err(TemplateRenderError::RenderError(e)) => Err(render_error(name, e).into()),
+                Err(e) => Err(e.into()),
+            }
+        };
+        Ok(vec![
+            global("pre_build")?,
+            own("pre_build", &comp.pre_build)?,
+            Some(job.command.clone()),
+            own("post_build", &comp.post_build)?,
+            global("post_build")?,
+        ]
+        .into_iter()
+        .flatten()
+        .collect())
+    }
+}
+
+/// Run the ci-generate plan locally: each level's commands in parallel, up
+/// to `jobs` at a time, stopping after the first level with a failure
+/// unless `keep_going`.
+pub fn run_build(path: &Path, options: &BuildOptions) -> anyhow::Result<()> {
+    if options.dry_run {
+        return print_plan(path, options);
+    }
+    let reg = CommandRegistry::new(path, &options.plan.hash_options.load, options.hermetic)?;
+    let steps = Steps::new(&options.hooks, options.skip_if_image_exists)?;
+    let registry = Registry::new();
+    let levels = plan_levels(path, &options.plan)?;
+    if let Some(log_dir) = options.log_dir {
+        prepare_log_dir(
+            log_dir,
+            levels.iter().flatten().map(|j| j.component.dir.as_str()),
+        )?;
+    }
+    let failed = Mutex::new(Vec::new());
+    let durations = Mutex::new(Vec::new());
+    let mut skipped = Vec::new();
+    // failed and skipped components; plan dependencies are direct, but
+    // anything depending on a blocked component is itself blocked
+    let mut blocked: HashSet<String> = HashSet::new();
+    for level in levels {
+        let (mut runnable, blocked_here): (Vec<Job>, Vec<Job>) = level
+            .into_iter()
+            .partition(|j| !j.dependencies.iter().any(|d| blocked.contains(d)));
+        for job in blocked_here {
+            log::info!("Skipping {} after a failed dependency", job.component.dir);
+            blocked.insert(job.component.dir.clone());
+            skipped.push(job.component.dir);
+        }
+        let res = for_each_parallel(&mut runnable, options.jobs, |job| {
+            let start = Instant::now();
+            let res = match steps.existing_image(&registry, job) {
+                Ok(Some(image)) => {
+                    log::info!("Skipping {}, {} exists", job.component.dir, image);
+                    return Ok(());
+                }
+                Ok(None) => run_job(path, &reg, &steps, job, options.log_dir),
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(()) => {
+                    let took = start.elapsed().as_secs_f64();
+                    durations
+                        .lock()
+                        .unwrap()
+                        .push((job.component.dir.clone(), took));
+                    Ok(())
+                }
+                Err(e) if options.keep_going => {
+                    log::error!("{}", e);
+                    failed.lock().unwrap().push(job.component.dir.clone());
+                    Ok(())
+                }
+                res => res,
+            }
+        });
+        if res.is_err() {
+            record_durations(options, durations.into_inner().unwrap())?;
+            return res;
+        }
+        blocked.extend(failed.lock().unwrap().iter().cloned());
+    }
+    record_durations(options, durations.into_inner().unwrap())?;
+    let mut failed = failed.into_inner().unwrap();
+    if !failed.is_empty() {
+        failed.sort();
+        skipped.sort();
+        return Err(CustomError::BuildFailedError { failed, skipped }.into());
+    }
+    Ok(())
+}
+
+fn record_durations(options: &BuildOptions, durations: Vec<(String, f64)>) -> anyhow::Result<()> {
+    let file = match options.state {
+        Some(file) => file,
+        None => return Ok(()),
+    };
+    let _lock = lock(file, options.wait)?;
+    let mut state = BuildState::load(file)?;
+    state.durations.extend(durations);
+    state.save(file)
+}
+
+fn print_plan(path: &Path, options: &BuildOptions) -> anyhow::Result<()> {
+    let steps = Steps::new(&options.hooks, options.skip_if_image_exists)?;
+    let registry = Registry::new();
+    for (i, level) in plan_levels(path, &options.plan)?.iter().enumerate() {
+        println!("level {}:", i);
+        for job in level {
+            if let Some(image) = steps.existing_image(&registry, job)? {
+                println!("  {}: skipped, {} exists", job.component.dir, image);
+                continue;
+            }
+            println!("  {}:", job.component.dir);
+            for step in steps.render(job)? {
+                println!("    {}", step);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub const PLAN_FORMATS: &[&str] = &["json", "stages", "stages-json"];
+
+/// Print the build plan. `json` is a list of actions in dependency order,
+/// each with the steps build would run for it (the command wrapped in any
+/// `hooks` and the component's own pre_build and post_build), the
+/// environment they would run with and its tree_sha as a cache key, for
+/// runners that schedule the commands themselves. `stages` and
+/// `stages-json` group the jobs into stages, each of which can run entirely
+/// in parallel once the ones before it are done, for CI systems to turn
+/// into a job matrix per stage; wide levels are split into consecutive
+/// stages of at most `max_width` components.
+pub fn run_plan(
+    path: &Path,
+    options: &CiOptions,
+    hooks: &Hooks,
+    format: &str,
+    max_width: Option<usize>,
+) -> anyhow::Result<()> {
+    let levels = plan_levels(path, options)?;
+    let steps = Steps::new(hooks, None)?;
+    if format != "json" {
+        return print_stages(&levels, &steps, max_width, format == "stages-json");
+    }
+    let reg = CommandRegistry::new(path, &options.hash_options.load, false)?;
+    let mut actions = Vec::new();
+    for job in levels.into_iter().flatten() {
+        let env: BTreeMap<String, String> = reg
+            .command_envs(&job.component)?
+            .into_iter()
+            .map(|(k, v)| (k, v.to_string_lossy().into_owned()))
+            .collect();
+        actions.push(json!({
+            "component": job.component.dir,
+            "command": job.command,
+            "steps": steps.render(&job)?,
+            "env": env,
+            "depends_on": job.dependencies,
+            "cache_key": job.component.tree_sha,
+        }));
+    }
+    println!("{}", serde_json::to_string_pretty(&actions)?);
+    Ok(())
+}
+
+fn print_stages(
+    levels: &[Vec<Job>],
+    steps: &Steps,
+    max_width: Option<usize>,
+    as_json: bool,
+) -> anyhow::Result<()> {
+    let stages: Vec<&[Job]> = levels
+        .iter()
+        .filter(|jobs| !jobs.is_empty())
+        .flat_map(|jobs| jobs.chunks(max_width.unwrap_or(jobs.len())))
+        .collect();
+    if !as_json {
+        for (i, jobs) in stages.iter().enumerate() {
+            let dirs: Vec<&str> = jobs.iter().map(|j| j.component.dir.as_str()).collect();
+            println!("stage {}: {}", i, dirs.join(" "));
+        }
+        return Ok(());
+    }
+    let mut plan = Vec::new();
+    for (i, jobs) in stages.iter().enumerate() {
+        let mut components = Vec::new();
+        for j in jobs.iter() {
+            components.push(json!({
+                "dir": j.component.dir,
+                "image": j.component.image_name(),
+                "tree_sha": j.component.tree_sha,
+                "tree_sha_short": j.component.tree_sha_short,
+                "command": j.command,
+                "steps": steps.render(j)?,
+                "depends_on": j.dependencies,
+            }));
+        }
+        plan.push(json!({ "stage": i, "components": components }));
+    }
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&json!({ "stages": plan }))?
+    );
+    Ok(())
+}
+
+fn run_job(
+    path: &Path,
+    reg: &CommandRegistry,
+    steps: &Steps,
+    job: &Job,
+    log_dir: Option<&Path>,
+) -> anyhow::Result<()> {
+    let (log_file, out) = open_log(log_dir, &job.component.dir)?.unzip();
+    for cmd in steps.render(job)? {
+        log::info!("Building {}: {}", job.component.dir, cmd);
+        reg.check_allowed("sh")?;
+        let mut com = Command::new("sh");
+        com.arg("-c").arg(&cmd).current_dir(path);
+        reg.set_envs(&mut com, &job.component)?;
+        if let Some(out) = out.as_ref() {
+            log_to(&mut com, out)?;
+        }
+        let status = metrics::global()
+            .time_phase("exec", || com.status())
+            .map_err(|e| CustomError::CommandExecutionError {
+                cmd_name: job.component.dir.clone(),
+                error: e,
+            })?;
+        if status.success() {
+            continue;
+        }
+        let mut reason = match status.code() {
+            Some(c) => format!("exit code {}", c),
+            None => "terminated by signal".to_string(),
+        };
+        if let Some(log_file) = log_file {
+            reason.push_str(&format!(", see {:?}", log_file));
+        }
+        return Err(CustomError::UnsuccessfulCommandError { cmd, reason }.into());
+    }
+    Ok(())
+}
+
+/// Create `log_dir` for the components `dirs`, failing if two of them
+/// would share a log file.
+pub fn prepare_log_dir<'b, I: Iterator<Item = &'b str>>(
+    log_dir: &Path,
+    dirs: I,
+) -> anyhow::Result<()> {
+    check_slugs(dirs)?;
+    create_dir_all(log_dir)?;
+    Ok(())
+}
+
+/// The fresh log file for component `dir` under `log_dir`, and its path,
+/// or None without a `log_dir`.
+pub fn open_log(log_dir: Option<&Path>, dir: &str) -> std::io::Result<Option<(PathBuf, File)>> {
+    log_dir
+        .map(|d| {
+            let path = log_path(d, dir);
+            File::create(&path).map(|f| (path, f))
+        })
+        .transpose()
+}
+
+/// Send both of `com`'s output streams to `out`.
+pub fn log_to(com: &mut Command, out: &File) -> std::io::Result<()> {
+    com.stdout(Stdio::from(out.try_clone()?))
+        .stderr(Stdio::from(out.try_clone()?));
+    Ok(())
+}
+
+fn log_path(log_dir: &Path, dir: &str) -> PathBuf {
+    log_dir.join(format!("{}.log", slug(dir)))
+}
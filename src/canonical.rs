@@ -0,0 +1,87 @@
+use serde_json::Value;
+
+/// Serialize `value` as RFC 8785 canonical JSON: no whitespace, object keys
+/// sorted by UTF-16 code units, numbers formatted as ECMAScript does and
+/// strings with only the escapes JSON requires.
+pub fn to_canonical_string(value: &Value) -> String {
+    let mut out = String::new();
+    write_value(value, &mut out);
+    out
+}
+
+fn write_value(value: &Value, out: &mut String) {
+    match value {
+        Value::Null | Value::Bool(_) | Value::String(_) => {
+            // serde_json's escaping is already the canonical one
+            out.push_str(&value.to_string())
+        }
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => out.push_str(&i.to_string()),
+            (_, Some(u)) => out.push_str(&u.to_string()),
+            _ => out.push_str(&format_f64(n.as_f64().unwrap())),
+        },
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(item, out);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.encode_utf16().cmp(b.0.encode_utf16()));
+            out.push('{');
+            for (i, (k, v)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::from(k.as_str()).to_string());
+                out.push(':');
+                write_value(v, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+/// ECMAScript's Number.prototype.toString for a finite double, built from
+/// the shortest round-tripping digits Rust finds.
+fn format_f64(f: f64) -> String {
+    if f == 0.0 {
+        return "0".to_owned();
+    }
+    let sci = format!("{:e}", f.abs());
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let k = digits.len() as i32;
+    // the decimal point goes after the first n digits
+    let n = exp.parse::<i32>().unwrap() + 1;
+    let body = if k <= n && n <= 21 {
+        format!("{}{}", digits, "0".repeat((n - k) as usize))
+    } else if 0 < n && n <= 21 {
+        format!("{}.{}", &digits[..n as usize], &digits[n as usize..])
+    } else if -6 < n && n <= 0 {
+        format!("0.{}{}", "0".repeat(-n as usize), digits)
+    } else {
+        let rest = if k > 1 {
+            format!(".{}", &digits[1..])
+        } else {
+            String::new()
+        };
+        format!(
+            "{}{}e{}{}",
+            &digits[..1],
+            rest,
+            if n > 0 { "+" } else { "-" },
+            (n - 1).abs()
+        )
+    };
+    if f < 0.0 {
+        format!("-{}", body)
+    } else {
+        body
+    }
+}
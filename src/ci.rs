@@ -0,0 +1,265 @@
+use handlebars::Handlebars;
+use serde_json::json;
+use std::path::Path;
+
+use crate::affected::affected_components;
+use crate::export::yaml_string;
+use crate::hasher::{hash_components, HashOptions};
+use crate::helpers::register_helpers;
+use crate::types::*;
+
+#[derive(Debug, Copy, Clone)]
+pub enum CiFormat {
+    Json,
+    Jenkins,
+    Buildkite,
+    Azure,
+    AzureMatrix,
+    Drone,
+}
+
+impl CiFormat {
+    pub const NAMES: &'static [&'static str] = &[
+        "json",
+        "jenkins",
+        "buildkite",
+        "azure",
+        "azure-matrix",
+        "drone",
+    ];
+
+    pub fn from_name(name: &str) -> CiFormat {
+        match name {
+            "json" => CiFormat::Json,
+            "jenkins" => CiFormat::Jenkins,
+            "buildkite" => CiFormat::Buildkite,
+            "azure" => CiFormat::Azure,
+            "azure-matrix" => CiFormat::AzureMatrix,
+            "drone" => CiFormat::Drone,
+            _ => panic!("unknown ci format {}", name),
+        }
+    }
+}
+
+pub struct CiOptions<'a> {
+    pub roots: Vec<&'a str>,
+    pub since: Option<&'a str>,
+    pub merge_base: bool,
+    /// Handlebars template for each component's build step.
+    pub command: &'a str,
+    pub hash_options: HashOptions,
+}
+
+/// A component's build step within the generated pipeline.
+pub struct Job {
+    pub component: Component,
+    pub command: String,
+    pub dependencies: Vec<String>,
+}
+
+pub fn run_ci_generate(
+    path: &Path,
+    format: CiFormat,
+    options: &CiOptions,
+) -> Result<(), anyhow::Error> {
+    let levels = plan_levels(path, options)?;
+    // these name jobs after the slug of their dir
+    if matches!(
+        format,
+        CiFormat::Buildkite | CiFormat::Azure | CiFormat::AzureMatrix | CiFormat::Drone
+    ) {
+        check_slugs(levels.iter().flatten().map(|j| j.component.dir.as_str()))?;
+    }
+    let out = match format {
+        CiFormat::Json => json_plan(&levels)?,
+        CiFormat::Jenkins => jenkins(&levels),
+        CiFormat::Buildkite => buildkite(&levels),
+        CiFormat::Azure => azure(&levels),
+        CiFormat::AzureMatrix => azure_matrix(&levels)?,
+        CiFormat::Drone => drone(&levels),
+    };
+    print!("{}", out);
+    Ok(())
+}
+
+/// Affected components grouped by toposort level, with rendered commands
+/// and dependencies restricted to other affected components.
+pub fn plan_levels(path: &Path, options: &CiOptions) -> anyhow::Result<Vec<Vec<Job>>> {
+    let affected = affected_components(
+        path,
+        &options.hash_options.load,
+        &options.roots,
+        options.since,
+        options.merge_base,
+    )?;
+    let mut hashed = hash_components(path, &options.hash_options, |_| Ok(()))?;
+    hashed.retain(|c| affected.iter().any(|a| a.dir == c.dir));
+    let mut reg = Handlebars::new();
+    reg.register_escape_fn(handlebars::no_escape);
+    register_helpers(&mut reg);
+    reg.register_template_string("command", options.command)
+        .map_err(|e| CustomError::TemplateError {
+            prop_name: "command".to_owned(),
+            error: Box::new(e),
+        })?;
+    let dirs: Vec<String> = hashed.iter().map(|c| c.dir.clone()).collect();
+    let mut res = Vec::new();
+    for level in toposort_levels(hashed)? {
+        let mut jobs = Vec::new();
+        for comp in level {
+            let command =
+                reg.render("command", &comp)
+                    .map_err(|e| CustomError::TemplateRenderError {
+                        cmd_name: "command".to_owned(),
+                        error: Box::new(e),
+                    })?;
+            jobs.push(Job {
+                dependencies: comp
+                    .depsorted()
+                    .into_iter()
+                    .filter(|d| dirs.contains(d))
+                    .collect(),
+                component: comp,
+                command,
+            });
+        }
+        res.push(jobs);
+    }
+    Ok(res)
+}
+
+fn json_plan(levels: &[Vec<Job>]) -> anyhow::Result<String> {
+    let plan: Vec<_> = levels
+        .iter()
+        .enumerate()
+        .map(|(i, jobs)| {
+            json!({
+                "level": i,
+                "components": jobs.iter().map(|j| json!({
+                    "dir": j.component.dir,
+                    "command": j.command,
+                    "depends_on": j.dependencies,
+                })).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&plan)? + "\n")
+}
+
+fn jenkins(levels: &[Vec<Job>]) -> String {
+    let mut out = String::from("// Generated by avocado-build-helper from components.json\n");
+    out.push_str("pipeline {\n    agent any\n    stages {\n");
+    for (i, jobs) in levels.iter().enumerate() {
+        out.push_str(&format!(
+            "        stage('level {}') {{\n            parallel {{\n",
+            i
+        ));
+        for job in jobs {
+            out.push_str(&format!(
+                "                stage({}) {{\n                    steps {{\n                        sh {}\n                    }}\n                }}\n",
+                groovy_string(&job.component.dir),
+                groovy_string(&job.command)
+            ));
+        }
+        out.push_str("            }\n        }\n");
+    }
+    out.push_str("    }\n}\n");
+    out
+}
+
+fn groovy_string(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Steps for `buildkite-agent pipeline upload`, keyed by slug so
+/// `depends_on` can follow the graph rather than waiting on whole levels.
+/// `$` is doubled so the agent leaves variables for the step's shell.
+fn buildkite(levels: &[Vec<Job>]) -> String {
+    let mut out = String::from("# Generated by avocado-build-helper from components.json\n");
+    out.push_str("steps:\n");
+    for job in levels.iter().flatten() {
+        out.push_str(&format!(
+            "  - key: {}\n    label: {}\n    command: {}\n",
+            yaml_string(&slug(&job.component.dir)),
+            yaml_string(&job.component.dir),
+            yaml_string(&job.command.replace('$', "$$"))
+        ));
+        if !job.dependencies.is_empty() {
+            out.push_str("    depends_on:\n");
+            for dep in job.dependencies.iter() {
+                out.push_str(&format!("      - {}\n", yaml_string(&slug(dep))));
+            }
+        }
+    }
+    out
+}
+
+/// Azure Pipelines job names only allow letters, digits and underscores.
+fn azure_name(dir: &str) -> String {
+    format!("c_{}", slug(dir).replace('-', "_"))
+}
+
+/// A single stage with one job per component, `dependsOn` following the
+/// graph.
+fn azure(levels: &[Vec<Job>]) -> String {
+    let mut out = String::from("# Generated by avocado-build-helper from components.json\n");
+    out.push_str("stages:\n  - stage: build\n    jobs:\n");
+    for job in levels.iter().flatten() {
+        out.push_str(&format!(
+            "      - job: {}\n        displayName: {}\n",
+            azure_name(&job.component.dir),
+            yaml_string(&job.component.dir)
+        ));
+        if !job.dependencies.is_empty() {
+            out.push_str("        dependsOn:\n");
+            for dep in job.dependencies.iter() {
+                out.push_str(&format!("          - {}\n", azure_name(dep)));
+            }
+        }
+        out.push_str(&format!(
+            "        steps:\n          - script: {}\n",
+            yaml_string(&job.command)
+        ));
+    }
+    out
+}
+
+/// One `strategy: matrix` value per level, for templates that run a matrix
+/// job per level after the previous one.
+fn azure_matrix(levels: &[Vec<Job>]) -> anyhow::Result<String> {
+    let matrices: Vec<_> = levels
+        .iter()
+        .map(|jobs| {
+            jobs.iter()
+                .map(|j| {
+                    (
+                        azure_name(&j.component.dir),
+                        json!({ "component": j.component.dir, "command": j.command }),
+                    )
+                })
+                .collect::<serde_json::Map<_, _>>()
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&matrices)? + "\n")
+}
+
+/// A .drone.yml with one exec pipeline per component, so the command runs
+/// on the runner like the other formats' shell steps. `$` is doubled to
+/// stop Drone substituting variables itself.
+fn drone(levels: &[Vec<Job>]) -> String {
+    let mut out = String::from("# Generated by avocado-build-helper from components.json\n");
+    for job in levels.iter().flatten() {
+        out.push_str(&format!(
+            "---\nkind: pipeline\ntype: exec\nname: {}\n\nsteps:\n  - name: build\n    commands:\n      - {}\n",
+            yaml_string(&slug(&job.component.dir)),
+            yaml_string(&job.command.replace('$', "$$"))
+        ));
+        if !job.dependencies.is_empty() {
+            out.push_str("\ndepends_on:\n");
+            for dep in job.dependencies.iter() {
+                out.push_str(&format!("  - {}\n", yaml_string(&slug(dep))));
+            }
+        }
+    }
+    out
+}
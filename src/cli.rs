@@ -0,0 +1,2590 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::{
+    affected, analysis, bench, build, ci, dockerignore, estimate, exec, executor, export, graph,
+    hasher, import, lock, man, manifest, metrics, pick, plugin, prove, provenance, registry,
+    render, report, rewrite, schema, server, sparse, tag, types, validate, verify, workspace,
+};
+
+use affected::{changed_components, changed_components_between};
+use analysis::{run_analyze_cycles, run_diamonds, run_paths, run_rank};
+use bench::run_bench;
+use build::{run_build, run_plan, BuildOptions, Hooks};
+use ci::{run_ci_generate, CiFormat, CiOptions};
+use dockerignore::*;
+use estimate::run_estimate;
+use exec::{run_exec, ExecOptions};
+use executor::{annotate_component, run_env, CommandConfig, CommandRegistry, Truthiness};
+use export::{run_export, ExportFormat, ExportOptions};
+use hasher::*;
+use man::run_generate_man;
+use manifest::LoadOptions;
+use pick::run_pick;
+use prove::run_prove;
+use provenance::run_provenance;
+use registry::{run_image_exists, run_registry_report};
+use render::{run_render, run_split, run_template_files};
+use report::run_report;
+use rewrite::{run_fmt, run_merge, run_prune};
+use schema::run_generate_schema;
+use server::run_server;
+use sparse::run_sparse_paths;
+use tag::run_tag_components;
+use types::CustomError;
+use validate::{run_validate, run_validate_dangling};
+use verify::run_verify_reproducibility;
+use workspace::{split_root, Workspace};
+
+enum Deps {
+    Dependencies,
+    Dependents,
+}
+
+/// How toposort and the transitive-* subcommands print components.
+#[derive(Clone, Copy)]
+enum ListFormat {
+    /// Dirs, one per line.
+    Text,
+    /// A JSON array of components.
+    Json,
+    /// One JSON component per line.
+    Jsonl,
+}
+
+impl ListFormat {
+    const NAMES: &'static [&'static str] = &["text", "json", "jsonl"];
+
+    fn from_matches(m: &ArgMatches) -> ListFormat {
+        match m.value_of("format") {
+            Some("json") => ListFormat::Json,
+            Some("jsonl") => ListFormat::Jsonl,
+            _ => ListFormat::Text,
+        }
+    }
+
+    fn print(self, components: &[&types::Component]) -> anyhow::Result<()> {
+        match self {
+            ListFormat::Text => components.iter().for_each(|c| println!("{}", c.dir)),
+            ListFormat::Json => println!("{}", serde_json::to_string_pretty(components)?),
+            ListFormat::Jsonl => {
+                for c in components {
+                    println!("{}", serde_json::to_string(c)?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn main() -> Result<(), anyhow::Error> {
+    env_logger::init();
+    let version = env!("CARGO_PKG_VERSION");
+    let app = App::new("Build Helper")
+        .version(version)
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .setting(AppSettings::AllowExternalSubcommands)
+        .arg(
+            Arg::with_name("version-json")
+                .long("version-json")
+                .help("Print version, build and supported manifest information as JSON"),
+        )
+        .arg(
+            Arg::with_name("overlay")
+                .long("overlay")
+                .help("Deep-merge components.<name>.json onto components.json (repeatable)")
+                .global(true)
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("timings")
+                .long("timings")
+                .help("Print the time spent loading, sorting, hashing, executing and writing to stderr")
+                .global(true)
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("hermetic")
+                .long("hermetic")
+                .help(
+                    "Refuse shell props, run only commands allowlisted in the manifest's hermetic \
+                     section and pass commands only its allowlisted environment",
+                )
+                .global(true)
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("wait")
+                .long("wait")
+                .help("Wait for other runs to finish with shared files (the default)")
+                .global(true)
+                .required(false)
+                .takes_value(false)
+                .overrides_with("no-wait"),
+        )
+        .arg(
+            Arg::with_name("no-wait")
+                .long("no-wait")
+                .help("Fail instead of waiting when another run holds a lock on components.json or a --state file")
+                .global(true)
+                .required(false)
+                .takes_value(false)
+                .overrides_with("wait"),
+        )
+        .arg(
+            Arg::with_name("manifest-file")
+                .long("manifest")
+                .help("Manifest file name, instead of the first of components.json, components.yaml and components.yml found")
+                .global(true)
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help("Error on component keys not declared in metadata_keys")
+                .global(true)
+                .required(false)
+                .takes_value(false),
+        )
+        .subcommand(
+            SubCommand::with_name("hash-components")
+                .about("Annotate components.json with hashes")
+                .arg(
+                    Arg::with_name("directory")
+                        .help("Dir holding the manifest, or - to read it from stdin for components in the current dir")
+                        .required(false)
+                        .index(1)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("workspace")
+                        .long("workspace")
+                        .help("Workspace file listing component roots to combine, addressed as root:dir")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("in-place")
+                        .long("in-place")
+                        .help("Write hashes and props into the manifest instead of printing the components")
+                        .required(false)
+                        .takes_value(false)
+                        .conflicts_with_all(&["workspace", "pretty-print", "canonical"]),
+                )
+                .arg(
+                    Arg::with_name("pretty-print")
+                        .short("-p")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("canonical")
+                        .long("canonical")
+                        .help("Output RFC 8785 canonical JSON, byte-identical for identical input")
+                        .required(false)
+                        .takes_value(false)
+                        .conflicts_with("pretty-print"),
+                )
+                .arg(
+                    Arg::with_name("short-shas")
+                        .long("short")
+                        .short("-s")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("remove-dependencies")
+                        .short("-r")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("follow-renames")
+                        .long("follow-renames")
+                        .help("Ignore commits that only move files into a component")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(hash_salt_arg())
+                .arg(
+                    Arg::with_name("content-hash")
+                        .long("content-hash")
+                        .help("Hash working tree contents, skipping paths matched by .hashignore")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("pin")
+                        .long("pin")
+                        .help("Use a fixed tree_sha for a component, as component=sha")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("metrics-file")
+                        .long("metrics-file")
+                        .help("Write Prometheus textfile-collector metrics to this file")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("include-dirty")
+                        .long("include-dirty")
+                        .help("Mix uncommitted and untracked changes into tree hashes, marking those components dirty")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .long("jobs")
+                        .short("j")
+                        .help("Find this many components' sources (git history or contents) at once [default: number of CPUs]")
+                        .required(false)
+                        .takes_value(true)
+                        .validator(is_positive_int),
+                )
+                .arg(
+                    Arg::with_name("prop-jobs")
+                        .long("prop-jobs")
+                        .help("Evaluate props for this many components at once")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(is_positive_int),
+                )
+                .args(&prop_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("gen-dockerignore")
+                .about("Generate .dockerignore file")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("overwrite")
+                        .short("f")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("no-include-ignore")
+                        .short("n")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("ignore-filename")
+                        .long("ignore-filename")
+                        .help("File to generate, e.g. .containerignore (repeatable)")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .default_value(".dockerignore"),
+                )
+                .arg(
+                    Arg::with_name("base")
+                        .long("base")
+                        .help(
+                            "File included verbatim around the generated rules, which go \
+                             at a '# avocado-build-helper: rules' line or else after it",
+                        )
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("component").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("toposort")
+                .about("Topologically sort components")
+                .arg(
+                    Arg::with_name("directory")
+                        .required(false)
+                        .index(1)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("workspace")
+                        .long("workspace")
+                        .help("Workspace file listing component roots to combine, addressed as root:dir")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("levels")
+                        .long("levels")
+                        .help("Print one line per level of components that can be built together")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .args(&list_args())
+                .arg(
+                    Arg::with_name("max-width")
+                        .long("max-width")
+                        .help("Split levels into batches of at most this many components")
+                        .required(false)
+                        .takes_value(true)
+                        .requires("levels")
+                        .validator(is_positive_int),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("transitive-dependencies")
+                .about("List all transitive dependencies of component (topologically sorted)")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("workspace")
+                        .long("workspace")
+                        .help("Workspace file listing component roots to combine, addressed as root:dir")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no-include-self")
+                        .short("n")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("reverse-topological-order")
+                        .short("r")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .args(&list_args())
+                .arg(
+                    Arg::with_name("component")
+                        .help("Component dirs or glob patterns such as services/* or libs/**")
+                        .required(true)
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("transitive-dependents")
+                .about("List all transitive dependents of component (topologically sorted)")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("workspace")
+                        .long("workspace")
+                        .help("Workspace file listing component roots to combine, addressed as root:dir")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no-include-self")
+                        .short("n")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .args(&since_args(
+                    "Also include components changed since the merge-base of this ref and HEAD",
+                ))
+                .args(&list_args())
+                .arg(
+                    Arg::with_name("component")
+                        .help("Component dirs or glob patterns such as services/* or libs/**")
+                        .required_unless("since")
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List components, all or those matching patterns (topologically sorted)")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("workspace")
+                        .long("workspace")
+                        .help("Workspace file listing component roots to combine, addressed as root:dir")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .args(&list_args())
+                .arg(
+                    Arg::with_name("pattern")
+                        .help("Component dirs or glob patterns such as services/* or libs/**")
+                        .required(false)
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("changed-components")
+                .about("List components changed between two refs and their transitive dependents (topologically sorted)")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("base")
+                        .long("base")
+                        .help("Diff from the merge-base of this ref and --head")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("head")
+                        .long("head")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("HEAD"),
+                )
+                .arg(
+                    Arg::with_name("exact-ref")
+                        .long("exact-ref")
+                        .help("Diff from the --base ref itself rather than its merge-base")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .args(&list_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("owners-of")
+                .about("List the owners of every transitive dependent of the given or changed components")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .args(&since_args(
+                    "Also include components changed since the merge-base of this ref and HEAD",
+                ))
+                .arg(
+                    Arg::with_name("component")
+                        .required_unless("since")
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("common-dependencies")
+                .about("List transitive dependencies shared by all given components (topologically sorted)")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("component")
+                        .required(true)
+                        .index(1)
+                        .multiple(true)
+                        .min_values(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rank")
+                .about("Rank components by how many others they transitively affect")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("by")
+                        .long("by")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("dependents")
+                        .possible_values(analysis::RANK_BY),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diamonds")
+                .about("Report dependencies reachable from a component along many distinct paths")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("min-paths")
+                        .long("min-paths")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("2")
+                        .validator(is_positive_int),
+                )
+                .arg(Arg::with_name("component").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("paths")
+                .about("List every dependency path from one component to another")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .help("Stop after this many paths")
+                        .required(false)
+                        .takes_value(true)
+                        .validator(is_positive_int),
+                )
+                .arg(Arg::with_name("from").required(true).index(1))
+                .arg(Arg::with_name("to").required(true).index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("pick")
+                .about("Interactively fuzzy-search components and print the ones picked")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("multi")
+                        .long("multi")
+                        .help("Allow picking several components"),
+                )
+                .arg(Arg::with_name("query").required(false).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("analyze-cycles")
+                .about("List every dependency cycle with its members and edges")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sparse-paths")
+                .about("List directories needed to build components (for git sparse-checkout set --stdin)")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("shared-path")
+                        .long("shared-path")
+                        .short("s")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("component")
+                        .required(true)
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tag-components")
+                .about("Create annotated git tags recording each component's hashes")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("template")
+                        .long("template")
+                        .short("t")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("{{dir}}/v-{{tree_sha_short}}"),
+                )
+                .arg(
+                    Arg::with_name("message")
+                        .long("message")
+                        .short("m")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("{{dir}} tree_sha {{tree_sha}}"),
+                )
+                .arg(
+                    Arg::with_name("push")
+                        .long("push")
+                        .help("Push created tags to this remote")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("follow-renames")
+                        .long("follow-renames")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(hash_salt_arg())
+                .arg(
+                    Arg::with_name("component")
+                        .required(false)
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export the component graph in other tools' formats")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .short("f")
+                        .required(true)
+                        .takes_value(true)
+                        .possible_values(ExportFormat::NAMES),
+                )
+                .arg(
+                    Arg::with_name("exclusive-dependencies")
+                        .long("exclusive-dependencies")
+                        .help("codeowners: also assign unowned dependencies used only by an owned component")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .long("remote")
+                        .help("Remote whose URL identifies the source repository")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("origin"),
+                )
+                .arg(
+                    Arg::with_name("image-template")
+                        .long("image-template")
+                        .help("skaffold, cache-hints, oci-index, cosign: handlebars template for image names")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("attest")
+                        .long("attest")
+                        .help("cosign: run cosign attest with each component's predicate")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("follow-renames")
+                        .long("follow-renames")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(hash_salt_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("provenance")
+                .about("Emit provenance attestations linking component hashes to their inputs")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("pretty-print")
+                        .short("-p")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .long("remote")
+                        .help("Remote whose URL identifies the source repository")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("origin"),
+                )
+                .arg(
+                    Arg::with_name("follow-renames")
+                        .long("follow-renames")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(hash_salt_arg())
+                .arg(
+                    Arg::with_name("component")
+                        .required(false)
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("generate-schema")
+                .about("Print a JSON Schema describing components.json"),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Serve read-only graph queries over HTTP")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .short("l")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("127.0.0.1:8080"),
+                )
+                .arg(
+                    Arg::with_name("watch")
+                        .long("watch")
+                        .short("w")
+                        .help("Reload components.json when it changes")
+                        .required(false)
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ci-generate")
+                .about("Generate CI pipeline config with parallel stages per toposort level")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .short("f")
+                        .required(true)
+                        .takes_value(true)
+                        .possible_values(CiFormat::NAMES),
+                )
+                .args(&plan_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("build-plan")
+                .about("Print affected components in stages of mutually independent components, for parallel CI jobs; plan --format stages")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .short("f")
+                        .help("text or json, as plan's stages or stages-json")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("text")
+                        .possible_values(&["text", "json"]),
+                )
+                .arg(
+                    Arg::with_name("max-width")
+                        .long("max-width")
+                        .help("Split stages into batches of at most this many components")
+                        .required(false)
+                        .takes_value(true)
+                        .validator(is_positive_int),
+                )
+                .args(&plan_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("build")
+                .about("Run each component's build command level by level, like a ci-generate pipeline")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .long("jobs")
+                        .short("j")
+                        .help("Build up to this many components of a level at once")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(is_positive_int),
+                )
+                .arg(log_dir_arg())
+                .arg(
+                    Arg::with_name("keep-going")
+                        .long("keep-going")
+                        .short("k")
+                        .help("Keep building components that don't depend on a failed one"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Print each level's components and rendered commands without running them"),
+                )
+                .args(&hook_args())
+                .arg(
+                    Arg::with_name("state")
+                        .long("state")
+                        .help("JSON file recording how long each component took, for estimate")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("skip-if-image-exists")
+                        .long("skip-if-image-exists")
+                        .help("Handlebars template for an image reference; skip components whose image is in the registry")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .args(&plan_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("exec")
+                .about("Run a command in each component's dir in topological order")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("dependencies-of")
+                        .long("dependencies-of")
+                        .help("Only this component's transitive dependencies and itself (repeatable, globs allowed)")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("dependents-of")
+                        .long("dependents-of")
+                        .help("Only this component's transitive dependents and itself (repeatable, globs allowed)")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .conflicts_with("dependencies-of"),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .long("jobs")
+                        .short("j")
+                        .help("Run in up to this many components of a level at once")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(is_positive_int),
+                )
+                .arg(log_dir_arg())
+                .arg(
+                    Arg::with_name("keep-going")
+                        .long("keep-going")
+                        .short("k")
+                        .help("Keep running in components that don't depend on a failed one"),
+                )
+                .arg(hash_salt_arg())
+                .arg(
+                    Arg::with_name("command")
+                        .help("Program and arguments, after --")
+                        .required(true)
+                        .multiple(true)
+                        .last(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("image-exists")
+                .about("Exit successfully only if every image reference exists in its registry, 1 if one is missing and 2 on errors")
+                .arg(
+                    Arg::with_name("image")
+                        .required(true)
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("registry-report")
+                .about("Report which hash-tagged images in a repository are live or stale")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("repo")
+                        .long("repo")
+                        .help("Image repository, e.g. ghcr.io/org/app")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("follow-renames")
+                        .long("follow-renames")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(hash_salt_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("estimate")
+                .about("Predict how long build would take from the durations in a --state file")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("state")
+                        .long("state")
+                        .help("JSON file written by build --state")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .long("jobs")
+                        .short("j")
+                        .help("Components of a level built at once")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(is_positive_int),
+                )
+                .arg(
+                    Arg::with_name("component")
+                        .required(false)
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("env")
+                .about("Print the AVOCADO_* environment commands get for a component")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .short("f")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("sh")
+                        .possible_values(executor::ENV_FORMATS),
+                )
+                .arg(hash_salt_arg())
+                .arg(Arg::with_name("component").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("render")
+                .about("Render a template once per component and print the results")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("template")
+                        .long("template")
+                        .short("t")
+                        .help("Handlebars template rendered with each component's data")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("hash")
+                        .long("hash")
+                        .help("Annotate components with hashes before rendering")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(hash_salt_arg().requires("hash"))
+                .arg(
+                    Arg::with_name("component")
+                        .help("Components to render for, all if none are given")
+                        .required(false)
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("template-files")
+                .about("Write a file rendered from a template for each component")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("template")
+                        .long("template")
+                        .short("t")
+                        .help("Handlebars template file rendered with each component's data")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .short("o")
+                        .help("Handlebars template for the output path, relative to components.json")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .args(&prop_args())
+                .arg(hash_salt_arg())
+                .arg(
+                    Arg::with_name("component")
+                        .help("Components to write files for, all if none are given")
+                        .required(false)
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("split")
+                .about("Write each hashed component to its own JSON file")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("out-dir")
+                        .long("out-dir")
+                        .short("o")
+                        .help("Directory for the <dir slug>.json files")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .args(&prop_args())
+                .arg(hash_salt_arg())
+                .arg(
+                    Arg::with_name("component")
+                        .help("Components to write, all if none are given")
+                        .required(false)
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("Time loading, sorting, hashing and prop evaluation")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("iterations")
+                        .long("iterations")
+                        .short("n")
+                        .help("Times to repeat each phase")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("5")
+                        .validator(is_positive_int),
+                )
+                .args(&prop_args())
+                .arg(hash_salt_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("plan")
+                .about("Print the actions build would run, for external runners")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .short("f")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("json")
+                        .possible_values(build::PLAN_FORMATS),
+                )
+                .arg(
+                    Arg::with_name("max-width")
+                        .long("max-width")
+                        .help("Split stages into batches of at most this many components")
+                        .required(false)
+                        .takes_value(true)
+                        .validator(is_positive_int),
+                )
+                .args(&hook_args())
+                .args(&plan_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-reproducibility")
+                .about("Hash twice and report any component whose values differ")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("backend")
+                        .long("backend")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("git")
+                        .possible_values(verify::BACKENDS),
+                )
+                .arg(
+                    Arg::with_name("follow-renames")
+                        .long("follow-renames")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(hash_salt_arg())
+                .arg(
+                    Arg::with_name("prop-jobs")
+                        .long("prop-jobs")
+                        .help("Evaluate props for this many components at once")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(is_positive_int),
+                )
+                .args(&prop_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("prove")
+                .about("Print the exact bytes hashed for a component's tree_sha as JSON")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("follow-renames")
+                        .long("follow-renames")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(hash_salt_arg())
+                .arg(
+                    Arg::with_name("content-hash")
+                        .long("content-hash")
+                        .help("Hash working tree contents, skipping paths matched by .hashignore")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("pin")
+                        .long("pin")
+                        .help("Use a fixed tree_sha for a component, as component=sha")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(Arg::with_name("component").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .about("Rewrite components.json keeping its key order and indentation")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Fail instead of rewriting if the file isn't formatted"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Combine the components of several manifests into one")
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .short("o")
+                        .help("Write the merged manifest here instead of to stdout")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("deep-merge")
+                        .long("deep-merge")
+                        .help("Deep-merge components with the same dir instead of failing"),
+                )
+                .arg(
+                    Arg::with_name("manifest")
+                        .required(true)
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Generate components.json from another build tool's project graph")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .help("The tool: nx and pants read graph JSON from `nx graph --file` or `pants peek ::`, turbo and lerna read the workspace root")
+                        .required(true)
+                        .takes_value(true)
+                        .possible_values(import::IMPORT_FORMATS),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .short("o")
+                        .help("Write the manifest here instead of to stdout")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("path").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("Remove hashes and generated props from components.json")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("keep")
+                        .long("keep")
+                        .help("Generated field or prop to leave in place")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("only")
+                        .long("only")
+                        .help("Remove just this generated field or prop")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("size")
+                .about(
+                    "Print file count, bytes and docker context bytes per component, \
+                     largest context first",
+                )
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("graph")
+                .about("Print the dependency graph as Graphviz DOT")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("highlight")
+                        .long("highlight")
+                        .help("Highlight this component and its transitive dependencies (repeatable)")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("dependents")
+                        .long("dependents")
+                        .help("Highlight transitive dependents instead of dependencies")
+                        .required(false)
+                        .takes_value(false)
+                        .requires("highlight"),
+                )
+                .arg(
+                    Arg::with_name("svg")
+                        .long("svg")
+                        .help("Render SVG with Graphviz dot instead")
+                        .required(false)
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("report")
+                .about("Write an HTML page with the graph, hashes, affected components and build times")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("html")
+                        .long("html")
+                        .help("File to write the report to")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .help("Highlight components affected by changes since this ref")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("HEAD~1"),
+                )
+                .arg(
+                    Arg::with_name("state")
+                        .long("state")
+                        .help("JSON file written by build --state, for build times")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(hash_salt_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check components.json for invalid dirs and dependency cycles")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .required(false)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("dangling")
+                        .long("dangling")
+                        .help("Only list dependencies that don't name a component, with their dependents"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("generate-man")
+                .about("Write roff man pages for the tool and each subcommand")
+                .setting(AppSettings::Hidden)
+                .arg(
+                    Arg::with_name("out-dir")
+                        .short("o")
+                        .long("out-dir")
+                        .required(false)
+                        .default_value("."),
+                ),
+        );
+    let matches = app.clone().get_matches();
+    if matches.is_present("version-json") {
+        return print_version_json();
+    }
+    if matches.subcommand_name().is_none() {
+        // clap's SubcommandRequired would also reject --version-json
+        clap::Error::with_description(
+            "A subcommand is required but one was not provided",
+            clap::ErrorKind::MissingSubcommand,
+        )
+        .exit();
+    }
+    let res = run_subcommand(&app, &matches);
+    // global args are propagated down, so the subcommand sees them either way
+    if matches
+        .subcommand()
+        .1
+        .unwrap_or(&matches)
+        .is_present("timings")
+    {
+        eprint!("{}", metrics::global().render_phases());
+    }
+    res
+}
+
+fn run_subcommand(app: &App, matches: &ArgMatches) -> anyhow::Result<()> {
+    if let Some(m) = matches.subcommand_matches("hash-components") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let stdin = p == Path::new("-");
+        if stdin && (m.is_present("in-place") || m.is_present("workspace")) {
+            anyhow::bail!("--in-place and --workspace need manifest files, not stdin");
+        }
+        let path = if stdin {
+            Path::new(".").canonicalize()?
+        } else {
+            p.canonicalize()?
+        };
+        let workspace = m
+            .value_of_os("workspace")
+            .map(|f| Workspace::load(f.as_ref()))
+            .transpose()?;
+        // props run against the root each component comes from
+        let mut regs = BTreeMap::new();
+        match &workspace {
+            Some(workspace) => {
+                for (name, root) in workspace.roots.iter() {
+                    regs.insert(
+                        name.as_str(),
+                        registry_with_props(root, m)?.in_workspace_root(name),
+                    );
+                }
+            }
+            None => {
+                regs.insert("", registry_with_props(&path, m)?);
+            }
+        }
+        let short = m.is_present("short-shas");
+        let options = HashOptions {
+            load: load_options(m),
+            remove_dependencies: m.is_present("remove-dependencies"),
+            include_short_shas: short,
+            follow_renames: m.is_present("follow-renames"),
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            content_hash: m.is_present("content-hash"),
+            pins: parse_pins(m.values_of("pin"))?,
+            prop_jobs: m.value_of("prop-jobs").unwrap().parse()?,
+            jobs: match m.value_of("jobs") {
+                Some(jobs) => jobs.parse()?,
+                None => default_jobs(),
+            },
+            include_dirty: m.is_present("include-dirty"),
+        };
+        let post = |c: &mut types::Component| {
+            let root = if workspace.is_some() {
+                split_root(&c.dir).0
+            } else {
+                ""
+            };
+            annotate_component(&regs[root], c)
+        };
+        let start = Instant::now();
+        let res = if m.is_present("in-place") {
+            // held from reading the manifest until it is written back
+            let file = rewrite::existing_manifest(&path, &options.load)?;
+            let _lock = lock::lock(&file, !m.is_present("no-wait"))?;
+            hash_components(&path, &options, post)
+                .and_then(|hashed| rewrite::write_hashes(&file, &hashed))
+        } else {
+            let text = if stdin {
+                Some(std::io::read_to_string(std::io::stdin())?)
+            } else {
+                None
+            };
+            let source = match (&workspace, &text) {
+                (Some(workspace), _) => Source::Workspace(workspace),
+                (None, Some(text)) => Source::Text(text),
+                (None, None) => Source::Manifest,
+            };
+            run_hasher(
+                &path,
+                source,
+                m.is_present("pretty-print"),
+                m.is_present("canonical"),
+                &options,
+                post,
+            )
+        };
+        if let Some(f) = m.value_of_os("metrics-file") {
+            metrics::global().add_duration(&metrics::RUN_DURATION, &[], start.elapsed());
+            metrics::global().write_to(f.as_ref())?;
+        }
+        res
+    } else if let Some(m) = matches.subcommand_matches("gen-dockerignore") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let d = m.value_of("component").unwrap();
+        let overwrite = m.is_present("overwrite");
+        let noinclude = m.is_present("no-include-ignore");
+        let filenames: Vec<_> = m.values_of("ignore-filename").unwrap().collect();
+        let base = m.value_of_os("base").map(Path::new);
+        run_dockerignore_creator(
+            &path,
+            &load_options(m),
+            d,
+            &filenames,
+            base,
+            overwrite,
+            noinclude,
+        )
+    } else if let Some(m) = matches.subcommand_matches("toposort") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let owners = owner_filter(m);
+        let components = load_selected(&path, m)?;
+        let format = ListFormat::from_matches(m);
+        if m.is_present("levels") {
+            let max_width = m.value_of("max-width").map(str::parse).transpose()?;
+            run_topo_levels(components, max_width, &owners, format)
+        } else {
+            run_topo(components, &owners, format)
+        }
+    } else if let Some(m) = matches.subcommand_matches("transitive-dependencies") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let components: Vec<_> = m.values_of("component").unwrap().collect();
+        let noinclude = m.is_present("no-include-self");
+        let reverse = m.is_present("reverse-topological-order");
+        let owners = owner_filter(m);
+        run_listdeps(
+            load_selected(&path, m)?,
+            Deps::Dependencies,
+            !noinclude,
+            Some(reverse),
+            components,
+            &owners,
+            ListFormat::from_matches(m),
+        )
+    } else if let Some(m) = matches.subcommand_matches("list") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let patterns: Vec<_> = m
+            .values_of("pattern")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        let owners = owner_filter(m);
+        run_list(
+            load_selected(&path, m)?,
+            &patterns,
+            &owners,
+            ListFormat::from_matches(m),
+        )
+    } else if let Some(m) = matches.subcommand_matches("transitive-dependents") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let mut components: Vec<String> = m
+            .values_of("component")
+            .map(|v| v.map(str::to_owned).collect())
+            .unwrap_or_default();
+        if let Some(since) = m.value_of("since") {
+            let merge_base = !m.is_present("exact-ref");
+            components.extend(match m.value_of_os("workspace") {
+                Some(file) => Workspace::load(file.as_ref())?.changed_components(
+                    &load_options(m),
+                    since,
+                    merge_base,
+                )?,
+                None => changed_components(&path, &load_options(m), since, merge_base)?,
+            });
+        }
+        let noinclude = m.is_present("no-include-self");
+        let owners = owner_filter(m);
+        run_listdeps(
+            load_selected(&path, m)?,
+            Deps::Dependents,
+            !noinclude,
+            None,
+            components.iter().map(String::as_str).collect(),
+            &owners,
+            ListFormat::from_matches(m),
+        )
+    } else if let Some(m) = matches.subcommand_matches("changed-components") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let changed = changed_components_between(
+            &path,
+            &load_options(m),
+            m.value_of("base").unwrap(),
+            m.value_of("head").unwrap(),
+            !m.is_present("exact-ref"),
+        )?;
+        let owners = owner_filter(m);
+        run_listdeps(
+            load_selected(&path, m)?,
+            Deps::Dependents,
+            true,
+            None,
+            changed.iter().map(String::as_str).collect(),
+            &owners,
+            ListFormat::from_matches(m),
+        )
+    } else if let Some(m) = matches.subcommand_matches("owners-of") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let components: Vec<_> = m
+            .values_of("component")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        run_owners_of(
+            &path,
+            &load_options(m),
+            &components,
+            m.value_of("since"),
+            !m.is_present("exact-ref"),
+        )
+    } else if let Some(m) = matches.subcommand_matches("common-dependencies") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let components: Vec<_> = m.values_of("component").unwrap().collect();
+        let data = load_options(m).load_components(&path)?;
+        for component in types::common_dependencies(data, &components)? {
+            println!("{}", component.dir);
+        }
+        Ok(())
+    } else if let Some(m) = matches.subcommand_matches("rank") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        run_rank(&path, &load_options(m), m.value_of("by").unwrap())
+    } else if let Some(m) = matches.subcommand_matches("diamonds") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        run_diamonds(
+            &path,
+            &load_options(m),
+            m.value_of("component").unwrap(),
+            m.value_of("min-paths").unwrap().parse()?,
+        )
+    } else if let Some(m) = matches.subcommand_matches("paths") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        run_paths(
+            &path,
+            &load_options(m),
+            m.value_of("from").unwrap(),
+            m.value_of("to").unwrap(),
+            m.value_of("limit").map(str::parse).transpose()?,
+        )
+    } else if let Some(m) = matches.subcommand_matches("pick") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        run_pick(
+            &path,
+            &load_options(m),
+            m.is_present("multi"),
+            m.value_of("query"),
+        )
+    } else if let Some(m) = matches.subcommand_matches("analyze-cycles") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        run_analyze_cycles(&path, &load_options(m))
+    } else if let Some(m) = matches.subcommand_matches("sparse-paths") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let components: Vec<_> = m.values_of("component").unwrap().collect();
+        let shared: Vec<_> = m
+            .values_of("shared-path")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        run_sparse_paths(&path, &load_options(m), &components, &shared)
+    } else if let Some(m) = matches.subcommand_matches("tag-components") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let components: Vec<_> = m
+            .values_of("component")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        let options = HashOptions {
+            load: load_options(m),
+            include_short_shas: true,
+            follow_renames: m.is_present("follow-renames"),
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            ..Default::default()
+        };
+        run_tag_components(
+            &path,
+            m.value_of("template").unwrap(),
+            m.value_of("message").unwrap(),
+            &components,
+            m.value_of("push"),
+            &options,
+        )
+    } else if let Some(m) = matches.subcommand_matches("export") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let options = ExportOptions {
+            exclusive_dependencies: m.is_present("exclusive-dependencies"),
+            remote: m.value_of("remote").unwrap().to_owned(),
+            hash_options: HashOptions {
+                load: load_options(m),
+                include_short_shas: true,
+                follow_renames: m.is_present("follow-renames"),
+                salt: m.value_of("hash-salt").map(str::to_owned),
+                ..Default::default()
+            },
+            image_template: m.value_of("image-template").map(str::to_owned),
+            attest: m.is_present("attest"),
+        };
+        run_export(
+            &path,
+            ExportFormat::from_name(m.value_of("format").unwrap()),
+            &options,
+        )
+    } else if let Some(m) = matches.subcommand_matches("provenance") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let components: Vec<_> = m
+            .values_of("component")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        let options = HashOptions {
+            load: load_options(m),
+            follow_renames: m.is_present("follow-renames"),
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            ..Default::default()
+        };
+        run_provenance(
+            &path,
+            &components,
+            m.value_of("remote").unwrap(),
+            m.is_present("pretty-print"),
+            &options,
+        )
+    } else if matches.subcommand_matches("generate-schema").is_some() {
+        run_generate_schema()
+    } else if let Some(m) = matches.subcommand_matches("serve") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        run_server(
+            &path,
+            &load_options(m),
+            m.value_of("listen").unwrap(),
+            m.is_present("watch"),
+        )
+    } else if let Some(m) = matches.subcommand_matches("ci-generate") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let options = plan_options(m);
+        run_ci_generate(
+            &path,
+            CiFormat::from_name(m.value_of("format").unwrap()),
+            &options,
+        )
+    } else if let Some(m) = matches.subcommand_matches("exec") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let options = HashOptions {
+            load: load_options(m),
+            include_short_shas: true,
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            ..Default::default()
+        };
+        let hashed = hash_components(&path, &options, |_| Ok(()))?;
+        let select = |roots: clap::Values| -> anyhow::Result<Vec<String>> {
+            let patterns: Vec<&str> = roots.collect();
+            let dirs = types::expand_patterns(&hashed, &patterns)?;
+            let refs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+            types::check_components_exist(&hashed, &refs)?;
+            Ok(dirs)
+        };
+        let roots = if let Some(roots) = m.values_of("dependencies-of") {
+            Some((Deps::Dependencies, select(roots)?))
+        } else if let Some(roots) = m.values_of("dependents-of") {
+            Some((Deps::Dependents, select(roots)?))
+        } else {
+            None
+        };
+        let components = match roots {
+            Some((deps, dirs)) => {
+                let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+                match deps {
+                    Deps::Dependencies => {
+                        types::transitive_dependencies(hashed, &dirs, true, false)?
+                    }
+                    Deps::Dependents => types::transitive_dependents(hashed, &dirs, true)?,
+                }
+            }
+            None => hashed,
+        };
+        let command: Vec<&str> = m.values_of("command").unwrap().collect();
+        run_exec(
+            &path,
+            components,
+            &command,
+            &ExecOptions {
+                jobs: m.value_of("jobs").unwrap().parse()?,
+                log_dir: m.value_of_os("log-dir").map(Path::new),
+                keep_going: m.is_present("keep-going"),
+                load: load_options(m),
+                hermetic: m.is_present("hermetic"),
+            },
+        )
+    } else if let Some(m) = matches.subcommand_matches("build-plan") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let options = plan_options(m);
+        let max_width = m.value_of("max-width").map(str::parse).transpose()?;
+        let format = match m.value_of("format") {
+            Some("json") => "stages-json",
+            _ => "stages",
+        };
+        run_plan(&path, &options, &Hooks::default(), format, max_width)
+    } else if let Some(m) = matches.subcommand_matches("build") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let options = BuildOptions {
+            plan: plan_options(m),
+            jobs: m.value_of("jobs").unwrap().parse()?,
+            log_dir: m.value_of_os("log-dir").map(Path::new),
+            keep_going: m.is_present("keep-going"),
+            dry_run: m.is_present("dry-run"),
+            hooks: build_hooks(m),
+            state: m.value_of_os("state").map(Path::new),
+            skip_if_image_exists: m.value_of("skip-if-image-exists"),
+            wait: !m.is_present("no-wait"),
+            hermetic: m.is_present("hermetic"),
+        };
+        run_build(&path, &options)
+    } else if let Some(m) = matches.subcommand_matches("image-exists") {
+        let images: Vec<_> = m.values_of("image").unwrap().collect();
+        // 1 means missing, so errors get their own code
+        match run_image_exists(&images) {
+            Ok(true) => Ok(()),
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(2)
+            }
+        }
+    } else if let Some(m) = matches.subcommand_matches("registry-report") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let options = HashOptions {
+            load: load_options(m),
+            include_short_shas: true,
+            follow_renames: m.is_present("follow-renames"),
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            ..Default::default()
+        };
+        run_registry_report(&path, m.value_of("repo").unwrap(), &options)
+    } else if let Some(m) = matches.subcommand_matches("estimate") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let components: Vec<_> = m
+            .values_of("component")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        run_estimate(
+            &path,
+            &load_options(m),
+            &components,
+            m.value_of("jobs").unwrap().parse()?,
+            m.value_of_os("state").unwrap().as_ref(),
+        )
+    } else if let Some(m) = matches.subcommand_matches("env") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let options = HashOptions {
+            load: load_options(m),
+            include_short_shas: true,
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            ..Default::default()
+        };
+        run_env(
+            &path,
+            m.value_of("component").unwrap(),
+            m.value_of("format").unwrap(),
+            &options,
+        )
+    } else if let Some(m) = matches.subcommand_matches("render") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let components: Vec<_> = m
+            .values_of("component")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        let options = HashOptions {
+            load: load_options(m),
+            include_short_shas: true,
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            ..Default::default()
+        };
+        run_render(
+            &path,
+            &load_options(m),
+            m.value_of("template").unwrap(),
+            &components,
+            Some(&options).filter(|_| m.is_present("hash")),
+        )
+    } else if let Some(m) = matches.subcommand_matches("template-files") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let reg = registry_with_props(&path, m)?;
+        let components: Vec<_> = m
+            .values_of("component")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        let options = HashOptions {
+            load: load_options(m),
+            include_short_shas: true,
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            ..Default::default()
+        };
+        run_template_files(
+            &path,
+            m.value_of_os("template").unwrap().as_ref(),
+            m.value_of("out").unwrap(),
+            &components,
+            &options,
+            |c| annotate_component(&reg, c),
+        )
+    } else if let Some(m) = matches.subcommand_matches("split") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let reg = registry_with_props(&path, m)?;
+        let components: Vec<_> = m
+            .values_of("component")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        let options = HashOptions {
+            load: load_options(m),
+            include_short_shas: true,
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            ..Default::default()
+        };
+        run_split(
+            &path,
+            m.value_of_os("out-dir").unwrap().as_ref(),
+            &components,
+            &options,
+            |c| annotate_component(&reg, c),
+        )
+    } else if let Some(m) = matches.subcommand_matches("bench") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let reg = registry_with_props(&path, m)?;
+        let options = HashOptions {
+            load: load_options(m),
+            include_short_shas: true,
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            ..Default::default()
+        };
+        run_bench(
+            &path,
+            m.value_of("iterations").unwrap().parse()?,
+            &options,
+            &reg,
+        )
+    } else if let Some(m) = matches.subcommand_matches("plan") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let options = plan_options(m);
+        let max_width = m.value_of("max-width").map(str::parse).transpose()?;
+        run_plan(
+            &path,
+            &options,
+            &build_hooks(m),
+            m.value_of("format").unwrap(),
+            max_width,
+        )
+    } else if let Some(m) = matches.subcommand_matches("generate-man") {
+        run_generate_man(app, m.value_of_os("out-dir").unwrap().as_ref())
+    } else if let Some(m) = matches.subcommand_matches("validate") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        if m.is_present("dangling") {
+            run_validate_dangling(&path, &load_options(m))
+        } else {
+            run_validate(&path, &load_options(m))
+        }
+    } else if let Some(m) = matches.subcommand_matches("verify-reproducibility") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let reg = registry_with_props(&path, m)?;
+        let options = HashOptions {
+            load: load_options(m),
+            include_short_shas: true,
+            follow_renames: m.is_present("follow-renames"),
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            prop_jobs: m.value_of("prop-jobs").unwrap().parse()?,
+            ..Default::default()
+        };
+        run_verify_reproducibility(&path, m.value_of("backend").unwrap(), &options, |c| {
+            annotate_component(&reg, c)
+        })
+    } else if let Some(m) = matches.subcommand_matches("prove") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let options = HashOptions {
+            load: load_options(m),
+            follow_renames: m.is_present("follow-renames"),
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            content_hash: m.is_present("content-hash"),
+            pins: parse_pins(m.values_of("pin"))?,
+            ..Default::default()
+        };
+        run_prove(&path, m.value_of("component").unwrap(), &options)
+    } else if let Some(m) = matches.subcommand_matches("fmt") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        run_fmt(
+            &path,
+            &load_options(m),
+            !m.is_present("no-wait"),
+            m.is_present("check"),
+        )
+    } else if let Some(m) = matches.subcommand_matches("prune") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let keep: Vec<_> = m.values_of("keep").map(|v| v.collect()).unwrap_or_default();
+        let only: Vec<_> = m.values_of("only").map(|v| v.collect()).unwrap_or_default();
+        run_prune(
+            &path,
+            &load_options(m),
+            !m.is_present("no-wait"),
+            &keep,
+            &only,
+        )
+    } else if let Some(m) = matches.subcommand_matches("size") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        run_size(&path, &load_options(m))
+    } else if let Some(m) = matches.subcommand_matches("graph") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let roots: Vec<_> = m
+            .values_of("highlight")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        graph::run_graph(
+            &path,
+            &load_options(m),
+            &roots,
+            m.is_present("dependents"),
+            m.is_present("svg"),
+        )
+    } else if let Some(m) = matches.subcommand_matches("report") {
+        let p: &Path = m.value_of_os("directory").unwrap().as_ref();
+        let path = p.canonicalize()?;
+        let options = HashOptions {
+            load: load_options(m),
+            include_short_shas: true,
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            ..Default::default()
+        };
+        run_report(
+            &path,
+            m.value_of_os("html").unwrap().as_ref(),
+            m.value_of("since").unwrap(),
+            m.value_of_os("state").map(Path::new),
+            &options,
+        )
+    } else if let Some(m) = matches.subcommand_matches("merge") {
+        let files: Vec<&Path> = m.values_of_os("manifest").unwrap().map(Path::new).collect();
+        run_merge(
+            &files,
+            m.is_present("deep-merge"),
+            m.is_present("strict"),
+            m.value_of_os("out").map(Path::new),
+            !m.is_present("no-wait"),
+        )
+    } else if let Some(m) = matches.subcommand_matches("import") {
+        import::run_import(
+            m.value_of("from").unwrap(),
+            Path::new(m.value_of_os("path").unwrap()),
+            m.value_of_os("out").map(Path::new),
+            !m.is_present("no-wait"),
+        )
+    } else if let (name, Some(m)) = matches.subcommand() {
+        let args: Vec<&str> = m.values_of("").map(|v| v.collect()).unwrap_or_default();
+        let code = plugin::run_plugin(
+            &std::env::current_dir()?,
+            &load_options(m),
+            m.is_present("hermetic"),
+            name,
+            &args,
+        )?;
+        if code != 0 {
+            std::process::exit(code);
+        }
+        Ok(())
+    } else {
+        panic!("unexpected subcommand")
+    }
+}
+
+fn print_version_json() -> anyhow::Result<()> {
+    let commit = env!("AVOCADO_GIT_COMMIT");
+    let features: Vec<&str> = env!("AVOCADO_FEATURES")
+        .split(',')
+        .filter(|f| !f.is_empty())
+        .collect();
+    let info = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_commit": if commit.is_empty() { None } else { Some(commit) },
+        "features": features,
+        "manifest_versions": (1..=manifest::MANIFEST_VERSION).collect::<Vec<_>>(),
+    });
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(())
+}
+
+/// `--overlay`, `--strict` and `--manifest`, global args the subcommand's
+/// matches see too.
+fn load_options(m: &ArgMatches) -> LoadOptions {
+    LoadOptions {
+        overlays: m
+            .values_of("overlay")
+            .map(|v| v.map(str::to_owned).collect())
+            .unwrap_or_default(),
+        strict: m.is_present("strict"),
+        manifest: m.value_of("manifest-file").map(str::to_owned),
+    }
+}
+
+fn hash_salt_arg() -> Arg<'static, 'static> {
+    Arg::with_name("hash-salt")
+        .long("hash-salt")
+        .help("Mixed into every tree hash to keep separate pipelines apart")
+        .required(false)
+        .takes_value(true)
+}
+
+fn log_dir_arg() -> Arg<'static, 'static> {
+    Arg::with_name("log-dir")
+        .long("log-dir")
+        .help("Write each component's output to <log-dir>/<component>.log instead of the console")
+        .required(false)
+        .takes_value(true)
+}
+
+/// Output and filtering for the subcommands that list components.
+fn list_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("format")
+            .long("format")
+            .help("Print dirs, or full components as a JSON array or one per line")
+            .required(false)
+            .takes_value(true)
+            .default_value("text")
+            .possible_values(ListFormat::NAMES),
+        Arg::with_name("with-hashes")
+            .long("with-hashes")
+            .help("Include hashes in JSON output")
+            .required(false)
+            .takes_value(false),
+        hash_salt_arg().requires("with-hashes"),
+        Arg::with_name("owner")
+            .long("owner")
+            .help("Only list components with this owner (repeatable)")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    ]
+}
+
+/// The `--owner`s of `list_args`.
+fn owner_filter<'a>(m: &'a ArgMatches) -> Vec<&'a str> {
+    m.values_of("owner")
+        .map(|v| v.collect())
+        .unwrap_or_default()
+}
+
+/// What ci-generate, build, plan and build-plan build and against what.
+fn plan_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("command")
+            .long("command")
+            .short("c")
+            .help("Handlebars template for each component's build step")
+            .required(false)
+            .takes_value(true)
+            .default_value("make -C {{dir}}"),
+        hash_salt_arg(),
+        Arg::with_name("component")
+            .required(false)
+            .index(1)
+            .multiple(true),
+    ]
+    .into_iter()
+    .chain(since_args(
+        "Only components affected by changes since the merge-base of this ref and HEAD",
+    ))
+    .collect()
+}
+
+/// Global `--pre-build` and `--post-build` templates, for build and plan.
+fn hook_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("pre-build")
+            .long("pre-build")
+            .help("Handlebars template run before every component's build")
+            .required(false)
+            .takes_value(true),
+        Arg::with_name("post-build")
+            .long("post-build")
+            .help("Handlebars template run after every component's successful build")
+            .required(false)
+            .takes_value(true),
+    ]
+}
+
+fn build_hooks<'a>(m: &'a ArgMatches) -> Hooks<'a> {
+    Hooks {
+        pre_build: m.value_of("pre-build"),
+        post_build: m.value_of("post-build"),
+    }
+}
+
+/// `--since`, described by `help`, and `--exact-ref` to diff against the
+/// ref itself rather than its merge-base.
+fn since_args(help: &'static str) -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("since")
+            .long("since")
+            .help(help)
+            .required(false)
+            .takes_value(true),
+        Arg::with_name("exact-ref")
+            .long("exact-ref")
+            .help("Diff against the --since ref itself rather than its merge-base")
+            .required(false)
+            .takes_value(false)
+            .requires("since"),
+    ]
+}
+
+fn plan_options<'a>(m: &'a ArgMatches) -> CiOptions<'a> {
+    CiOptions {
+        roots: m
+            .values_of("component")
+            .map(|v| v.collect())
+            .unwrap_or_default(),
+        since: m.value_of("since"),
+        merge_base: !m.is_present("exact-ref"),
+        command: m.value_of("command").unwrap(),
+        hash_options: HashOptions {
+            load: load_options(m),
+            include_short_shas: true,
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            ..Default::default()
+        },
+    }
+}
+
+/// The props `registry_with_props` adds.
+fn prop_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("add-prop")
+            .long("add-prop")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("add-sh-prop")
+            .long("add-sh-prop")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("add-exec-prop")
+            .long("add-exec-prop")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("add-script-prop")
+            .long("add-script-prop")
+            .help(
+                "Add a property from a script, as name=path.rhai: evaluated without \
+                 spawning a process, with the component as `component`, its result \
+                 becoming the JSON value",
+            )
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("add-plugin-prop")
+            .long("add-plugin-prop")
+            .help(
+                "Add a property from a plugin, as name=plugin [args]: runs \
+                 avocado-build-helper-<plugin> with the component JSON on stdin",
+            )
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    ]
+}
+
+/// A registry with the props given by `--add-prop`, `--add-sh-prop`,
+/// `--add-exec-prop`, `--add-script-prop` and `--add-plugin-prop`.
+fn registry_with_props<'a>(path: &Path, m: &ArgMatches) -> anyhow::Result<CommandRegistry<'a>> {
+    let mut reg = CommandRegistry::new(path, &load_options(m), m.is_present("hermetic"))?;
+    if let Some(cmds) = m.values_of("add-exec-prop") {
+        register_added_props(&mut reg, cmds, CommandConfig::new_exec_command())?;
+    }
+    if let Some(cmds) = m.values_of("add-sh-prop") {
+        register_added_props(&mut reg, cmds, CommandConfig::new_shell_command())?;
+    }
+    if let Some(cmds) = m.values_of("add-prop") {
+        register_added_props(&mut reg, cmds, CommandConfig::new_template())?;
+    }
+    if let Some(cmds) = m.values_of("add-script-prop") {
+        let scripts = cmds
+            .map(|c| match c.find('=') {
+                Some(p) => Ok(format!("{}={}", &c[..p], read_to_string(&c[p + 1..])?)),
+                None => Ok(c.to_owned()),
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        register_added_props(&mut reg, scripts.iter(), CommandConfig::new_script())?;
+    }
+    if let Some(cmds) = m.values_of("add-plugin-prop") {
+        let cmds = cmds.map(|c| c.replacen('=', &format!("={}", plugin::PLUGIN_PREFIX), 1));
+        let config = CommandConfig::new_exec_command().set_stdin_json();
+        register_added_props(&mut reg, cmds, config)?;
+    }
+    Ok(reg)
+}
+
+fn register_added_props<A: Iterator<Item = T>, T: AsRef<str>>(
+    reg: &mut CommandRegistry,
+    props: A,
+    config: CommandConfig,
+) -> Result<(), CustomError> {
+    for cmd_ref in props {
+        let cmd = cmd_ref.as_ref();
+        if let Some(p) = cmd.find('=') {
+            let mut x = &cmd[..p];
+            let is_command = config.is_command();
+            // `name?=`, `name?0,2=` (true exit codes) and `name!?=` (inverted)
+            let mut conf = match x.rfind('?') {
+                Some(q) if is_command => {
+                    let truthiness = parse_truthiness(&x[..q], &x[q + 1..], cmd)?;
+                    x = x[..q].trim_end_matches('!');
+                    config.clone().set_bool(truthiness)
+                }
+                _ => config.clone(),
+            };
+            // `name<=` pipes the component JSON to the command
+            if is_command && x.ends_with('<') {
+                x = &x[..x.len() - 1];
+                conf = conf.set_stdin_json();
+            }
+            let y = &cmd[p + 1..];
+            reg.add_command(x, y, conf)?;
+        } else {
+            return Err(CustomError::PropMissingEqualsError {
+                argument: cmd.to_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn parse_truthiness(name: &str, codes: &str, argument: &str) -> Result<Truthiness, CustomError> {
+    let true_codes = if codes.is_empty() {
+        vec![0]
+    } else {
+        codes
+            .split(',')
+            .map(|c| c.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| CustomError::InvalidExitCodesError {
+                argument: argument.to_owned(),
+            })?
+    };
+    Ok(Truthiness {
+        true_codes,
+        invert: name.ends_with('!'),
+    })
+}
+
+fn is_positive_int(v: String) -> Result<(), String> {
+    match v.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("{:?} is not a positive integer", v)),
+    }
+}
+
+fn parse_pins<'a, A: Iterator<Item = &'a str>>(
+    pins: Option<A>,
+) -> Result<BTreeMap<String, String>, CustomError> {
+    let mut res = BTreeMap::new();
+    for pin in pins.into_iter().flatten() {
+        match pin.split_once('=') {
+            Some((dir, sha)) => res.insert(dir.to_owned(), sha.to_owned()),
+            None => {
+                return Err(CustomError::PropMissingEqualsError {
+                    argument: pin.to_owned(),
+                })
+            }
+        };
+    }
+    Ok(res)
+}
+
+/// Components of the workspace given with `--workspace`, otherwise of the
+/// components.json in `path`.
+fn load_selected(path: &Path, m: &ArgMatches) -> anyhow::Result<Vec<types::Component>> {
+    let workspace = m
+        .value_of_os("workspace")
+        .map(|f| Workspace::load(f.as_ref()))
+        .transpose()?;
+    if m.is_present("with-hashes") {
+        let options = HashOptions {
+            load: load_options(m),
+            include_short_shas: true,
+            salt: m.value_of("hash-salt").map(str::to_owned),
+            ..Default::default()
+        };
+        return match workspace {
+            Some(workspace) => hash_workspace_components(&workspace, &options, |_| Ok(())),
+            None => hash_components(path, &options, |_| Ok(())),
+        };
+    }
+    Ok(match workspace {
+        Some(workspace) => workspace.load_components(&load_options(m))?,
+        None => load_options(m).load_components(path)?,
+    })
+}
+
+fn run_topo(x: Vec<types::Component>, owners: &[&str], format: ListFormat) -> anyhow::Result<()> {
+    let sorted = types::toposort_components(x)?;
+    let selected: Vec<_> = sorted.iter().filter(|c| c.is_owned_by(owners)).collect();
+    format.print(&selected)
+}
+
+/// Components whose dirs are or match `patterns`, every one without any.
+fn run_list(
+    x: Vec<types::Component>,
+    patterns: &[&str],
+    owners: &[&str],
+    format: ListFormat,
+) -> anyhow::Result<()> {
+    let dirs = types::expand_patterns(&x, patterns)?;
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+    types::check_components_exist(&x, &dirs)?;
+    let sorted = types::toposort_components(x)?;
+    let selected: Vec<_> = sorted
+        .iter()
+        .filter(|c| dirs.is_empty() || dirs.contains(&c.dir.as_str()))
+        .filter(|c| c.is_owned_by(owners))
+        .collect();
+    format.print(&selected)
+}
+
+/// One line per level of mutually independent components, wide levels
+/// split into consecutive batches of at most `max_width`. As JSON, an
+/// array of those batches, or one batch per line.
+fn run_topo_levels(
+    x: Vec<types::Component>,
+    max_width: Option<usize>,
+    owners: &[&str],
+    format: ListFormat,
+) -> anyhow::Result<()> {
+    let levels = types::toposort_levels(x)?;
+    let mut batches = Vec::new();
+    for level in levels.iter() {
+        let selected: Vec<&types::Component> =
+            level.iter().filter(|c| c.is_owned_by(owners)).collect();
+        if selected.is_empty() {
+            continue;
+        }
+        batches.extend(
+            selected
+                .chunks(max_width.unwrap_or(selected.len()))
+                .map(<[_]>::to_vec),
+        );
+    }
+    match format {
+        ListFormat::Text => {
+            for batch in batches {
+                let dirs: Vec<&str> = batch.iter().map(|c| c.dir.as_str()).collect();
+                println!("{}", dirs.join(" "));
+            }
+        }
+        ListFormat::Json => println!("{}", serde_json::to_string_pretty(&batches)?),
+        ListFormat::Jsonl => {
+            for batch in batches {
+                println!("{}", serde_json::to_string(&batch)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_listdeps(
+    data: Vec<types::Component>,
+    deps: Deps,
+    include_self: bool,
+    reverse_order: Option<bool>,
+    components: Vec<&str>,
+    owners: &[&str],
+    format: ListFormat,
+) -> anyhow::Result<()> {
+    let components = types::expand_patterns(&data, &components)?;
+    let components: Vec<&str> = components.iter().map(String::as_str).collect();
+    types::check_components_exist(&data, &components)?;
+    let r = match deps {
+        Deps::Dependencies => types::transitive_dependencies(
+            data,
+            &components[..],
+            include_self,
+            reverse_order.unwrap_or(false),
+        )?,
+        Deps::Dependents => types::transitive_dependents(data, &components[..], include_self)?,
+    };
+    let selected: Vec<_> = r.iter().filter(|c| c.is_owned_by(owners)).collect();
+    format.print(&selected)
+}
+
+/// Owners of every component affected by `roots` and changes since
+/// `since`, one per line, for routing reviews and failures.
+fn run_owners_of(
+    path: &Path,
+    load: &LoadOptions,
+    roots: &[&str],
+    since: Option<&str>,
+    merge_base: bool,
+) -> anyhow::Result<()> {
+    let components = affected::affected_components(path, load, roots, since, merge_base)?;
+    let owners: std::collections::BTreeSet<&str> = components
+        .iter()
+        .flat_map(|c| c.owners.iter().map(String::as_str))
+        .collect();
+    for owner in owners {
+        println!("{}", owner);
+    }
+    Ok(())
+}
@@ -0,0 +1,157 @@
+use anyhow::Result;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::fs::{read_dir, read_to_string, symlink_metadata};
+use std::path::Path;
+
+use crate::glob::glob_regex;
+use crate::types::{path_to_slash, path_to_slash_bytes};
+
+/// Skipped when hashing contents unless dropped with `!<pattern>` in
+/// `.hashignore`; these are either VCS metadata or local build output.
+pub const DEFAULT_HASH_IGNORE: &[&str] = &[
+    ".git",
+    ".hg",
+    ".svn",
+    ".DS_Store",
+    "target/",
+    "node_modules/",
+    "__pycache__/",
+    ".pytest_cache/",
+    ".mypy_cache/",
+    ".tox/",
+    ".venv/",
+    ".gradle/",
+];
+
+#[derive(Clone)]
+struct Rule {
+    re: Regex,
+    dir_only: bool,
+    /// Patterns containing a `/` match the path from the component dir,
+    /// others match any single file or directory name.
+    anchored: bool,
+}
+
+/// Paths left out of content hashes, from the defaults plus the
+/// `.hashignore` file next to components.json.
+pub struct HashIgnore {
+    rules: Vec<Rule>,
+}
+
+impl HashIgnore {
+    pub fn load(path: &Path) -> Result<HashIgnore> {
+        let mut patterns: Vec<String> = DEFAULT_HASH_IGNORE
+            .iter()
+            .map(|p| (*p).to_owned())
+            .collect();
+        match read_to_string(path.join(".hashignore")) {
+            Ok(text) => {
+                for line in text.lines().map(str::trim) {
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    if let Some(dropped) = line.strip_prefix('!') {
+                        patterns.retain(|p| p != dropped);
+                    } else {
+                        patterns.push(line.to_owned());
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(HashIgnore::new(&patterns))
+    }
+
+    /// Just `patterns`, without the defaults.
+    pub fn new(patterns: &[String]) -> HashIgnore {
+        let rules = patterns
+            .iter()
+            .map(|p| {
+                let dir_only = p.ends_with('/');
+                let p = p.trim_end_matches('/');
+                Rule {
+                    re: glob_regex(p.trim_start_matches('/')),
+                    dir_only,
+                    anchored: p.contains('/'),
+                }
+            })
+            .collect();
+        HashIgnore { rules }
+    }
+
+    /// These rules plus `patterns`, such as a component's own `ignore`.
+    pub fn extended(&self, patterns: &[String]) -> HashIgnore {
+        let mut rules = self.rules.clone();
+        rules.extend(HashIgnore::new(patterns).rules);
+        HashIgnore { rules }
+    }
+
+    /// Whether the file at `rel`, or any dir it's in, is ignored.
+    pub fn ignores(&self, rel: &str) -> bool {
+        let parts: Vec<&str> = rel.split('/').collect();
+        (1..=parts.len()).any(|n| self.is_ignored(&parts[..n].join("/"), n < parts.len()))
+    }
+
+    fn is_ignored(&self, rel: &str, is_dir: bool) -> bool {
+        let name = rel.rsplit('/').next().unwrap_or(rel);
+        self.rules
+            .iter()
+            .any(|r| (is_dir || !r.dir_only) && r.re.is_match(if r.anchored { rel } else { name }))
+    }
+}
+
+/// Hex sha256 over the paths, modes and contents of everything under `dir`
+/// that isn't ignored, independent of git history.
+pub fn hash_dir(dir: &Path, ignore: &HashIgnore) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hash_entries(dir, dir, ignore, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Hex sha256 of a single file's contents.
+pub fn hash_file(file: &Path) -> Result<String> {
+    Ok(hex::encode(Sha256::digest(&std::fs::read(file)?)))
+}
+
+fn hash_entries(root: &Path, dir: &Path, ignore: &HashIgnore, hasher: &mut Sha256) -> Result<()> {
+    let mut entries = read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let rel = path.strip_prefix(root)?;
+        let meta = symlink_metadata(&path)?;
+        if ignore.is_ignored(&path_to_slash(rel), meta.is_dir()) {
+            log::debug!("Not hashing ignored path {:?}", path);
+            continue;
+        }
+        if meta.is_dir() {
+            hash_entries(root, &path, ignore, hasher)?;
+            continue;
+        }
+        let (kind, content) = if meta.file_type().is_symlink() {
+            (b'l', path_to_slash_bytes(&std::fs::read_link(&path)?))
+        } else if is_executable(&meta) {
+            (b'x', std::fs::read(&path)?)
+        } else {
+            (b'f', std::fs::read(&path)?)
+        };
+        hasher.update([kind]);
+        hasher.update(path_to_slash_bytes(rel));
+        hasher.update([0]);
+        hasher.update(Sha256::digest(&content));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_meta: &std::fs::Metadata) -> bool {
+    false
+}
@@ -1,43 +1,188 @@
-use std::fs::read_to_string;
+use regex::Regex;
+use std::fs::{read_dir, read_to_string, symlink_metadata};
 use std::io::Write;
 use std::path::Path;
 
+use crate::glob::glob_regex;
+use crate::manifest::{LoadOptions, Manifest};
 use crate::types::*;
 
+/// A line in a `--base` file marking where the generated rules go; without
+/// it the whole file comes before them.
+pub const BASE_RULES_MARKER: &str = "# avocado-build-helper: rules";
+
 pub fn run_dockerignore_creator(
     path: &Path,
+    load: &LoadOptions,
     dir: &str,
+    filenames: &[&str],
+    base: Option<&Path>,
     write_to_file: bool,
     no_include_ignore: bool,
 ) -> Result<(), anyhow::Error> {
-    let dockerignore_path = path.join(".dockerignore");
+    let manifest = load.load_manifest(path)?;
+    check_components_exist(&manifest.components, &[dir])?;
+    let (header, footer) = match base {
+        Some(base) => split_base(&read_to_string(base)?),
+        None => (String::new(), String::new()),
+    };
+    let mut rules = header;
+    rules.push_str(&generated_rules(&manifest, dir)?);
+    rules.push_str(&footer);
+    // on stdout only the first file's existing contents are merged
+    let filenames = if write_to_file {
+        filenames
+    } else {
+        &filenames[..1]
+    };
+    for filename in filenames {
+        let ignore_path = path.join(filename);
+        let contents = if !no_include_ignore && ignore_path.is_file() {
+            Some(read_to_string(&ignore_path)?)
+        } else {
+            None
+        };
+        let mut output: Box<dyn Write> = if write_to_file {
+            Box::new(
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&ignore_path)?,
+            )
+        } else {
+            Box::new(std::io::stdout())
+        };
+        output.write_all(rules.as_ref())?;
+        if let Some(d) = contents {
+            output.write_all(d.as_ref())?;
+        }
+    }
+    Ok(())
+}
+
+/// Exclude everything but the component and its transitive dependencies,
+/// then the manifest's `always_exclude` patterns.
+fn generated_rules(manifest: &Manifest, dir: &str) -> Result<String, CustomError> {
     let x = transitive_dependencies(
-        load_components(path),
+        manifest.components.clone(),
         std::slice::from_ref(&dir),
         true,
         true,
     )?;
-    let contents =
-        if !no_include_ignore && dockerignore_path.exists() && dockerignore_path.is_file() {
-            Some(read_to_string(&dockerignore_path)?)
-        } else {
-            None
-        };
-    let mut output: Box<dyn Write> = if write_to_file {
-        Box::new(
-            std::fs::OpenOptions::new()
-                .write(true)
-                .open(&dockerignore_path)?,
-        )
-    } else {
-        Box::new(std::io::stdout())
-    };
-    output.write_all("*\n".as_ref())?;
+    let mut rules = String::from("*\n");
     for i in x.iter() {
-        output.write_all(format!("!{}/**\n", i.dir).as_ref())?;
+        rules.push_str(&format!("!{}/**\n", normalize_dir(&i.dir)));
     }
-    if let Some(d) = contents {
-        output.write_all(d.as_ref())?;
+    for pattern in manifest.always_exclude.iter() {
+        rules.push_str(&format!("{}\n", pattern));
+    }
+    Ok(rules)
+}
+
+/// Parsed .dockerignore rules: the last rule matching a path or any of its
+/// parent directories decides whether it's excluded.
+struct IgnoreRules {
+    rules: Vec<(Regex, bool)>,
+}
+
+impl IgnoreRules {
+    fn parse(text: &str) -> IgnoreRules {
+        let rules = text
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| {
+                let (pattern, include) = match l.strip_prefix('!') {
+                    Some(p) => (p, true),
+                    None => (l, false),
+                };
+                let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+                (glob_regex(&normalize_dir(pattern)), include)
+            })
+            .collect();
+        IgnoreRules { rules }
+    }
+
+    fn excludes(&self, rel: &str) -> bool {
+        let mut paths = vec![rel];
+        paths.extend(rel.match_indices('/').map(|(i, _)| &rel[..i]));
+        self.rules
+            .iter()
+            .rev()
+            .find(|(re, _)| paths.iter().any(|p| re.is_match(p)))
+            .is_some_and(|(_, include)| !include)
+    }
+}
+
+/// Per component, most context bytes first: the files and bytes under its
+/// dir, and the bytes of its docker context (components.json's dir) after
+/// the rules gen-dockerignore would write for it.
+pub fn run_size(path: &Path, load: &LoadOptions) -> anyhow::Result<()> {
+    let manifest = load.load_manifest(path)?;
+    let mut files = Vec::new();
+    list_files(path, path, &mut files)?;
+    let mut sizes = Vec::new();
+    for comp in manifest.components.iter() {
+        let dir = normalize_dir(&comp.dir);
+        let own: Vec<u64> = files
+            .iter()
+            .filter(|(rel, _)| dir == "." || rel.starts_with(&format!("{}/", dir)))
+            .map(|(_, size)| *size)
+            .collect();
+        let rules = IgnoreRules::parse(&generated_rules(&manifest, &comp.dir)?);
+        let context: u64 = files
+            .iter()
+            .filter(|(rel, _)| !rules.excludes(rel))
+            .map(|(_, size)| *size)
+            .sum();
+        sizes.push((
+            context,
+            own.iter().sum::<u64>(),
+            own.len(),
+            comp.dir.as_str(),
+        ));
+    }
+    sizes.sort_by(|a, b| b.0.cmp(&a.0).then(a.3.cmp(b.3)));
+    for (context, bytes, count, dir) in sizes {
+        println!("{}\t{}\t{}\t{}", count, bytes, context, dir);
     }
     Ok(())
 }
+
+/// Every file under `dir` but outside `.git`, relative to `root`, with its
+/// size. Symlinks aren't followed.
+fn list_files(root: &Path, dir: &Path, files: &mut Vec<(String, u64)>) -> anyhow::Result<()> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let meta = symlink_metadata(&path)?;
+        if meta.is_dir() {
+            if path.file_name() != Some(".git".as_ref()) {
+                list_files(root, &path, files)?;
+            }
+        } else {
+            files.push((path_to_slash(path.strip_prefix(root)?), meta.len()));
+        }
+    }
+    Ok(())
+}
+
+fn split_base(base: &str) -> (String, String) {
+    let mut header = String::new();
+    let mut footer = String::new();
+    let mut seen_marker = false;
+    for line in base.lines() {
+        if !seen_marker && line.trim() == BASE_RULES_MARKER {
+            seen_marker = true;
+            continue;
+        }
+        let part = if seen_marker {
+            &mut footer
+        } else {
+            &mut header
+        };
+        part.push_str(line);
+        part.push('\n');
+    }
+    (header, footer)
+}
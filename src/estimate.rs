@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use crate::affected::affected_components;
+use crate::manifest::LoadOptions;
+use crate::state::BuildState;
+use crate::types::*;
+
+/// Predict how long build would take for `roots` (everything if empty) at
+/// `jobs`, from the durations recorded in `state`: levels run one after
+/// another, each packed longest-first onto the least busy of `jobs` workers.
+/// Components with no history count as the average of those with.
+pub fn run_estimate(
+    path: &Path,
+    load: &LoadOptions,
+    roots: &[&str],
+    jobs: usize,
+    state: &Path,
+) -> anyhow::Result<()> {
+    let state = BuildState::load(state)?;
+    let components = affected_components(path, load, roots, None, true)?;
+    let known: Vec<f64> = components
+        .iter()
+        .filter_map(|c| state.durations.get(&c.dir).copied())
+        .collect();
+    let fallback = if known.is_empty() {
+        0.0
+    } else {
+        known.iter().sum::<f64>() / known.len() as f64
+    };
+    let mut unknown = Vec::new();
+    let mut total = 0.0;
+    for level in toposort_levels(components)? {
+        let mut durations: Vec<f64> = level
+            .iter()
+            .map(|c| match state.durations.get(&c.dir) {
+                Some(d) => *d,
+                None => {
+                    unknown.push(c.dir.clone());
+                    fallback
+                }
+            })
+            .collect();
+        durations.sort_by(|a, b| b.partial_cmp(a).unwrap());
+        let mut workers = vec![0.0_f64; jobs.min(durations.len().max(1))];
+        for d in durations {
+            let least = workers
+                .iter_mut()
+                .min_by(|a, b| a.partial_cmp(b).unwrap())
+                .unwrap();
+            *least += d;
+        }
+        total += workers.iter().cloned().fold(0.0, f64::max);
+    }
+    println!("{:.1}s", total);
+    if !unknown.is_empty() {
+        unknown.sort();
+        eprintln!("No recorded durations for: {}", unknown.join(" "));
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+
+use crate::build::{log_to, open_log, prepare_log_dir};
+use crate::executor::CommandRegistry;
+use crate::manifest::LoadOptions;
+use crate::metrics;
+use crate::pool::for_each_parallel;
+use crate::types::*;
+
+pub struct ExecOptions<'a> {
+    /// Components of a level run at once.
+    pub jobs: usize,
+    /// Write each component's output to `<log_dir>/<slug>.log` instead of
+    /// the console.
+    pub log_dir: Option<&'a Path>,
+    /// Carry on with components that don't depend on a failed one.
+    pub keep_going: bool,
+    /// How the manifest is found and loaded.
+    pub load: LoadOptions,
+    /// Run only what the manifest allowlists, see `Hermetic`.
+    pub hermetic: bool,
+}
+
+/// Run `command` in the dir of each of `components`, level by level in
+/// topological order, with the same AVOCADO_* variables as props. Stops
+/// after the first level with a failure unless `keep_going`, which only
+/// skips the failed components' dependents.
+pub fn run_exec(
+    path: &Path,
+    components: Vec<Component>,
+    command: &[&str],
+    options: &ExecOptions,
+) -> anyhow::Result<()> {
+    let reg = CommandRegistry::new(path, &options.load, options.hermetic)?;
+    reg.check_allowed(command[0])?;
+    if let Some(log_dir) = options.log_dir {
+        prepare_log_dir(log_dir, components.iter().map(|c| c.dir.as_str()))?;
+    }
+    let failed = Mutex::new(Vec::new());
+    let mut skipped = Vec::new();
+    let mut blocked: HashSet<String> = HashSet::new();
+    for level in toposort_levels(components)? {
+        let (mut runnable, blocked_here): (Vec<Component>, Vec<Component>) = level
+            .into_iter()
+            .partition(|c| !c.dependencies.iter().any(|d| blocked.contains(d)));
+        for comp in blocked_here {
+            log::info!("Skipping {} after a failed dependency", comp.dir);
+            blocked.insert(comp.dir.clone());
+            skipped.push(comp.dir);
+        }
+        for_each_parallel(&mut runnable, options.jobs, |comp| {
+            match run_in(path, &reg, comp, command, options.log_dir) {
+                Err(e) if options.keep_going => {
+                    log::error!("{}", e);
+                    failed.lock().unwrap().push(comp.dir.clone());
+                    Ok(())
+                }
+                res => res,
+            }
+        })?;
+        blocked.extend(failed.lock().unwrap().iter().cloned());
+    }
+    let mut failed = failed.into_inner().unwrap();
+    if !failed.is_empty() {
+        failed.sort();
+        skipped.sort();
+        return Err(CustomError::ExecFailedError { failed, skipped }.into());
+    }
+    Ok(())
+}
+
+fn run_in(
+    path: &Path,
+    reg: &CommandRegistry,
+    comp: &Component,
+    command: &[&str],
+    log_dir: Option<&Path>,
+) -> anyhow::Result<()> {
+    let cmd = shell_words::join(command);
+    log::info!("Running in {}: {}", comp.dir, cmd);
+    let mut com = Command::new(command[0]);
+    com.args(&command[1..]).current_dir(path.join(&comp.dir));
+    reg.set_envs(&mut com, comp)?;
+    let (log_file, out) = open_log(log_dir, &comp.dir)?.unzip();
+    if let Some(out) = out.as_ref() {
+        log_to(&mut com, out)?;
+    }
+    let status = metrics::global()
+        .time_phase("exec", || com.status())
+        .map_err(|e| CustomError::CommandExecutionError {
+            cmd_name: comp.dir.clone(),
+            error: e,
+        })?;
+    if status.success() {
+        return Ok(());
+    }
+    let mut reason = match status.code() {
+        Some(c) => format!("exit code {} in {}", c, comp.dir),
+        None => format!("terminated by signal in {}", comp.dir),
+    };
+    if let Some(log_file) = log_file {
+        reason.push_str(&format!(", see {:?}", log_file));
+    }
+    Err(CustomError::UnsuccessfulCommandError { cmd, reason }.into())
+}
@@ -1,50 +1,136 @@
 use handlebars::Handlebars;
 use regex::Regex;
+use rhai::{Dynamic, Engine, Scope, AST};
 use serde_json::Value;
 use shell_words::split;
 use std::collections::HashMap;
-use std::process::{Command, Stdio};
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::time::Instant;
 use std::vec::Vec;
 
-use crate::types::{Component, CustomError};
+use crate::hasher::{hash_components, HashOptions};
+use crate::helpers::register_helpers;
+use crate::manifest::{Hermetic, LoadOptions};
+use crate::metrics::{self, PHASE_DURATION, PROP_DURATION, PROP_FAILURES};
+use crate::types::{check_components_exist, Component, CustomError};
+use crate::vcs::Repo;
+use crate::workspace::split_root;
 
-#[derive(Debug, Copy, Clone)]
+/// Which exit codes make a bool prop `true`, before any inversion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Truthiness {
+    pub true_codes: Vec<i32>,
+    pub invert: bool,
+}
+
+impl Default for Truthiness {
+    fn default() -> Truthiness {
+        Truthiness {
+            true_codes: vec![0],
+            invert: false,
+        }
+    }
+}
+
+impl Truthiness {
+    /// Commands killed by a signal have no code and are never in the set.
+    fn is_true(&self, code: Option<i32>) -> bool {
+        code.is_some_and(|c| self.true_codes.contains(&c)) != self.invert
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum CommandConfig {
-    ExecCommand { is_bool: bool },
-    ShellCommand { is_bool: bool },
+    ExecCommand {
+        is_bool: Option<Truthiness>,
+        stdin_json: bool,
+    },
+    ShellCommand {
+        is_bool: Option<Truthiness>,
+        stdin_json: bool,
+    },
     Template,
+    /// A rhai script, whose result becomes the prop as JSON.
+    Script,
 }
 
 impl CommandConfig {
     #[allow(dead_code)]
     pub fn new_command(is_shell: bool, is_bool: bool) -> CommandConfig {
+        let is_bool = if is_bool {
+            Some(Truthiness::default())
+        } else {
+            None
+        };
+        let stdin_json = false;
         if is_shell {
-            CommandConfig::ShellCommand { is_bool }
+            CommandConfig::ShellCommand {
+                is_bool,
+                stdin_json,
+            }
         } else {
-            CommandConfig::ExecCommand { is_bool }
+            CommandConfig::ExecCommand {
+                is_bool,
+                stdin_json,
+            }
         }
     }
 
     pub fn new_shell_command() -> CommandConfig {
-        CommandConfig::ShellCommand { is_bool: false }
+        CommandConfig::ShellCommand {
+            is_bool: None,
+            stdin_json: false,
+        }
     }
 
     pub fn new_exec_command() -> CommandConfig {
-        CommandConfig::ExecCommand { is_bool: false }
+        CommandConfig::ExecCommand {
+            is_bool: None,
+            stdin_json: false,
+        }
     }
 
     pub fn new_template() -> CommandConfig {
         CommandConfig::Template
     }
 
-    pub fn set_bool(self) -> CommandConfig {
+    pub fn new_script() -> CommandConfig {
+        CommandConfig::Script
+    }
+
+    pub fn set_bool(self, truthiness: Truthiness) -> CommandConfig {
+        let is_bool = Some(truthiness);
         match self {
-            CommandConfig::ExecCommand { .. } => CommandConfig::ExecCommand { is_bool: true },
-            CommandConfig::ShellCommand { .. } => CommandConfig::ShellCommand { is_bool: true },
+            CommandConfig::ExecCommand { stdin_json, .. } => CommandConfig::ExecCommand {
+                is_bool,
+                stdin_json,
+            },
+            CommandConfig::ShellCommand { stdin_json, .. } => CommandConfig::ShellCommand {
+                is_bool,
+                stdin_json,
+            },
             _ => panic!("Cannot set bool on templates"),
         }
     }
 
+    /// Write the component as JSON to the command's stdin.
+    pub fn set_stdin_json(self) -> CommandConfig {
+        match self {
+            CommandConfig::ExecCommand { is_bool, .. } => CommandConfig::ExecCommand {
+                is_bool,
+                stdin_json: true,
+            },
+            CommandConfig::ShellCommand { is_bool, .. } => CommandConfig::ShellCommand {
+                is_bool,
+                stdin_json: true,
+            },
+            _ => panic!("Cannot pipe stdin to templates"),
+        }
+    }
+
     pub fn is_shell_command(&self) -> bool {
         matches!(self, CommandConfig::ShellCommand { .. })
     }
@@ -58,6 +144,10 @@ impl CommandConfig {
         matches!(self, CommandConfig::Template)
     }
 
+    pub fn is_script(&self) -> bool {
+        matches!(self, CommandConfig::Script)
+    }
+
     pub fn is_command(&self) -> bool {
         matches!(
             self,
@@ -65,11 +155,24 @@ impl CommandConfig {
         )
     }
 
-    pub fn is_bool_result(&self) -> bool {
+    pub fn bool_result(&self) -> Option<&Truthiness> {
+        match self {
+            CommandConfig::ExecCommand { is_bool, .. }
+            | CommandConfig::ShellCommand { is_bool, .. } => is_bool.as_ref(),
+            CommandConfig::Template | CommandConfig::Script => None,
+        }
+    }
+
+    pub fn is_stdin_json(&self) -> bool {
         matches!(
             self,
-            CommandConfig::ExecCommand { is_bool: true }
-                | CommandConfig::ShellCommand { is_bool: true }
+            CommandConfig::ExecCommand {
+                stdin_json: true,
+                ..
+            } | CommandConfig::ShellCommand {
+                stdin_json: true,
+                ..
+            }
         )
     }
 }
@@ -78,6 +181,46 @@ pub struct CommandRegistry<'a> {
     commands: Vec<String>,
     is_shell_map: HashMap<String, CommandConfig>,
     handlebars: Handlebars<'a>,
+    engine: Engine,
+    scripts: HashMap<String, AST>,
+    repo_root: PathBuf,
+    manifest_dir: PathBuf,
+    manifest_file: PathBuf,
+    /// The workspace root the manifest is, whose name prefixes component dirs.
+    workspace_root: Option<String>,
+    /// Set under `--hermetic`, from the manifest's `hermetic` section.
+    hermetic: Option<Hermetic>,
+}
+
+/// With `hermetic`, the allowlists of the manifest in `path`, see `Hermetic`.
+pub fn hermetic_policy(
+    path: &Path,
+    load: &LoadOptions,
+    hermetic: bool,
+) -> anyhow::Result<Option<Hermetic>> {
+    if hermetic {
+        Ok(Some(load.load_manifest(path)?.hermetic.unwrap_or_default()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Fail unless `program` is allowlisted, when hermetic.
+pub fn check_hermetic(hermetic: Option<&Hermetic>, program: &str) -> Result<(), CustomError> {
+    match hermetic {
+        Some(h) if !h.commands.iter().any(|c| c == program) => Err(CustomError::HermeticError(
+            format!("{:?} is not in hermetic.commands", program),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// When hermetic, let `com` inherit only the allowlisted variables.
+pub fn restrict_env(hermetic: Option<&Hermetic>, com: &mut Command) {
+    if let Some(h) = hermetic {
+        com.env_clear();
+        com.envs(std::env::vars_os().filter(|(k, _)| h.env.iter().any(|e| k == e.as_str())));
+    }
 }
 
 fn new_shell_command(cmd: &str) -> Command {
@@ -87,7 +230,7 @@ fn new_shell_command(cmd: &str) -> Command {
 }
 
 fn new_command(cmd: &str) -> Result<Command, CustomError> {
-    let args = split(&cmd).map_err(|e| CustomError::CommandParseError {
+    let args = split(cmd).map_err(|e| CustomError::CommandParseError {
         cmd: cmd.to_owned(),
         error: e,
     })?;
@@ -97,13 +240,48 @@ fn new_command(cmd: &str) -> Result<Command, CustomError> {
 }
 
 impl<'a> CommandRegistry<'a> {
-    pub fn new() -> Self {
-        let reg = Handlebars::new();
-        CommandRegistry {
+    /// `path` is the directory containing components.json. With
+    /// `hermetic`, only what the manifest allowlists is run, see `Hermetic`.
+    pub fn new(path: &Path, load: &LoadOptions, hermetic: bool) -> anyhow::Result<Self> {
+        let mut reg = Handlebars::new();
+        register_helpers(&mut reg);
+        let mut engine = Engine::new();
+        // stdout is for our output
+        engine.on_print(|s| log::info!("{}", s));
+        engine.on_debug(|s, _, pos| log::debug!("{:?}: {}", pos, s));
+        let hermetic = hermetic_policy(path, load, hermetic)?;
+        Ok(CommandRegistry {
             commands: Vec::new(),
             is_shell_map: HashMap::new(),
             handlebars: reg,
-        }
+            engine,
+            scripts: HashMap::new(),
+            repo_root: Repo::discover(path)?.root,
+            manifest_dir: path.to_owned(),
+            manifest_file: load.manifest_file(path),
+            workspace_root: None,
+            hermetic,
+        })
+    }
+
+    /// For the manifest of workspace root `name`, whose components are
+    /// addressed as `name:dir`.
+    pub fn in_workspace_root(mut self, name: &str) -> Self {
+        self.workspace_root = Some(name.to_owned());
+        self
+    }
+
+    /// Under `--hermetic`, fail unless `program` is allowlisted.
+    pub fn check_allowed(&self, program: &str) -> Result<(), CustomError> {
+        check_hermetic(self.hermetic.as_ref(), program)
+    }
+
+    /// Give `com` the environment for `data`; under `--hermetic` nothing
+    /// else is inherited but the allowlisted variables.
+    pub fn set_envs(&self, com: &mut Command, data: &Component) -> anyhow::Result<()> {
+        restrict_env(self.hermetic.as_ref(), com);
+        com.envs(self.command_envs(data)?);
+        Ok(())
     }
 
     pub fn add_command(
@@ -112,29 +290,50 @@ impl<'a> CommandRegistry<'a> {
         command: &str,
         config: CommandConfig,
     ) -> Result<(), CustomError> {
+        if self.hermetic.is_some() && config.is_shell_command() {
+            return Err(CustomError::HermeticError(format!(
+                "shell command for property {}",
+                name
+            )));
+        }
         if self.is_shell_map.contains_key(name) {
             return Err(CustomError::DuplicatePropertyNameError {
                 name: name.to_owned(),
             });
         }
         self.commands.push(name.to_owned());
+        if config.is_script() {
+            let ast = self
+                .engine
+                .compile(command)
+                .map_err(|e| CustomError::ScriptError {
+                    prop_name: name.to_owned(),
+                    error: Box::new(e),
+                })?;
+            self.scripts.insert(name.to_owned(), ast);
+            self.is_shell_map.insert(name.to_owned(), config);
+            return Ok(());
+        }
         self.is_shell_map.insert(name.to_owned(), config);
         self.handlebars
             .register_template_string(name, command)
             .map_err(|e| CustomError::TemplateError {
                 prop_name: name.to_owned(),
-                error: e,
+                error: Box::new(e),
             })
     }
 
     pub fn run_command(&self, name: &str, data: &Component) -> anyhow::Result<String> {
         let config = self.is_shell_map.get(name).unwrap();
+        if config.is_script() {
+            return Ok(self.run_script(name, data)?.to_string());
+        }
         let cmd =
             self.handlebars
                 .render(name, data)
                 .map_err(|e| CustomError::TemplateRenderError {
                     cmd_name: name.to_owned(),
-                    error: e,
+                    error: Box::new(e),
                 })?;
         if config.is_template() {
             return Ok(cmd);
@@ -144,20 +343,20 @@ impl<'a> CommandRegistry<'a> {
         } else {
             new_command(&cmd)?
         };
-        let out = com
-            .envs(component_to_envs("AVOCADO_", data)?)
-            .stderr(Stdio::inherit())
-            .output()
-            .map_err(|e| CustomError::CommandExecutionError {
-                cmd_name: name.to_owned(),
-                error: e,
-            })?;
-        if config.is_bool_result() {
-            return Ok(match out.status.success() {
-                true => "true",
-                false => "false",
-            }
-            .to_owned());
+        self.check_allowed(&com.get_program().to_string_lossy())?;
+        self.set_envs(&mut com, data)?;
+        com.stderr(Stdio::inherit());
+        let out = if config.is_stdin_json() {
+            output_with_input(&mut com, serde_json::to_vec(data)?)
+        } else {
+            com.output()
+        }
+        .map_err(|e| CustomError::CommandExecutionError {
+            cmd_name: name.to_owned(),
+            error: e,
+        })?;
+        if let Some(truthiness) = config.bool_result() {
+            return Ok(truthiness.is_true(out.status.code()).to_string());
         }
         if !out.status.success() {
             anyhow::bail!(CustomError::UnsuccessfulCommandError {
@@ -172,19 +371,144 @@ impl<'a> CommandRegistry<'a> {
         }
     }
 
-    pub fn run_all(&self, data: &Component) -> anyhow::Result<Vec<(String, String)>> {
+    /// Evaluate the script prop `name` in process, with `data` in scope as
+    /// the map `component`.
+    pub fn run_script(&self, name: &str, data: &Component) -> anyhow::Result<Value> {
+        let run_error = |error| CustomError::ScriptRunError {
+            prop_name: name.to_owned(),
+            error,
+        };
+        let mut scope = Scope::new();
+        scope.push(
+            "component",
+            rhai::serde::to_dynamic(data).map_err(run_error)?,
+        );
+        let res: Dynamic = self
+            .engine
+            .eval_ast_with_scope(&mut scope, &self.scripts[name])
+            .map_err(run_error)?;
+        Ok(rhai::serde::from_dynamic(&res).map_err(run_error)?)
+    }
+
+    /// The environment commands are run with for `data`.
+    pub fn command_envs(&self, data: &Component) -> anyhow::Result<Vec<(String, OsString)>> {
+        let mut envs: Vec<(String, OsString)> = component_to_envs("AVOCADO_", data)?
+            .into_iter()
+            .map(|(k, v)| (k, v.into()))
+            .collect();
+        envs.extend(
+            self.location_envs(data)
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v)),
+        );
+        Ok(envs)
+    }
+
+    /// Where things are, set after the component's own fields so these
+    /// always win.
+    fn location_envs(&self, data: &Component) -> Vec<(&'static str, OsString)> {
+        vec![
+            ("AVOCADO_ROOT", self.repo_root.clone().into_os_string()),
+            (
+                "AVOCADO_COMPONENTS_FILE",
+                self.manifest_file.clone().into_os_string(),
+            ),
+            (
+                "AVOCADO_COMPONENT_PATH",
+                self.manifest_dir
+                    .join(match &self.workspace_root {
+                        Some(_) => split_root(&data.dir).1,
+                        None => &data.dir,
+                    })
+                    .into_os_string(),
+            ),
+        ]
+    }
+
+    /// Every prop's value: a string, or for scripts any JSON value.
+    pub fn run_all(&self, data: &Component) -> anyhow::Result<Vec<(String, Value)>> {
         self.commands
             .iter()
-            .map(|c| self.run_command(c, data).map(|v| (c.clone(), v)))
+            .map(|c| {
+                let start = Instant::now();
+                let res = if self.is_shell_map[c].is_script() {
+                    self.run_script(c, data)
+                } else {
+                    self.run_command(c, data).map(Value::from)
+                };
+                let labels = [("prop", c.as_str()), ("component", data.dir.as_str())];
+                metrics::global().add_duration(&PROP_DURATION, &labels, start.elapsed());
+                metrics::global().add_duration(
+                    &PHASE_DURATION,
+                    &[("phase", "exec")],
+                    start.elapsed(),
+                );
+                if res.is_err() {
+                    metrics::global().add(&PROP_FAILURES, &labels[..1], 1.0);
+                }
+                Ok((c.clone(), res?))
+            })
             .collect()
     }
 }
 
+/// Like `Command::output`, feeding `input` to stdin from another thread so
+/// a child that writes before reading everything can't deadlock us.
+fn output_with_input(com: &mut Command, input: Vec<u8>) -> std::io::Result<Output> {
+    let mut child = com.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || {
+        // the child may exit without reading it all, which isn't our error
+        let _ = stdin.write_all(&input);
+    });
+    let out = child.wait_with_output()?;
+    writer.join().unwrap();
+    Ok(out)
+}
+
 pub fn annotate_component(reg: &CommandRegistry, component: &mut Component) -> anyhow::Result<()> {
     let mut cres = reg.run_all(component)?;
     let m = component.rem.as_object_mut().unwrap();
     for (k, v) in cres.drain(..) {
-        m.insert(k, Value::from(v));
+        if !component.generated_props.contains(&k) {
+            component.generated_props.push(k.clone());
+        }
+        m.insert(k, v);
+    }
+    Ok(())
+}
+
+pub const ENV_FORMATS: &[&str] = &["sh", "json", "dotenv"];
+
+/// Print the environment commands are run with for the component `dir`,
+/// hashed, so prop commands can be debugged by hand after
+/// `eval $(avocado-build-helper env <dir>)`.
+pub fn run_env(path: &Path, dir: &str, format: &str, options: &HashOptions) -> anyhow::Result<()> {
+    let reg = CommandRegistry::new(path, &options.load, false)?;
+    let components = hash_components(path, options, |_| Ok(()))?;
+    check_components_exist(&components, &[dir])?;
+    let comp = components.iter().find(|c| c.dir == dir).unwrap();
+    let envs: Vec<(String, String)> = reg
+        .command_envs(comp)?
+        .into_iter()
+        .map(|(k, v)| (k, v.to_string_lossy().into_owned()))
+        .collect();
+    match format {
+        "json" => {
+            let m: serde_json::Map<String, Value> =
+                envs.into_iter().map(|(k, v)| (k, Value::from(v))).collect();
+            println!("{}", serde_json::to_string_pretty(&m)?);
+        }
+        "dotenv" => {
+            for (k, v) in envs {
+                println!("{}={}", k, Value::from(v));
+            }
+        }
+        _ => {
+            for (k, v) in envs {
+                println!("export {}={}", k, shell_words::quote(&v));
+            }
+        }
     }
     Ok(())
 }
@@ -0,0 +1,644 @@
+use handlebars::Handlebars;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::hasher::{hash_components, HashOptions};
+use crate::helpers::register_helpers;
+use crate::registry::{ImageRef, Registry};
+use crate::types::*;
+use crate::vcs::Vcs;
+
+/// Predicate type recorded by `cosign attest`.
+const COSIGN_PREDICATE_TYPE: &str =
+    "https://github.com/edwardgeorge/avocado-build-helper/component/v1";
+
+#[derive(Debug, Copy, Clone)]
+pub enum ExportFormat {
+    CodeOwners,
+    OciLabels,
+    Nix,
+    Earthly,
+    Skaffold,
+    CacheHints,
+    OciIndex,
+    Cosign,
+}
+
+impl ExportFormat {
+    pub const NAMES: &'static [&'static str] = &[
+        "codeowners",
+        "oci-labels",
+        "nix",
+        "earthly",
+        "skaffold",
+        "cache-hints",
+        "oci-index",
+        "cosign",
+    ];
+
+    pub fn from_name(name: &str) -> ExportFormat {
+        match name {
+            "codeowners" => ExportFormat::CodeOwners,
+            "oci-labels" => ExportFormat::OciLabels,
+            "nix" => ExportFormat::Nix,
+            "earthly" => ExportFormat::Earthly,
+            "skaffold" => ExportFormat::Skaffold,
+            "cache-hints" => ExportFormat::CacheHints,
+            "oci-index" => ExportFormat::OciIndex,
+            "cosign" => ExportFormat::Cosign,
+            _ => panic!("unknown export format {}", name),
+        }
+    }
+
+    fn needs_hashes(self) -> bool {
+        !matches!(
+            self,
+            ExportFormat::CodeOwners | ExportFormat::Nix | ExportFormat::Skaffold
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ExportOptions {
+    /// Assign unowned dependencies used only by one owned component to that
+    /// component's owners.
+    pub exclusive_dependencies: bool,
+    /// Remote whose URL is recorded as the image source.
+    pub remote: String,
+    pub hash_options: HashOptions,
+    /// Handlebars template for image names, defaults to the `image` field
+    /// or a slug of the dir.
+    pub image_template: Option<String>,
+    /// cosign: also run `cosign attest` with each predicate.
+    pub attest: bool,
+}
+
+pub fn run_export(
+    path: &Path,
+    format: ExportFormat,
+    options: &ExportOptions,
+) -> Result<(), anyhow::Error> {
+    let components = if format.needs_hashes() {
+        hash_components(path, &options.hash_options, |_| Ok(()))?
+    } else {
+        toposort_components(options.hash_options.load.load_components(path)?)?
+    };
+    // these name targets after the slug of their dir
+    if matches!(
+        format,
+        ExportFormat::Nix | ExportFormat::Earthly | ExportFormat::Skaffold
+    ) {
+        check_slugs(components.iter().map(|c| c.dir.as_str()))?;
+    }
+    let out = match format {
+        ExportFormat::CodeOwners => codeowners(path, &components, options)?,
+        ExportFormat::OciLabels => oci_labels(path, &components, options)?,
+        ExportFormat::Nix => nix(&components)?,
+        ExportFormat::Earthly => earthly(&components),
+        ExportFormat::Skaffold => skaffold(path, &components, options)?,
+        ExportFormat::CacheHints => cache_hints(&components, options)?,
+        ExportFormat::OciIndex => oci_index(path, &components, options)?,
+        ExportFormat::Cosign => cosign(path, &components, options)?,
+    };
+    print!("{}", out);
+    Ok(())
+}
+
+fn codeowners(
+    path: &Path,
+    components: &[Component],
+    options: &ExportOptions,
+) -> anyhow::Result<String> {
+    let vcs = Vcs::discover(path)?;
+    let dependencies = dependency_closures(components)?;
+    let dependents = dependent_closures(components)?;
+    let mut out = String::from("# Generated by avocado-build-helper from components.json\n");
+    for comp in components.iter().filter(|c| !c.owners.is_empty()) {
+        let mut dirs = vec![comp.dir.as_str()];
+        if options.exclusive_dependencies {
+            let closure = &dependencies[&comp.dir];
+            let exclusive = components
+                .iter()
+                .filter(|d| d.owners.is_empty() && closure.contains(&d.dir))
+                .filter(|d| {
+                    dependents[&d.dir]
+                        .iter()
+                        .all(|p| *p == comp.dir || closure.contains(p))
+                })
+                .map(|d| d.dir.as_str());
+            dirs.extend(exclusive);
+        }
+        for dir in dirs {
+            let rel = vcs.top().relative_path(&path.join(dir))?;
+            out.push_str(&format!(
+                "/{}/ {}\n",
+                path_to_slash(&rel).trim_end_matches('/'),
+                comp.owners.join(" ")
+            ));
+        }
+    }
+    Ok(out)
+}
+
+fn oci_labels(
+    path: &Path,
+    components: &[Component],
+    options: &ExportOptions,
+) -> anyhow::Result<String> {
+    let vcs = Vcs::discover(path)?;
+    let mut res = Map::new();
+    for comp in components {
+        let mut labels: Vec<(&str, String)> = vec![
+            (
+                "org.opencontainers.image.revision",
+                comp.commit_sha.clone().unwrap(),
+            ),
+            ("avocado.component", comp.dir.clone()),
+            ("avocado.tree_sha", comp.tree_sha.clone().unwrap()),
+            ("avocado.dependencies", comp.depsorted().join(",")),
+        ];
+        if let Some(url) = vcs.remote_url(&path.join(&comp.dir), &options.remote) {
+            labels.push(("org.opencontainers.image.source", url));
+        }
+        let args: Vec<String> = labels
+            .iter()
+            .flat_map(|(k, v)| vec!["--label".to_owned(), format!("{}={}", k, v)])
+            .collect();
+        let annotations: Map<String, Value> = labels
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), Value::from(v)))
+            .collect();
+        res.insert(
+            comp.dir.clone(),
+            json!({
+                "annotations": annotations,
+                "label_args": shell_words::join(args),
+            }),
+        );
+    }
+    Ok(serde_json::to_string_pretty(&res)? + "\n")
+}
+
+const NIX_PRELUDE: &str = r#"{ pkgs ? import <nixpkgs> { }, src ? ./. }:
+let
+  lib = pkgs.lib;
+  # restrict src to the given dirs (and the parents needed to reach them)
+  closureSrc = dirs: lib.cleanSourceWith {
+    inherit src;
+    filter = path: type:
+      let rel = lib.removePrefix (toString src + "/") (toString path);
+      in lib.any (d: rel == d || lib.hasPrefix (d + "/") rel || lib.hasPrefix (rel + "/") d) dirs;
+  };
+in
+lib.fix (self: {
+"#;
+
+fn nix(components: &[Component]) -> anyhow::Result<String> {
+    let closures = dependency_closures(components)?;
+    let mut out = String::from("# Generated by avocado-build-helper from components.json\n");
+    out.push_str(NIX_PRELUDE);
+    for comp in components {
+        let mut dirs: Vec<&str> = closures[&comp.dir].iter().map(String::as_str).collect();
+        dirs.push(&comp.dir);
+        dirs.sort_unstable();
+        let inputs = comp
+            .depsorted()
+            .iter()
+            .map(|d| format!("self.{}", nix_string(d)))
+            .collect::<Vec<_>>();
+        out.push_str(&format!(
+            "  {} = pkgs.stdenv.mkDerivation {{\n    \
+             name = {};\n    \
+             src = closureSrc {};\n    \
+             sourceRoot = \"source/{}\";\n    \
+             buildInputs = {};\n  \
+             }};\n",
+            nix_string(&comp.dir),
+            nix_string(&slug(&comp.dir)),
+            nix_list(dirs.iter().map(|d| nix_string(d)).collect()),
+            nix_escape(&comp.dir),
+            nix_list(inputs),
+        ));
+    }
+    out.push_str("})\n");
+    Ok(out)
+}
+
+fn nix_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+}
+
+fn nix_string(s: &str) -> String {
+    format!("\"{}\"", nix_escape(s))
+}
+
+fn nix_list(items: Vec<String>) -> String {
+    if items.is_empty() {
+        "[ ]".to_owned()
+    } else {
+        format!("[ {} ]", items.join(" "))
+    }
+}
+
+fn earthly(components: &[Component]) -> String {
+    let mut out = String::from("# Generated by avocado-build-helper from components.json\n");
+    out.push_str("VERSION 0.7\n\nall:\n");
+    for comp in components {
+        out.push_str(&format!("    BUILD +{}\n", slug(&comp.dir)));
+    }
+    for comp in components {
+        out.push_str(&format!("\n{}:\n", slug(&comp.dir)));
+        for dep in comp.depsorted() {
+            out.push_str(&format!("    BUILD +{}\n", slug(&dep)));
+        }
+        let mut from = format!("    FROM DOCKERFILE -f ./{}", comp.dockerfile_path());
+        if let Some(target) = &comp.target {
+            from.push_str(&format!(" --target {}", target));
+        }
+        for (k, v) in comp.build_args.iter() {
+            from.push_str(&format!(
+                " --build-arg {}",
+                shell_words::quote(&format!("{}={}", k, v))
+            ));
+        }
+        out.push_str(&format!(
+            "{} ./{}\n    SAVE IMAGE {}:{}\n",
+            from,
+            comp.dir.trim_end_matches('/'),
+            comp.image_name(),
+            comp.tree_sha_short.as_ref().unwrap()
+        ));
+    }
+    out
+}
+
+fn skaffold(
+    path: &Path,
+    components: &[Component],
+    options: &ExportOptions,
+) -> anyhow::Result<String> {
+    let images = ImageNames::new(options)?;
+    let by_dir: HashMap<&str, &Component> =
+        components.iter().map(|c| (c.dir.as_str(), c)).collect();
+    let images = components
+        .iter()
+        .filter(|c| deployable(path, c))
+        .map(|c| Ok((c.dir.as_str(), images.render(c)?)))
+        .collect::<Result<HashMap<_, _>, CustomError>>()?;
+    let mut out = String::from("# Generated by avocado-build-helper from components.json\n");
+    out.push_str("apiVersion: skaffold/v4beta6\nkind: Config\nbuild:\n  artifacts:\n");
+    for comp in components
+        .iter()
+        .filter(|c| images.contains_key(c.dir.as_str()))
+    {
+        out.push_str(&format!(
+            "    - image: {}\n      context: {}\n      docker:\n        dockerfile: {}\n",
+            yaml_string(&images[comp.dir.as_str()]),
+            yaml_string(&comp.dir),
+            yaml_string(&relative_to(&comp.dir, &comp.dockerfile_path()))
+        ));
+        if let Some(target) = &comp.target {
+            out.push_str(&format!("        target: {}\n", yaml_string(target)));
+        }
+        if !comp.build_args.is_empty() {
+            out.push_str("        buildArgs:\n");
+            for (k, v) in comp.build_args.iter() {
+                out.push_str(&format!(
+                    "          {}: {}\n",
+                    yaml_string(k),
+                    yaml_string(v)
+                ));
+            }
+        }
+        // artifacts can only require other artifacts, so look through
+        // non-deployable dependencies to the nearest deployable ones
+        let mut required = Vec::new();
+        let mut stack = comp.depsorted();
+        stack.reverse();
+        while let Some(dep) = stack.pop() {
+            if images.contains_key(dep.as_str()) {
+                if !required.contains(&dep) {
+                    required.push(dep);
+                }
+            } else {
+                stack.extend(by_dir[dep.as_str()].depsorted().into_iter().rev());
+            }
+        }
+        if !required.is_empty() {
+            out.push_str("      requires:\n");
+            for dep in required {
+                out.push_str(&format!(
+                    "        - image: {}\n          alias: {}\n",
+                    yaml_string(&images[dep.as_str()]),
+                    slug(&dep).replace('-', "_").to_uppercase()
+                ));
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// A cosign predicate per deployable component tying its image to the
+/// tree hash, dependency hashes and source commit it was built from, and
+/// with `attest` attached to the image with `cosign attest`.
+fn cosign(
+    path: &Path,
+    components: &[Component],
+    options: &ExportOptions,
+) -> anyhow::Result<String> {
+    let vcs = Vcs::discover(path)?;
+    let images = ImageNames::new(options)?;
+    let registry = Registry::new();
+    let tree_shas: HashMap<&str, &str> = components
+        .iter()
+        .map(|c| (c.dir.as_str(), c.tree_sha.as_deref().unwrap()))
+        .collect();
+    let mut res = Map::new();
+    for comp in components.iter().filter(|c| deployable(path, c)) {
+        let image = images.reference(comp)?;
+        let commit = comp.commit_sha.as_ref().unwrap();
+        let dir = path.join(&comp.dir);
+        let source = vcs
+            .remote_url(&dir, &options.remote)
+            .unwrap_or_else(|| vcs.repo_for(&dir).root.to_string_lossy().into_owned());
+        let dependencies: Map<String, Value> = comp
+            .depsorted()
+            .into_iter()
+            .map(|d| {
+                let sha = tree_shas[d.as_str()];
+                (d, Value::from(sha))
+            })
+            .collect();
+        let predicate = json!({
+            "builder": format!("avocado-build-helper@{}", env!("CARGO_PKG_VERSION")),
+            "component": comp.dir,
+            "tree_sha": comp.tree_sha,
+            "dependencies": dependencies,
+            "source": {
+                "uri": format!("git+{}@{}", source, commit),
+                "commit": commit,
+            },
+        });
+        if options.attest {
+            attest(&registry, &image, &predicate)?;
+        }
+        res.insert(
+            comp.dir.clone(),
+            json!({ "image": image, "predicate": predicate }),
+        );
+    }
+    Ok(serde_json::to_string_pretty(&res)? + "\n")
+}
+
+/// Attach `predicate` to `image` with `cosign attest`, by digest so it
+/// can't land on a different image if the tag moves meanwhile.
+fn attest(registry: &Registry, image: &str, predicate: &Value) -> anyhow::Result<()> {
+    let image_ref = ImageRef::parse(image)?;
+    let image = match registry.manifest(&image_ref)? {
+        Some(manifest) => image_ref.with_digest(&manifest.digest),
+        None => anyhow::bail!("can't attest {}, it isn't in the registry", image),
+    };
+    let dir = private_temp_dir()?;
+    let file = dir.join("predicate.json");
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&file)
+        .and_then(|mut f| f.write_all(&serde_json::to_vec(predicate)?));
+    if let Err(e) = written {
+        std::fs::remove_dir_all(&dir)?;
+        return Err(e.into());
+    }
+    log::info!("Attesting {}", image);
+    let status = Command::new("cosign")
+        .args([
+            "attest",
+            "--yes",
+            "--type",
+            COSIGN_PREDICATE_TYPE,
+            "--predicate",
+        ])
+        .arg(&file)
+        .arg(&image)
+        .status();
+    std::fs::remove_dir_all(&dir)?;
+    let status = status.map_err(|e| CustomError::CommandExecutionError {
+        cmd_name: "cosign".to_owned(),
+        error: e,
+    })?;
+    if !status.success() {
+        anyhow::bail!(CustomError::UnsuccessfulCommandError {
+            cmd: format!("cosign attest {}", image),
+            reason: match status.code() {
+                Some(c) => format!("exit code {}", c),
+                None => "terminated by signal".to_string(),
+            },
+        });
+    }
+    Ok(())
+}
+
+/// A new directory under the temp dir that only this user can read, so
+/// nobody can swap or read what is put in it.
+fn private_temp_dir() -> std::io::Result<PathBuf> {
+    let mut builder = DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    let mut n = 0;
+    loop {
+        let dir = std::env::temp_dir().join(format!("avocado-{}-{}", std::process::id(), n));
+        match builder.create(&dir) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => n += 1,
+            res => return res.map(|()| dir),
+        }
+    }
+}
+
+/// Components that build an image: those naming one or with a Dockerfile.
+fn deployable(path: &Path, comp: &Component) -> bool {
+    comp.image.is_some() || path.join(comp.dockerfile_path()).is_file()
+}
+
+/// An OCI image index referencing every deployable component's image by
+/// digest, looked up in the registry, so one artifact pins the images for
+/// this state of the manifest.
+fn oci_index(
+    path: &Path,
+    components: &[Component],
+    options: &ExportOptions,
+) -> anyhow::Result<String> {
+    let images = ImageNames::new(options)?;
+    let registry = Registry::new();
+    let mut manifests = Vec::new();
+    let mut missing = Vec::new();
+    for comp in components.iter().filter(|c| deployable(path, c)) {
+        let image = images.reference(comp)?;
+        let manifest = match registry.manifest(&ImageRef::parse(&image)?)? {
+            Some(manifest) => manifest,
+            None => {
+                missing.push(image);
+                continue;
+            }
+        };
+        manifests.push(json!({
+            "mediaType": manifest.media_type,
+            "digest": manifest.digest,
+            "size": manifest.size,
+            "annotations": {
+                "org.opencontainers.image.ref.name": image,
+                "avocado.component": comp.dir,
+                "avocado.tree_sha": comp.tree_sha,
+            },
+        }));
+    }
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "images not found in their registries: {}",
+            missing.join(", ")
+        );
+    }
+    let index = json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": manifests,
+    });
+    Ok(serde_json::to_string_pretty(&index)? + "\n")
+}
+
+/// Registry cache references for each component: one keyed on its own
+/// tree hash and one keyed only on its dependencies' tree hashes, so a
+/// change within the component still reuses layers from any earlier build
+/// against the same dependencies, whichever branch it was on.
+fn cache_hints(components: &[Component], options: &ExportOptions) -> anyhow::Result<String> {
+    let images = ImageNames::new(options)?;
+    let tree_shas: HashMap<&str, &str> = components
+        .iter()
+        .map(|c| (c.dir.as_str(), c.tree_sha.as_deref().unwrap()))
+        .collect();
+    let mut res = Map::new();
+    for comp in components {
+        let image = images.render(comp)?;
+        let mut deps = Sha256::new();
+        for dep in comp.depsorted() {
+            deps.update(format!("{} {}\n", dep, tree_shas[dep.as_str()]));
+        }
+        let deps_key = &hex::encode(deps.finalize())[..16];
+        let refs = [
+            format!(
+                "{}:buildcache-{}",
+                image,
+                comp.tree_sha_short.as_ref().unwrap()
+            ),
+            format!("{}:buildcache-deps-{}", image, deps_key),
+        ];
+        let cache_from: Vec<String> = refs
+            .iter()
+            .map(|r| format!("type=registry,ref={}", r))
+            .collect();
+        let cache_to: Vec<String> = refs
+            .iter()
+            .map(|r| format!("type=registry,ref={},mode=max", r))
+            .collect();
+        let args: Vec<String> = cache_from
+            .iter()
+            .flat_map(|r| vec!["--cache-from".to_owned(), r.clone()])
+            .chain(
+                cache_to
+                    .iter()
+                    .flat_map(|r| vec!["--cache-to".to_owned(), r.clone()]),
+            )
+            .collect();
+        res.insert(
+            comp.dir.clone(),
+            json!({
+                "cache_from": cache_from,
+                "cache_to": cache_to,
+                "args": shell_words::join(args),
+            }),
+        );
+    }
+    Ok(serde_json::to_string_pretty(&res)? + "\n")
+}
+
+/// Image names from `--image-template` when given, else `image_name()`.
+struct ImageNames<'a> {
+    reg: Option<Handlebars<'a>>,
+}
+
+impl<'a> ImageNames<'a> {
+    fn new(options: &ExportOptions) -> Result<ImageNames<'a>, CustomError> {
+        let tpl = match &options.image_template {
+            Some(tpl) => tpl,
+            None => return Ok(ImageNames { reg: None }),
+        };
+        let mut reg = Handlebars::new();
+        reg.register_escape_fn(handlebars::no_escape);
+        register_helpers(&mut reg);
+        reg.register_template_string("image", tpl)
+            .map_err(|e| CustomError::TemplateError {
+                prop_name: "image".to_owned(),
+                error: Box::new(e),
+            })?;
+        Ok(ImageNames { reg: Some(reg) })
+    }
+
+    /// A full image reference: the template should render one, without it
+    /// the image is tagged with the short tree hash.
+    fn reference(&self, comp: &Component) -> Result<String, CustomError> {
+        match &self.reg {
+            Some(_) => self.render(comp),
+            None => Ok(format!(
+                "{}:{}",
+                comp.image_name(),
+                comp.tree_sha_short.as_ref().unwrap()
+            )),
+        }
+    }
+
+    fn render(&self, comp: &Component) -> Result<String, CustomError> {
+        match &self.reg {
+            Some(reg) => reg
+                .render("image", comp)
+                .map_err(|e| CustomError::TemplateRenderError {
+                    cmd_name: "image".to_owned(),
+                    error: Box::new(e),
+                }),
+            None => Ok(comp.image_name()),
+        }
+    }
+}
+
+/// Lexically express `file` (relative to components.json) relative to `dir`.
+fn relative_to(dir: &str, file: &str) -> String {
+    let dir: Vec<&str> = dir
+        .split('/')
+        .filter(|p| !p.is_empty() && *p != ".")
+        .collect();
+    let file: Vec<&str> = file
+        .split('/')
+        .filter(|p| !p.is_empty() && *p != ".")
+        .collect();
+    let common = dir
+        .iter()
+        .zip(file.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut parts = vec![".."; dir.len() - common];
+    parts.extend(&file[common..]);
+    parts.join("/")
+}
+
+/// Double-quoted YAML scalar; JSON string syntax is a subset of it.
+pub fn yaml_string(s: &str) -> String {
+    Value::from(s).to_string()
+}
@@ -0,0 +1,170 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::manifest::LoadOptions;
+use crate::types::*;
+
+/// Print the dependency graph of `path` as DOT, or as SVG rendered by
+/// Graphviz `dot`, with `roots` and their transitive dependencies (or
+/// dependents) highlighted.
+pub fn run_graph(
+    path: &Path,
+    load: &LoadOptions,
+    roots: &[&str],
+    dependents: bool,
+    svg: bool,
+) -> anyhow::Result<()> {
+    let components = toposort_components(load.load_components(path)?)?;
+    check_components_exist(&components, roots)?;
+    let closure: HashSet<String> = if roots.is_empty() {
+        HashSet::new()
+    } else if dependents {
+        transitive_dependents(components.clone(), roots, true)?
+            .into_iter()
+            .map(|c| c.dir)
+            .collect()
+    } else {
+        transitive_dependencies(components.clone(), roots, true, false)?
+            .into_iter()
+            .map(|c| c.dir)
+            .collect()
+    };
+    let roots: HashSet<String> = roots.iter().map(|r| (*r).to_owned()).collect();
+    let dot = render_dot(&components, &roots, &closure);
+    if !svg {
+        print!("{}", dot);
+        return Ok(());
+    }
+    let mut child = match Command::new("dot")
+        .arg("-Tsvg")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::inherit())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            anyhow::bail!("dot not found on PATH, install Graphviz or drop --svg")
+        }
+        Err(e) => return Err(e.into()),
+    };
+    child.stdin.take().unwrap().write_all(dot.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(CustomError::UnsuccessfulCommandError {
+            cmd: "dot -Tsvg".to_owned(),
+            reason: status.to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Render the dependency graph in Graphviz DOT, edges pointing at
+/// dependencies. `roots` are filled and the rest of `closure` shaded, with
+/// the edges between them drawn in the same colour.
+pub fn render_dot(
+    components: &[Component],
+    roots: &HashSet<String>,
+    closure: &HashSet<String>,
+) -> String {
+    let mut out = String::from("digraph components {\n    rankdir=LR;\n");
+    for comp in components {
+        let style = if roots.contains(&comp.dir) {
+            " [style=filled, fillcolor=\"#d9534f\", fontcolor=white]"
+        } else if closure.contains(&comp.dir) {
+            " [style=filled, fillcolor=\"#f6d5d4\"]"
+        } else {
+            ""
+        };
+        out.push_str(&format!("    {}{};\n", dot_id(&comp.dir), style));
+    }
+    for comp in components {
+        for dep in comp.depsorted() {
+            let style = if closure.contains(&comp.dir) && closure.contains(&dep) {
+                " [color=\"#d9534f\"]"
+            } else {
+                ""
+            };
+            out.push_str(&format!(
+                "    {} -> {}{};\n",
+                dot_id(&comp.dir),
+                dot_id(&dep),
+                style
+            ));
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn dot_id(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+const NODE_WIDTH: usize = 200;
+const NODE_HEIGHT: usize = 28;
+const COLUMN: usize = 260;
+const ROW: usize = 40;
+
+/// Render the dependency graph as SVG, one column per level of
+/// `toposort_levels` with dependencies on the left, and the components in
+/// `highlight` filled in.
+pub fn render_svg(levels: &[Vec<Component>], highlight: &HashSet<String>) -> String {
+    let mut pos: HashMap<&str, (usize, usize)> = HashMap::new();
+    for (x, level) in levels.iter().enumerate() {
+        for (y, comp) in level.iter().enumerate() {
+            pos.insert(&comp.dir, (10 + x * COLUMN, 10 + y * ROW));
+        }
+    }
+    let width = 20 + levels.len().saturating_sub(1) * COLUMN + NODE_WIDTH;
+    let height = 20 + levels.iter().map(Vec::len).max().unwrap_or(0) * ROW;
+    let mut out = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"sans-serif\" font-size=\"12\">\n",
+        width, height
+    );
+    for comp in levels.iter().flatten() {
+        let (x, y) = pos[comp.dir.as_str()];
+        for dep in comp.depsorted() {
+            if let Some((dx, dy)) = pos.get(dep.as_str()) {
+                out.push_str(&format!(
+                    "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"#999\"/>\n",
+                    x,
+                    y + NODE_HEIGHT / 2,
+                    dx + NODE_WIDTH,
+                    dy + NODE_HEIGHT / 2
+                ));
+            }
+        }
+    }
+    for comp in levels.iter().flatten() {
+        let (x, y) = pos[comp.dir.as_str()];
+        let fill = if highlight.contains(&comp.dir) {
+            "#fdd"
+        } else {
+            "#fff"
+        };
+        out.push_str(&format!(
+            "<g><title>{dir}</title><rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"4\" fill=\"{}\" stroke=\"#333\"/>\
+             <text x=\"{}\" y=\"{}\">{dir}</text></g>\n",
+            x,
+            y,
+            NODE_WIDTH,
+            NODE_HEIGHT,
+            fill,
+            x + 6,
+            y + NODE_HEIGHT / 2 + 4,
+            dir = xml_escape(&comp.dir),
+        ));
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -1,124 +1,708 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryInto;
 use std::hash::Hash;
 use std::io::{stdout, Write};
-use std::path::Path;
-use std::process::Command;
-use std::str::from_utf8;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use crate::canonical::to_canonical_string;
+use crate::content::{hash_dir, hash_file, HashIgnore};
+use crate::manifest::{is_yaml, is_yaml_text, LoadOptions};
+use crate::metrics::{self, COMPONENTS_HASHED, HASH_DURATION, PHASE_DURATION};
+use crate::pool::for_each_parallel;
 use crate::types::*;
+use crate::vcs::Vcs;
+use crate::workspace::{split_root, Workspace};
+use crate::yaml;
 
+#[derive(Debug, Clone)]
+pub struct HashOptions {
+    pub remove_dependencies: bool,
+    pub include_short_shas: bool,
+    pub follow_renames: bool,
+    /// Derive tree hashes from working tree contents rather than the last
+    /// commit touching each dir.
+    pub content_hash: bool,
+    /// tree_sha overrides by component dir, taking precedence over any
+    /// `hash_override` in the manifest.
+    pub pins: BTreeMap<String, String>,
+    /// Mixed into every node so separate pipelines get distinct hashes.
+    pub salt: Option<String>,
+    /// Mix uncommitted changes under each component into its tree hash.
+    pub include_dirty: bool,
+    /// Components whose sources are found concurrently, by default one per
+    /// CPU; tree hashes are still combined in dependency order.
+    pub jobs: usize,
+    /// Components whose props are evaluated concurrently, once all hashes
+    /// are known; 1 evaluates each component's props as it is hashed.
+    pub prop_jobs: usize,
+    /// How the manifest is found and loaded.
+    pub load: LoadOptions,
+}
+
+impl Default for HashOptions {
+    fn default() -> HashOptions {
+        HashOptions {
+            remove_dependencies: false,
+            include_short_shas: false,
+            follow_renames: false,
+            content_hash: false,
+            pins: BTreeMap::new(),
+            salt: None,
+            include_dirty: false,
+            jobs: default_jobs(),
+            prop_jobs: 0,
+            load: LoadOptions::default(),
+        }
+    }
+}
+
+/// One job per CPU available to us.
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Where the components hash-components hashes are listed.
+pub enum Source<'a> {
+    /// The manifest in the dir being hashed.
+    Manifest,
+    /// Manifest text read from elsewhere, such as stdin.
+    Text(&'a str),
+    Workspace(&'a Workspace),
+}
+
+/// Print the hashed components of `source` as JSON: compact, pretty
+/// printed, or with `canonical` in RFC 8785 form. For a YAML manifest
+/// they're printed as YAML instead, unless `canonical`.
 pub fn run_hasher<F>(
     path: &Path,
+    source: Source,
     pretty_print: bool,
-    remove_dependencies: bool,
-    include_short_shas: bool,
+    canonical: bool,
+    options: &HashOptions,
     post_process: F,
 ) -> Result<(), anyhow::Error>
 where
-    F: Fn(&mut Component) -> anyhow::Result<()>,
+    F: Fn(&mut Component) -> anyhow::Result<()> + Sync,
 {
-    let mut x = load_components(path);
-    x = toposort_components(x)?;
-    let mut n: HashMap<String, (i32, [u8; 32])> = HashMap::new();
-    let x: Result<Vec<_>> = x
-        .iter_mut()
-        .map(|comp| {
-            log::debug!(
-                "Calculating hashes for {}, dependencies: {:?}",
-                comp.dir,
-                comp.dependencies
-            );
-            let commit_hash = hash_for_dir(path, &path.join(&comp.dir)).unwrap();
-            let res = hash_for_node(&commit_hash, &comp.depsorted(), &n);
-            n.insert(comp.dir.to_owned(), res);
-            let tree_hex = hex::encode(res.1);
-            if include_short_shas {
-                comp.commit_sha_short = Some(commit_hash[..8].to_owned());
-                comp.tree_sha_short = Some(tree_hex[..16].to_owned());
-            }
-            comp.commit_sha = Some(commit_hash);
-            comp.tree_sha = Some(tree_hex);
-            if remove_dependencies {
-                comp.dependencies = Vec::new();
-            }
-            post_process(comp)?;
-            Ok(comp)
-        })
-        .collect();
-    let y = x?;
-    let json = if pretty_print {
+    let (y, as_yaml) = match source {
+        Source::Manifest => (
+            hash_components(path, options, post_process)?,
+            is_yaml(&options.load.manifest_file(path)),
+        ),
+        Source::Text(text) => (
+            hash_manifest_text(path, text, options, post_process)?,
+            is_yaml_text(text),
+        ),
+        Source::Workspace(workspace) => (
+            hash_workspace_components(workspace, options, post_process)?,
+            false,
+        ),
+    };
+    let text = if canonical {
+        to_canonical_string(&serde_json::to_value(&y)?)
+    } else if as_yaml {
+        // keep to the format the manifest is written in
+        yaml::to_string(&serde_json::to_value(&y)?)
+    } else if pretty_print {
         serde_json::to_string_pretty(&y)?
     } else {
         serde_json::to_string(&y)?
     };
-    stdout().write_all(json.as_ref())?;
+    metrics::global().time_phase("write", || stdout().write_all(text.as_ref()))?;
     Ok(())
 }
 
-fn hash_for_dir(git_dir: &Path, path: &Path) -> Result<String> {
-    let out = Command::new("git")
-        .args(&[
-            "-C".as_ref(),
-            git_dir,
-            "log".as_ref(),
-            "-1".as_ref(),
-            "--pretty=format:%H".as_ref(),
-            path,
-        ])
-        .output()?;
-    if out.status.success() {
-        let r = String::from(from_utf8(&out.stdout)?.trim());
-        Ok(r)
+/// Load components and annotate them with hashes, in topological order.
+pub fn hash_components<F>(
+    path: &Path,
+    options: &HashOptions,
+    post_process: F,
+) -> Result<Vec<Component>>
+where
+    F: Fn(&mut Component) -> anyhow::Result<()> + Sync,
+{
+    Ok(hash_components_with_proofs(path, options, post_process)?.0)
+}
+
+/// Like `hash_components`, also returning what went into each tree hash.
+pub fn hash_components_with_proofs<F>(
+    path: &Path,
+    options: &HashOptions,
+    post_process: F,
+) -> Result<(Vec<Component>, HashMap<String, Proof>)>
+where
+    F: Fn(&mut Component) -> anyhow::Result<()> + Sync,
+{
+    hash_loaded(
+        path,
+        options.load.load_components(path)?,
+        options,
+        post_process,
+    )
+}
+
+/// Like `hash_components`, for the components of manifest `text` read
+/// elsewhere, such as stdin, rather than the manifest in `path`.
+pub fn hash_manifest_text<F>(
+    path: &Path,
+    text: &str,
+    options: &HashOptions,
+    post_process: F,
+) -> Result<Vec<Component>>
+where
+    F: Fn(&mut Component) -> anyhow::Result<()> + Sync,
+{
+    let components = options.load.load_manifest_text(path, text)?.components;
+    Ok(hash_loaded(path, components, options, post_process)?.0)
+}
+
+fn hash_loaded<F>(
+    path: &Path,
+    components: Vec<Component>,
+    options: &HashOptions,
+    post_process: F,
+) -> Result<(Vec<Component>, HashMap<String, Proof>)>
+where
+    F: Fn(&mut Component) -> anyhow::Result<()> + Sync,
+{
+    let mut roots = BTreeMap::new();
+    roots.insert(String::new(), Root::open(path, options)?);
+    hash_sorted(
+        toposort_components(components)?,
+        &roots,
+        options,
+        post_process,
+    )
+}
+
+/// Like `hash_components`, over every root of a workspace.
+pub fn hash_workspace_components<F>(
+    workspace: &Workspace,
+    options: &HashOptions,
+    post_process: F,
+) -> Result<Vec<Component>>
+where
+    F: Fn(&mut Component) -> anyhow::Result<()> + Sync,
+{
+    let mut roots = BTreeMap::new();
+    for (name, path) in workspace.roots.iter() {
+        roots.insert(name.clone(), Root::open(path, options)?);
+    }
+    let components = toposort_components(workspace.load_components(&options.load)?)?;
+    Ok(hash_sorted(components, &roots, options, post_process)?.0)
+}
+
+/// A dir holding components.json, and the repositories its components
+/// live in.
+struct Root {
+    path: PathBuf,
+    vcs: Vcs,
+    ignore: Option<HashIgnore>,
+    /// Paths with uncommitted changes, when hashing them.
+    dirty: Vec<PathBuf>,
+}
+
+impl Root {
+    fn open(path: &Path, options: &HashOptions) -> Result<Root> {
+        let vcs = Vcs::discover(path)?;
+        Ok(Root {
+            path: path.to_owned(),
+            dirty: if options.include_dirty {
+                vcs.dirty_paths()?
+            } else {
+                Vec::new()
+            },
+            vcs,
+            ignore: if options.content_hash {
+                Some(HashIgnore::load(path)?)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+/// The root name, url and ref of a remote dependency.
+type RemoteKey = (String, String, String);
+
+/// Hash components already in topological order, resolving each address
+/// against the root it names.
+fn hash_sorted<F>(
+    mut x: Vec<Component>,
+    roots: &BTreeMap<String, Root>,
+    options: &HashOptions,
+    post_process: F,
+) -> Result<(Vec<Component>, HashMap<String, Proof>)>
+where
+    F: Fn(&mut Component) -> anyhow::Result<()> + Sync,
+{
+    let missing: Vec<String> = options
+        .pins
+        .keys()
+        .filter(|p| !x.iter().any(|c| c.dir == **p))
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        return Err(CustomError::MissingComponentError(missing).into());
+    }
+    // git (or content hashing) dominates and doesn't depend on other
+    // components, so sources are found concurrently before the tree hashes
+    // are combined in dependency order
+    // remotes are resolved from the root of the component naming them, as
+    // relative urls and credentials depend on that repo
+    let mut remotes: Vec<(RemoteKey, Option<String>)> = x
+        .iter()
+        .flat_map(|c| {
+            let root = split_address(roots, &c.dir).0;
+            c.remote_dependencies
+                .iter()
+                .map(move |d| (root.to_owned(), d.git.clone(), d.reference.clone()))
+        })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|k| (k, None))
+        .collect();
+    for_each_parallel(
+        &mut remotes,
+        options.jobs,
+        |((root, git, reference), commit)| {
+            let root = roots.get(root.as_str()).ok_or_else(|| {
+                CustomError::WorkspaceError(format!("no root named {:?} in the workspace", root))
+            })?;
+            *commit = Some(root.vcs.remote_commit(git, reference)?);
+            Ok(())
+        },
+    )?;
+    let remote_commits: HashMap<RemoteKey, String> = remotes
+        .into_iter()
+        .map(|(k, commit)| (k, commit.unwrap()))
+        .collect();
+    let last_commits = last_commits(&x, roots, options)?;
+    let mut sources: Vec<(&Component, Option<Sources>)> = x.iter().map(|c| (c, None)).collect();
+    for_each_parallel(&mut sources, options.jobs, |(comp, res)| {
+        *res = Some(find_sources(
+            comp,
+            roots,
+            &remote_commits,
+            &last_commits,
+            options,
+        )?);
+        Ok(())
+    })?;
+    let sources: Vec<Sources> = sources.into_iter().map(|(_, s)| s.unwrap()).collect();
+    let mut n: HashMap<String, (i32, [u8; 32])> = HashMap::new();
+    let mut proofs = HashMap::new();
+    for (comp, sources) in x.iter_mut().zip(sources) {
+        log::debug!(
+            "Calculating hashes for {}, dependencies: {:?}",
+            comp.dir,
+            comp.dependencies
+        );
+        let start = Instant::now();
+        let Sources {
+            commit_hash,
+            source_hash,
+            inputs,
+            dirty,
+            elapsed,
+        } = sources;
+        let node_hash = if inputs.is_empty() {
+            source_hash.clone()
+        } else {
+            fold_inputs(&source_hash, &inputs)
+        };
+        let deps = comp.depsorted();
+        let (depth, preimage) = node_preimage(&node_hash, &deps, &n, options.salt.as_deref());
+        let mut res = (depth, sha256(&preimage));
+        let pin = options.pins.get(&comp.dir).or(comp.hash_override.as_ref());
+        if let Some(pin) = pin {
+            log::debug!("Using pinned tree hash {} for {}", pin, comp.dir);
+            res.1 = parse_pin(&comp.dir, pin)?;
+        }
+        let tree_hex = hex::encode(res.1);
+        let children = deps
+            .iter()
+            .enumerate()
+            .map(|(i, d)| ProofChild {
+                component: d.clone(),
+                offset: i as u32,
+                depth: n[d].0 as u16,
+                tree_sha: hex::encode(n[d].1),
+                last: i == deps.len() - 1,
+            })
+            .collect();
+        proofs.insert(
+            comp.dir.clone(),
+            Proof {
+                component: comp.dir.clone(),
+                layout: PROOF_LAYOUT,
+                source_hash,
+                inputs,
+                node_hash,
+                depth: depth as u16,
+                children,
+                salt: options.salt.clone(),
+                preimage: hex::encode(&preimage),
+                tree_sha: tree_hex.clone(),
+                pinned: pin.is_some(),
+            },
+        );
+        n.insert(comp.dir.to_owned(), res);
+        if options.include_short_shas {
+            // uncommitted components (with include_dirty) have no commit
+            comp.commit_sha_short = Some(commit_hash.get(..8).unwrap_or(&commit_hash).to_owned());
+            comp.tree_sha_short = Some(tree_hex[..16].to_owned());
+        }
+        comp.commit_sha = Some(commit_hash);
+        comp.tree_sha = Some(tree_hex);
+        comp.dirty = if dirty { Some(true) } else { None };
+        let elapsed = elapsed + start.elapsed();
+        let labels = [("component", comp.dir.as_str())];
+        metrics::global().add_duration(&HASH_DURATION, &labels, elapsed);
+        metrics::global().add_duration(&PHASE_DURATION, &[("phase", "hash")], elapsed);
+        metrics::global().add(&COMPONENTS_HASHED, &[], 1.0);
+        if options.remove_dependencies {
+            comp.dependencies = Vec::new();
+        }
+        if options.prop_jobs <= 1 {
+            post_process(comp)?;
+        }
+    }
+    if options.prop_jobs > 1 {
+        for_each_parallel(&mut x, options.prop_jobs, &post_process)?;
+    }
+    Ok((x, proofs))
+}
+
+/// What a component's node hash is made from, besides its dependencies.
+struct Sources {
+    commit_hash: String,
+    source_hash: String,
+    inputs: Vec<Input>,
+    /// Whether uncommitted changes are among the inputs.
+    dirty: bool,
+    /// Time taken finding them.
+    elapsed: Duration,
+}
+
+/// Split an address into its root name and the path in that root. Outside a
+/// workspace the only root is unnamed and addresses are plain dirs, colons
+/// and all.
+fn split_address<'a>(roots: &BTreeMap<String, Root>, address: &'a str) -> (&'a str, &'a str) {
+    if roots.contains_key("") {
+        ("", address)
+    } else {
+        split_root(address)
+    }
+}
+
+fn root_of<'a>(roots: &'a BTreeMap<String, Root>, address: &str) -> Result<&'a Root> {
+    let name = split_address(roots, address).0;
+    Ok(roots.get(name).ok_or_else(|| {
+        CustomError::WorkspaceError(format!("{:?} isn't in any root of the workspace", address))
+    })?)
+}
+
+/// The root an address is in and its path there.
+fn locate<'a>(roots: &'a BTreeMap<String, Root>, address: &str) -> Result<(&'a Root, PathBuf)> {
+    let root = root_of(roots, address)?;
+    Ok((root, root.path.join(split_address(roots, address).1)))
+}
+
+/// Last commits keyed by absolute path and ignore patterns.
+type LastCommits = HashMap<(PathBuf, Vec<String>), String>;
+
+/// The last commits `find_sources` needs, found with one history walk per
+/// root rather than a lookup per path. Following renames goes path by path,
+/// so is left to `find_sources`, as are addresses that don't resolve.
+fn last_commits(
+    x: &[Component],
+    roots: &BTreeMap<String, Root>,
+    options: &HashOptions,
+) -> Result<LastCommits> {
+    let mut res = HashMap::new();
+    if options.follow_renames {
+        return Ok(res);
+    }
+    let mut queries: BTreeMap<String, BTreeSet<(PathBuf, Vec<String>)>> = BTreeMap::new();
+    for comp in x {
+        let wanted = std::iter::once((comp.dir.clone(), comp.ignore.clone()))
+            .chain(comp.external_dockerfile().map(|d| (d, Vec::new())))
+            .chain(comp.external_paths().into_iter().map(|p| (p, Vec::new())));
+        for (address, ignore) in wanted {
+            if let Ok((_, path)) = locate(roots, &address) {
+                let root = split_address(roots, &address).0.to_owned();
+                queries.entry(root).or_default().insert((path, ignore));
+            }
+        }
+    }
+    for (root, queries) in queries {
+        let queries: Vec<_> = queries.into_iter().collect();
+        let commits = roots[root.as_str()].vcs.last_commits(&queries)?;
+        res.extend(queries.into_iter().zip(commits));
+    }
+    Ok(res)
+}
+
+fn find_sources(
+    comp: &Component,
+    roots: &BTreeMap<String, Root>,
+    remote_commits: &HashMap<RemoteKey, String>,
+    last_commits: &LastCommits,
+    options: &HashOptions,
+) -> Result<Sources> {
+    let locate = |address: &str| locate(roots, address);
+    let last_commit = |root: &Root, path: &Path, ignore: &[String]| match last_commits
+        .get(&(path.to_owned(), ignore.to_vec()))
+    {
+        Some(commit) => Ok(commit.clone()),
+        None => root.vcs.last_commit(path, ignore, options.follow_renames),
+    };
+    let start = Instant::now();
+    let (root, dir) = locate(&comp.dir)?;
+    let commit_hash = last_commit(root, &dir, &comp.ignore)?;
+    let source_hash = match &root.ignore {
+        Some(ignore) if comp.ignore.is_empty() => hash_dir(&dir, ignore)?,
+        Some(ignore) => hash_dir(&dir, &ignore.extended(&comp.ignore))?,
+        None => commit_hash.clone(),
+    };
+    let mut inputs = Vec::new();
+    if let Some(dockerfile) = comp.external_dockerfile() {
+        let (root, file) = locate(&dockerfile)?;
+        if !file.is_file() {
+            anyhow::bail!("dockerfile {} of {} does not exist", dockerfile, comp.dir);
+        }
+        let file_hash = if root.ignore.is_some() {
+            hash_file(&file)?
+        } else {
+            last_commit(root, &file, &[])?
+        };
+        inputs.push(Input {
+            path: dockerfile,
+            hash: file_hash,
+        });
+    }
+    // extra paths tracked alongside the dir also move its commit forward
+    let mut commits = vec![commit_hash.clone()];
+    let mut extras_dirty = false;
+    for extra in comp.external_paths() {
+        let (extra_root, path) = locate(&extra)?;
+        if !path.exists() {
+            anyhow::bail!("extra path {} of {} does not exist", extra, comp.dir);
+        }
+        let commit = last_commit(extra_root, &path, &[])?;
+        if commit.is_empty() {
+            anyhow::bail!("extra path {} of {} has no commits", extra, comp.dir);
+        }
+        let same_repo = std::ptr::eq(extra_root, root)
+            && root.vcs.repo_for(&path).root == root.vcs.repo_for(&dir).root;
+        if same_repo {
+            commits.push(commit.clone());
+        }
+        let hash = match &extra_root.ignore {
+            Some(ignore) if path.is_dir() => hash_dir(&path, ignore)?,
+            Some(_) => hash_file(&path)?,
+            None => commit,
+        };
+        let dirty: Vec<&PathBuf> = extra_root
+            .dirty
+            .iter()
+            .filter(|p| p.starts_with(&path))
+            .collect();
+        let dirty_hash = if dirty.is_empty() {
+            None
+        } else {
+            Some(hash_dirty(&path, &dirty)?)
+        };
+        inputs.push(Input {
+            path: extra.clone(),
+            hash,
+        });
+        if let Some(hash) = dirty_hash {
+            extras_dirty = true;
+            inputs.push(Input {
+                path: format!("{}:dirty", extra),
+                hash,
+            });
+        }
+    }
+    let commit_hash = if commits.len() > 1 {
+        let commits: Vec<&str> = commits.iter().map(String::as_str).collect();
+        root.vcs.newest_commit(&dir, &commits)?
     } else {
-        match out.status.code() {
-            Some(c) => Err(anyhow!(
-                "git command exited with error code: {}\n{}",
-                c,
-                from_utf8(&out.stderr)?
-            )),
-            None => Err(anyhow!("git command exited with signal")),
+        commit_hash
+    };
+    let mut remote_inputs: Vec<Input> = comp
+        .remote_dependencies
+        .iter()
+        .map(|dep| Input {
+            path: format!("{}#{}:{}", dep.git, dep.reference, dep.component),
+            hash: remote_commits[&(
+                split_address(roots, &comp.dir).0.to_owned(),
+                dep.git.clone(),
+                dep.reference.clone(),
+            )]
+                .clone(),
+        })
+        .collect();
+    remote_inputs.sort_by(|a, b| a.path.cmp(&b.path));
+    inputs.extend(remote_inputs);
+    let ignored = HashIgnore::new(&comp.ignore);
+    let dirty: Vec<&PathBuf> = root
+        .dirty
+        .iter()
+        .filter(|p| match p.strip_prefix(&dir) {
+            Ok(rel) => !ignored.ignores(&path_to_slash(rel)),
+            Err(_) => false,
+        })
+        .collect();
+    if !dirty.is_empty() {
+        inputs.push(Input {
+            path: format!("{}:dirty", comp.dir),
+            hash: hash_dirty(&dir, &dirty)?,
+        });
+    }
+    Ok(Sources {
+        commit_hash,
+        source_hash,
+        inputs,
+        dirty: extras_dirty || !dirty.is_empty(),
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Hash of the working tree state of changed paths under `dir`: each
+/// path with its content, or as deleted.
+fn hash_dirty(dir: &Path, paths: &[&PathBuf]) -> Result<String> {
+    // *([path length u32] path [0 u8] | [1 u8] content hash)
+    let mut hasher = Sha256::new();
+    for path in paths {
+        let rel = path_to_slash_bytes(path.strip_prefix(dir)?);
+        hasher.update((rel.len() as u32).to_be_bytes());
+        hasher.update(&rel);
+        if path.is_file() {
+            hasher.update([1]);
+            hasher.update(hex::decode(hash_file(path)?)?);
+        } else {
+            hasher.update([0]);
         }
     }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// A file outside the component's dir, or a component in another
+/// repository, that its hash also covers.
+#[derive(Debug, Clone, Serialize)]
+pub struct Input {
+    pub path: String,
+    pub hash: String,
 }
 
-fn hash_for_node<S, T>(
+/// Combine a node hash with those of its inputs.
+fn fold_inputs(node_hash: &str, inputs: &[Input]) -> String {
+    // [node hash] * ([path length u32] path hash)
+    let mut hasher = Sha256::new();
+    hasher.update(hex::decode(node_hash).unwrap());
+    for input in inputs {
+        hasher.update((input.path.len() as u32).to_be_bytes());
+        hasher.update(input.path.as_bytes());
+        hasher.update(hex::decode(&input.hash).unwrap());
+    }
+    hex::encode(hasher.finalize())
+}
+
+const PROOF_LAYOUT: &str = "tree_sha = sha256([depth u16] node_hash *child [2 u8] \
+                            ?([salt length u32] salt)); \
+                            child = [offset u32] [depth u16] tree_sha [last u8 = 0/1]; \
+                            integers big-endian. node_hash = source_hash, or when there \
+                            are inputs sha256(source_hash *([path length u32] path hash))";
+
+/// Everything that went into a component's tree hash, enough for someone
+/// else to recompute it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Proof {
+    pub component: String,
+    pub layout: &'static str,
+    /// Last commit touching the dir, or its content hash.
+    pub source_hash: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<Input>,
+    pub node_hash: String,
+    pub depth: u16,
+    pub children: Vec<ProofChild>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+    /// Hex of the exact bytes hashed.
+    pub preimage: String,
+    pub tree_sha: String,
+    /// The tree_sha was pinned rather than computed from the preimage.
+    pub pinned: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProofChild {
+    pub component: String,
+    pub offset: u32,
+    pub depth: u16,
+    pub tree_sha: String,
+    pub last: bool,
+}
+
+fn parse_pin(dir: &str, pin: &str) -> Result<[u8; 32], CustomError> {
+    hex::decode(pin)
+        .ok()
+        .and_then(|b| b.as_slice().try_into().ok())
+        .ok_or_else(|| CustomError::InvalidHashOverrideError {
+            component: dir.to_owned(),
+            value: pin.to_owned(),
+        })
+}
+
+fn node_preimage<S, T>(
     node_hash: &str,
     deps: &[S],
     hashes: &HashMap<T, (i32, [u8; 32])>,
-) -> (i32, [u8; 32])
+    salt: Option<&str>,
+) -> (i32, Vec<u8>)
 where
     S: Borrow<T> + std::fmt::Display,
     T: Hash + Eq,
 {
     // hash format:
-    // [depth u16] hash [child data] [root node u8 = 2]
+    // [depth u16] hash [child data] [root node u8 = 2] [salt, if any]
+    // salt = [length u32] utf-8 bytes
     // child data = * [offset u32] [depth u16] hash [end flag u8 = 0/1]
-    let mut hasher = Sha256::new();
     let (d, data) = build_hash(deps, hashes);
     let mydepth = d + 1;
     let root_hash = hex::decode(node_hash).unwrap();
-    hasher.update((mydepth as u16).to_be_bytes());
-    hasher.update(&root_hash);
-    hasher.update(&data);
-    hasher.update([2]);
+    let mut preimage = Vec::with_capacity(2 + root_hash.len() + data.len() + 1);
+    preimage.extend((mydepth as u16).to_be_bytes().iter());
+    preimage.extend(&root_hash);
+    preimage.extend(&data);
+    preimage.push(2);
+    if let Some(salt) = salt {
+        preimage.extend((salt.len() as u32).to_be_bytes().iter());
+        preimage.extend(salt.as_bytes());
+    }
     log::debug!(
         "root: [depth: {}] {:?} [child data: {:?}] [2]",
         mydepth as u16,
         root_hash,
         data
     );
-    (
-        mydepth,
-        hasher
-            .finalize()
-            .as_slice()
-            .try_into()
-            .expect("Wrong length hash"),
-    )
+    (mydepth, preimage)
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data)
+        .as_slice()
+        .try_into()
+        .expect("Wrong length hash")
 }
 
 fn build_hash<S, T>(deps: &[S], hashes: &HashMap<T, (i32, [u8; 32])>) -> (i32, Vec<u8>)
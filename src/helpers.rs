@@ -0,0 +1,41 @@
+use handlebars::{
+    handlebars_helper, Context, Handlebars, Helper, HelperResult, Output, RenderContext,
+    RenderError,
+};
+use serde_json::Value;
+
+handlebars_helper!(get: |value: Json, path: str| {
+    lookup(value, path).cloned().unwrap_or(Value::Null)
+});
+
+/// Helpers for reaching nested metadata: `{{get this "docker.args.FOO"}}`
+/// and `{{json this}}`.
+pub fn register_helpers(reg: &mut Handlebars) {
+    reg.register_helper("get", Box::new(get));
+    reg.register_helper("json", Box::new(json));
+}
+
+/// Written out directly, so never HTML-escaped even where other values are.
+fn json(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = h
+        .param(0)
+        .ok_or_else(|| RenderError::new("`json` helper: Couldn't read parameter value"))?;
+    out.write(&value.value().to_string())?;
+    Ok(())
+}
+
+/// Follow a dotted path through object keys and array indices.
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .filter(|p| !p.is_empty())
+        .try_fold(value, |v, key| match v {
+            Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+            _ => v.get(key),
+        })
+}
@@ -0,0 +1,249 @@
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{read_dir, read_to_string};
+use std::path::Path;
+
+use crate::glob::glob_regex;
+use crate::manifest::MANIFEST_VERSION;
+use crate::rewrite::output_manifest;
+use crate::types::*;
+
+pub const IMPORT_FORMATS: &[&str] = &["nx", "turbo", "lerna", "pants"];
+
+/// Write a components.json, see `output_manifest`, equivalent to another tool's project graph:
+///
+/// - nx: the JSON written by `nx graph --file=<path>`.
+/// - turbo, lerna: the workspace root at `<path>`, whose packages come from
+///   package.json `workspaces`, pnpm-workspace.yaml or lerna.json, with
+///   dependencies between them from each package.json.
+/// - pants: the JSON written by `pants peek :: > <path>`, one component per
+///   directory of targets.
+pub fn run_import(from: &str, path: &Path, out: Option<&Path>, wait: bool) -> anyhow::Result<()> {
+    let graph = match from {
+        "nx" => nx_graph(path)?,
+        "turbo" | "lerna" => workspace_graph(path, from == "lerna")?,
+        "pants" => pants_graph(path)?,
+        _ => panic!("unknown import format {}", from),
+    };
+    let components: Vec<Value> = graph
+        .into_iter()
+        .map(|(dir, deps)| {
+            let mut comp = json!({ "dir": dir });
+            if !deps.is_empty() {
+                comp["dependencies"] = json!(deps);
+            }
+            comp
+        })
+        .collect();
+    let manifest = json!({ "version": MANIFEST_VERSION, "components": components });
+    output_manifest(manifest, out, wait)
+}
+
+/// Component dirs and the dirs they depend on.
+type Graph = BTreeMap<String, BTreeSet<String>>;
+
+fn read_json(file: &Path) -> anyhow::Result<Value> {
+    let text = read_to_string(file).map_err(|e| anyhow::anyhow!("{:?}: {}", file, e))?;
+    serde_json::from_str(&text).map_err(|e| anyhow::anyhow!("{:?}: {}", file, e))
+}
+
+fn nx_graph(file: &Path) -> anyhow::Result<Graph> {
+    let raw = read_json(file)?;
+    let graph = raw.get("graph").unwrap_or(&raw);
+    let nodes = graph
+        .get("nodes")
+        .and_then(Value::as_object)
+        .ok_or_else(|| anyhow::anyhow!("{:?} has no graph.nodes", file))?;
+    let roots: BTreeMap<&str, String> = nodes
+        .iter()
+        .filter_map(|(name, node)| {
+            let root = node.pointer("/data/root").and_then(Value::as_str)?;
+            Some((name.as_str(), normalize_dir(root)))
+        })
+        .collect();
+    let mut res: Graph = roots
+        .values()
+        .map(|d| (d.clone(), BTreeSet::new()))
+        .collect();
+    let deps = graph.get("dependencies").and_then(Value::as_object);
+    for (name, targets) in deps.into_iter().flatten() {
+        let dir = match roots.get(name.as_str()) {
+            Some(d) => d,
+            None => continue,
+        };
+        for target in targets.as_array().into_iter().flatten() {
+            // npm packages show up as targets too; only projects are kept
+            if let Some(dep) = target
+                .get("target")
+                .and_then(Value::as_str)
+                .and_then(|t| roots.get(t))
+            {
+                if dep != dir {
+                    res.get_mut(dir).unwrap().insert(dep.clone());
+                }
+            }
+        }
+    }
+    Ok(res)
+}
+
+fn workspace_graph(root: &Path, lerna: bool) -> anyhow::Result<Graph> {
+    let mut patterns = Vec::new();
+    if lerna && root.join("lerna.json").is_file() {
+        patterns.extend(string_list(
+            read_json(&root.join("lerna.json"))?.get("packages"),
+        ));
+    }
+    if patterns.is_empty() && root.join("package.json").is_file() {
+        let package = read_json(&root.join("package.json"))?;
+        let workspaces = package.get("workspaces");
+        // either a list or yarn's {"packages": [...]}
+        patterns.extend(string_list(workspaces));
+        patterns.extend(string_list(workspaces.and_then(|w| w.get("packages"))));
+    }
+    if patterns.is_empty() && root.join("pnpm-workspace.yaml").is_file() {
+        patterns.extend(pnpm_packages(&read_to_string(
+            root.join("pnpm-workspace.yaml"),
+        )?));
+    }
+    if patterns.is_empty() {
+        anyhow::bail!("No workspace packages declared in {:?}", root);
+    }
+    let (include, exclude): (Vec<&String>, Vec<&String>) =
+        patterns.iter().partition(|p| !p.starts_with('!'));
+    let include: Vec<_> = include
+        .iter()
+        .map(|p| glob_regex(&normalize_dir(p)))
+        .collect();
+    let exclude: Vec<_> = exclude
+        .iter()
+        .map(|p| glob_regex(&normalize_dir(&p[1..])))
+        .collect();
+    let mut dirs = Vec::new();
+    find_packages(root, root, &mut dirs)?;
+    let mut packages = BTreeMap::new();
+    for dir in dirs {
+        if include.iter().any(|r| r.is_match(&dir)) && !exclude.iter().any(|r| r.is_match(&dir)) {
+            let package = read_json(&root.join(&dir).join("package.json"))?;
+            if let Some(name) = package.get("name").and_then(Value::as_str) {
+                packages.insert(name.to_owned(), (dir, package));
+            }
+        }
+    }
+    let mut res = Graph::new();
+    for (dir, package) in packages.values() {
+        let mut deps = BTreeSet::new();
+        for section in [
+            "dependencies",
+            "devDependencies",
+            "peerDependencies",
+            "optionalDependencies",
+        ] {
+            for name in package
+                .get(section)
+                .and_then(Value::as_object)
+                .into_iter()
+                .flatten()
+            {
+                if let Some((dep, _)) = packages.get(name.0) {
+                    if dep != dir {
+                        deps.insert(dep.clone());
+                    }
+                }
+            }
+        }
+        res.insert(dir.clone(), deps);
+    }
+    Ok(res)
+}
+
+fn string_list(value: Option<&Value>) -> Vec<String> {
+    value
+        .and_then(Value::as_array)
+        .map(|a| {
+            a.iter()
+                .filter_map(Value::as_str)
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The `packages` list of a pnpm-workspace.yaml, which is all it holds.
+fn pnpm_packages(text: &str) -> Vec<String> {
+    let mut res = Vec::new();
+    let mut in_packages = false;
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with(' ') && !line.starts_with('-') {
+            in_packages = trimmed == "packages:";
+        } else if let Some(item) = trimmed.strip_prefix('-').filter(|_| in_packages) {
+            res.push(
+                item.trim()
+                    .trim_matches(|c| c == '\'' || c == '"')
+                    .to_owned(),
+            );
+        }
+    }
+    res
+}
+
+/// Dirs below `root` holding a package.json, skipping node_modules.
+fn find_packages(root: &Path, dir: &Path, res: &mut Vec<String>) -> anyhow::Result<()> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !entry.file_type()?.is_dir() || name == "node_modules" || name == ".git" {
+            continue;
+        }
+        let path = entry.path();
+        if path.join("package.json").is_file() {
+            res.push(path_to_slash(path.strip_prefix(root)?));
+        }
+        find_packages(root, &path, res)?;
+    }
+    Ok(())
+}
+
+fn pants_graph(file: &Path) -> anyhow::Result<Graph> {
+    let raw = read_json(file)?;
+    let targets = raw
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("{:?} isn't a list of targets", file))?;
+    // `src/py/app:lib`, `src/py/app/main.py:lib` and `//:root` all name dirs
+    let dir_of = |address: &str| {
+        let spec = address.split(':').next().unwrap_or(address);
+        let spec = spec.trim_start_matches("//");
+        let dir = match spec.rsplit_once('/') {
+            Some((parent, last)) if last.contains('.') => parent,
+            _ if spec.contains('.') && !spec.contains('/') => "",
+            _ => spec,
+        };
+        normalize_dir(dir)
+    };
+    let mut res = Graph::new();
+    for target in targets {
+        let address = match target.get("address").and_then(Value::as_str) {
+            Some(a) => a,
+            None => continue,
+        };
+        let dir = dir_of(address);
+        let deps = res.entry(dir.clone()).or_default();
+        for dep in string_list(target.get("dependencies")) {
+            let dep = dir_of(&dep);
+            if dep != dir {
+                deps.insert(dep);
+            }
+        }
+    }
+    // dependencies on dirs with no targets of their own (3rdparty) aren't
+    // components
+    let dirs: BTreeSet<String> = res.keys().cloned().collect();
+    for deps in res.values_mut() {
+        deps.retain(|d| dirs.contains(d));
+    }
+    Ok(res)
+}
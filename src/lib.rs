@@ -0,0 +1,63 @@
+//! Components, their dependency graph and hashes, as used by the
+//! `avocado-build-helper` binary, for build tooling that would rather link
+//! against them than shell out.
+//!
+//! - `Component`, `load_components` and graph operations such as
+//!   `toposort_components` and `transitive_dependents`.
+//! - `hash_components` and `HashOptions`.
+//! - `CommandRegistry` for evaluating props.
+//! - `load_manifest` and the components.json layout.
+//!
+//! `cli` is the binary's command line and is not part of the API.
+
+mod affected;
+mod analysis;
+mod bench;
+mod build;
+mod canonical;
+mod ci;
+#[doc(hidden)]
+pub mod cli;
+mod content;
+mod dockerignore;
+mod estimate;
+mod exec;
+mod executor;
+mod export;
+mod glob;
+mod graph;
+mod hasher;
+mod helpers;
+mod import;
+mod lock;
+mod man;
+mod manifest;
+mod metrics;
+mod pick;
+mod plugin;
+mod pool;
+mod prove;
+mod provenance;
+mod registry;
+mod render;
+mod report;
+mod rewrite;
+mod schema;
+mod server;
+mod sparse;
+mod state;
+mod tag;
+mod types;
+mod validate;
+mod vcs;
+mod verify;
+mod workspace;
+mod yaml;
+
+pub use executor::{annotate_component, CommandConfig, CommandRegistry, Truthiness};
+pub use hasher::{hash_components, HashOptions};
+pub use manifest::{load_manifest, Hermetic, LoadOptions, Manifest};
+pub use types::{
+    load_components, toposort_components, toposort_levels, transitive_dependencies,
+    transitive_dependents, Component, CustomError, RemoteDependency,
+};
@@ -0,0 +1,58 @@
+use sha2::{Digest, Sha256};
+use std::fs::{create_dir_all, File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+
+use crate::types::{path_to_slash_bytes, CustomError};
+use crate::vcs::git_path;
+
+/// An advisory lock on a shared file, released when dropped. It is taken
+/// on a separate lock file, as the file itself is replaced by renaming
+/// rather than rewritten: one under the git dir, so the work tree is left
+/// alone, or outside a repository a `<file>.lock` next to it. Lock files
+/// are never removed, since a process waiting on one would otherwise hold
+/// a lock nobody else can see.
+pub struct FileLock {
+    _file: File,
+}
+
+/// Lock `file`, waiting for another process holding it, or with `wait`
+/// false failing straight away.
+pub fn lock(file: &Path, wait: bool) -> anyhow::Result<FileLock> {
+    let path = lock_path(file)?;
+    let lock_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+    match lock_file.try_lock() {
+        Ok(()) => (),
+        Err(TryLockError::WouldBlock) if wait => {
+            log::info!("Waiting for another process to release {:?}", path);
+            lock_file.lock()?;
+        }
+        Err(TryLockError::WouldBlock) => return Err(CustomError::LockedError(path).into()),
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    Ok(FileLock { _file: lock_file })
+}
+
+fn lock_path(file: &Path) -> anyhow::Result<PathBuf> {
+    let dir = match file.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    match git_path(dir, "avocado-build-helper/locks") {
+        Some(locks) => {
+            create_dir_all(&locks)?;
+            let name = file.file_name().unwrap_or_default();
+            let key = dir.canonicalize()?.join(name);
+            let digest = hex::encode(Sha256::digest(&path_to_slash_bytes(&key)));
+            Ok(locks.join(format!("{}.lock", &digest[..16])))
+        }
+        None => {
+            let mut name = file.as_os_str().to_owned();
+            name.push(".lock");
+            Ok(PathBuf::from(name))
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use clap::{App, ErrorKind};
+use std::fs;
+use std::path::Path;
+
+const BIN_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Write a section 1 man page for the tool and for each of its visible
+/// subcommands into `out_dir`, built from the same text as `--help`.
+pub fn run_generate_man(app: &App, out_dir: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(out_dir)?;
+    let subcommands: Vec<String> = app
+        .p
+        .subcommands
+        .iter()
+        .map(|s| s.get_name().to_owned())
+        .filter(|name| name != "generate-man")
+        .collect();
+    let mut pages = vec![(BIN_NAME.to_owned(), help_text(app, &[])?)];
+    for name in subcommands.iter() {
+        pages.push((
+            format!("{}-{}", BIN_NAME, name),
+            help_text(app, &[name.as_str()])?,
+        ));
+    }
+    for (page, help) in pages {
+        let file = out_dir.join(format!("{}.1", page));
+        log::info!("Writing {:?}", file);
+        fs::write(file, render_page(&page, &help))?;
+    }
+    Ok(())
+}
+
+/// clap 2 only renders complete help, with globals propagated, while
+/// parsing, so ask for it the way a user would.
+fn help_text(app: &App, subcommand: &[&str]) -> anyhow::Result<String> {
+    let args = std::iter::once(BIN_NAME)
+        .chain(subcommand.iter().copied())
+        .chain(std::iter::once("--help"));
+    match app.clone().bin_name(BIN_NAME).get_matches_from_safe(args) {
+        Err(e) if e.kind == ErrorKind::HelpDisplayed => Ok(e.message),
+        Err(e) => Err(e.into()),
+        Ok(_) => panic!("--help didn't display help"),
+    }
+}
+
+fn render_page(page: &str, help: &str) -> String {
+    let mut out = format!(
+        ".TH \"{}\" 1 \"\" \"{} {}\"\n.SH NAME\n{}\n.SH DESCRIPTION\n.nf\n",
+        page.to_uppercase(),
+        BIN_NAME,
+        env!("CARGO_PKG_VERSION"),
+        escape(page),
+    );
+    for line in help.lines() {
+        out.push_str(&escape(line));
+        out.push('\n');
+    }
+    out.push_str(".fi\n");
+    out
+}
+
+fn escape(line: &str) -> String {
+    let line = line.replace('\\', "\\e").replace('-', "\\-");
+    if line.starts_with('.') || line.starts_with('\'') {
+        format!("\\&{}", line)
+    } else {
+        line
+    }
+}
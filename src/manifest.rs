@@ -0,0 +1,781 @@
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, from_value, Map, Value};
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use crate::metrics;
+use crate::types::{dir_problem, normalize_dir, to_slash, Component, CustomError};
+use crate::yaml;
+
+/// The newest components.json layout this build understands.
+pub const MANIFEST_VERSION: u64 = 2;
+
+/// components.json layouts:
+///
+/// - 1: a bare array of components, dependencies as dir strings.
+/// - 2: `{"version": 2, "components": [...]}`; dependencies may also be
+///   objects of the form `{"component": "<dir>"}`, or with `git` and `ref`
+///   keys for a component of another repository. Optional `defaults` and
+///   `groups` sections are merged into components when loading, and
+///   `${VAR}` in component strings is replaced from the environment or the
+///   `vars` section.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u64,
+    pub components: Vec<Component>,
+    /// Fields merged into every component.
+    #[serde(skip_serializing_if = "Map::is_empty", default)]
+    pub defaults: Map<String, Value>,
+    /// Named sets of defaults, applied to components with a matching `group`.
+    #[serde(skip_serializing_if = "Map::is_empty", default)]
+    pub groups: Map<String, Value>,
+    /// Values for `${VAR}` substitution; the environment takes precedence.
+    #[serde(skip_serializing_if = "Map::is_empty", default)]
+    pub vars: Map<String, Value>,
+    /// Extra component keys allowed when loading strictly.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub metadata_keys: Vec<String>,
+    /// Patterns appended to every generated .dockerignore.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub always_exclude: Vec<String>,
+    /// What commands may run and see under `--hermetic`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hermetic: Option<Hermetic>,
+}
+
+/// Allowlists for `--hermetic` runs.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Hermetic {
+    /// Programs exec props, plugins (as `avocado-build-helper-<name>`) and
+    /// build (as `sh`) may run.
+    #[serde(default)]
+    pub commands: Vec<String>,
+    /// Environment variables passed through to commands.
+    #[serde(default)]
+    pub env: Vec<String>,
+}
+
+impl Manifest {
+    pub const FIELDS: &'static [&'static str] = &[
+        "version",
+        "components",
+        "defaults",
+        "groups",
+        "vars",
+        "metadata_keys",
+        "always_exclude",
+        "hermetic",
+    ];
+}
+
+/// How manifests are found and loaded, from `--manifest`, `--overlay` and
+/// `--strict`. The defaults load the manifest as it is.
+#[derive(Debug, Default, Clone)]
+pub struct LoadOptions {
+    /// Overlays deep-merged onto the base manifest, in order.
+    pub overlays: Vec<String>,
+    /// Reject component keys that are neither fields nor declared metadata.
+    pub strict: bool,
+    /// Manifest file name, instead of the first of `MANIFEST_NAMES` found.
+    pub manifest: Option<String>,
+}
+
+/// Manifest file names looked for, in order.
+pub const MANIFEST_NAMES: &[&str] = &["components.json", "components.yaml", "components.yml"];
+
+impl LoadOptions {
+    /// The manifest file in `path`: `manifest` if given, otherwise the
+    /// first of `MANIFEST_NAMES` that exists, or components.json.
+    pub fn manifest_file(&self, path: &Path) -> PathBuf {
+        if let Some(name) = &self.manifest {
+            return path.join(name);
+        }
+        MANIFEST_NAMES
+            .iter()
+            .map(|n| path.join(n))
+            .find(|f| f.is_file())
+            .unwrap_or_else(|| path.join(MANIFEST_NAMES[0]))
+    }
+
+    pub fn load_manifest(&self, path: &Path) -> Result<Manifest, CustomError> {
+        metrics::global().time_phase("load", || {
+            self.apply_overlays(path, read_manifest_file(&self.manifest_file(path))?)
+        })
+    }
+
+    pub fn load_components(&self, path: &Path) -> Result<Vec<Component>, CustomError> {
+        Ok(self.load_manifest(path)?.components)
+    }
+
+    /// Load a manifest from `text` read elsewhere, such as stdin, for the
+    /// components in `path`. Overlays still come from `path`.
+    pub fn load_manifest_text(&self, path: &Path, text: &str) -> Result<Manifest, CustomError> {
+        metrics::global().time_phase("load", || {
+            let raw = parse_manifest(Path::new("<stdin>"), text, is_yaml_text(text))?;
+            self.apply_overlays(path, raw)
+        })
+    }
+
+    fn apply_overlays(&self, path: &Path, raw: Value) -> Result<Manifest, CustomError> {
+        let mut raw = upgrade(raw)?;
+        for name in self.overlays.iter() {
+            let overlay_path = self.overlay_path(path, name);
+            log::debug!("Applying overlay {:?}", overlay_path);
+            let overlay = upgrade(read_manifest_file(&overlay_path)?)?;
+            merge_manifests(&mut raw, overlay)?;
+        }
+        resolve(raw, self.strict)
+    }
+
+    /// Overlays are named (`prod` -> `components.prod.json`, or `.yaml`
+    /// beside a YAML manifest) or given as paths.
+    fn overlay_path(&self, path: &Path, name: &str) -> PathBuf {
+        if name.ends_with(".json") || is_yaml(Path::new(name)) || name.contains('/') {
+            path.join(name)
+        } else {
+            let base = self.manifest_file(path);
+            let ext = if is_yaml(&base) {
+                base.extension().unwrap().to_string_lossy().into_owned()
+            } else {
+                "json".to_owned()
+            };
+            path.join(format!("components.{}.{}", name, ext))
+        }
+    }
+}
+
+pub fn is_yaml(file: &Path) -> bool {
+    matches!(
+        file.extension().and_then(|e| e.to_str()),
+        Some("yaml") | Some("yml")
+    )
+}
+
+/// The manifest in `path` as it is, see `LoadOptions::load_manifest`.
+pub fn load_manifest(path: &Path) -> Result<Manifest, CustomError> {
+    LoadOptions::default().load_manifest(path)
+}
+
+/// Whether manifest text without a file name is YAML rather than JSON,
+/// looking past any JSONC comments it starts with.
+pub fn is_yaml_text(text: &str) -> bool {
+    let mut rest = text.trim_start();
+    loop {
+        rest = if let Some(line) = rest.strip_prefix("//") {
+            line.split_once('\n').map_or("", |(_, r)| r).trim_start()
+        } else if let Some(block) = rest.strip_prefix("/*") {
+            block.split_once("*/").map_or("", |(_, r)| r).trim_start()
+        } else {
+            return !rest.starts_with(['{', '[']);
+        }
+    }
+}
+
+/// The raw JSON or YAML manifest at `path`, before overlays and upgrades.
+pub fn read_manifest_file(path: &Path) -> Result<Value, CustomError> {
+    let text = read_to_string(path).map_err(|error| match error.kind() {
+        std::io::ErrorKind::NotFound => CustomError::ManifestNotFoundError(path.to_owned()),
+        _ => CustomError::ManifestReadError {
+            path: path.to_owned(),
+            error,
+        },
+    })?;
+    parse_manifest(path, &text, is_yaml(path))
+}
+
+pub fn parse_manifest(path: &Path, text: &str, yaml: bool) -> Result<Value, CustomError> {
+    if yaml {
+        yaml::parse(text).map_err(|e| yaml_error(path, e))
+    } else {
+        from_str(&strip_jsonc(text)).map_err(|e| {
+            // serde_json appends the position, which is reported separately
+            let message = e.to_string();
+            let reason = message
+                .rsplit_once(" at line ")
+                .map_or(&*message, |(r, _)| r);
+            CustomError::ManifestParseError {
+                path: path.to_owned(),
+                line: e.line(),
+                column: Some(e.column()),
+                reason: reason.to_owned(),
+            }
+        })
+    }
+}
+
+/// YAML errors read `line N: reason`.
+fn yaml_error(path: &Path, error: String) -> CustomError {
+    let located = error
+        .strip_prefix("line ")
+        .and_then(|rest| rest.split_once(": "))
+        .and_then(|(n, reason)| Some((n.parse().ok()?, reason)));
+    match located {
+        Some((line, reason)) => CustomError::ManifestParseError {
+            path: path.to_owned(),
+            line,
+            column: None,
+            reason: reason.to_owned(),
+        },
+        None => CustomError::ManifestFormatError {
+            reason: format!("{:?} {}", path, error),
+        },
+    }
+}
+
+/// Blank out `//` and `/* */` comments and trailing commas so the manifest
+/// can be annotated, keeping every other character (and so error line and
+/// column numbers) where it was.
+pub fn strip_jsonc(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    let mut in_string = false;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if in_string {
+            out.push(c);
+            if c == '\\' {
+                if let Some(n) = next {
+                    out.push(n);
+                    i += 1;
+                }
+            } else if c == '"' {
+                in_string = false;
+            }
+            i += 1;
+        } else if c == '"' {
+            in_string = true;
+            out.push(c);
+            i += 1;
+        } else if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                out.push(' ');
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            out.push_str("  ");
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                out.push(if chars[i] == '\n' { '\n' } else { ' ' });
+                i += 1;
+            }
+            out.push_str("  ");
+            i += 2;
+        } else if c == ',' && closes_after_comma(&chars[i + 1..]) {
+            out.push(' ');
+            i += 1;
+        } else {
+            out.push(c);
+            i += 1;
+        }
+    }
+    out
+}
+
+/// Whether `text` has `//` or `/* */` comments, which a rewrite would lose.
+pub fn has_comments(text: &str) -> bool {
+    // stripping blanks comments and trailing commas in place
+    text.chars()
+        .zip(strip_jsonc(text).chars())
+        .any(|(a, b)| a != b && a != ',')
+}
+
+/// Whether only whitespace and comments stand between here and a closing
+/// bracket, i.e. the preceding comma is a trailing one.
+fn closes_after_comma(rest: &[char]) -> bool {
+    let mut i = 0;
+    while i < rest.len() {
+        match (rest[i], rest.get(i + 1)) {
+            (c, _) if c.is_whitespace() => i += 1,
+            ('/', Some('/')) => {
+                while i < rest.len() && rest[i] != '\n' {
+                    i += 1;
+                }
+            }
+            ('/', Some('*')) => {
+                i += 2;
+                while i < rest.len() && !(rest[i] == '*' && rest.get(i + 1) == Some(&'/')) {
+                    i += 1;
+                }
+                i += 2;
+            }
+            (c, _) => return c == ']' || c == '}',
+        }
+    }
+    false
+}
+
+/// Merge an overlay manifest into `base`. Components are matched by `dir`
+/// and deep-merged, unknown ones are added, and `"$remove": true` drops one.
+fn merge_manifests(base: &mut Value, mut overlay: Value) -> Result<(), CustomError> {
+    let overlay_components = match overlay.get_mut("components") {
+        Some(c) => c.take(),
+        None => Value::Array(Vec::new()),
+    };
+    let overlay_components = match overlay_components {
+        Value::Array(a) => a,
+        _ => {
+            return Err(CustomError::ManifestFormatError {
+                reason: "overlay components must be an array".to_owned(),
+            })
+        }
+    };
+    overlay.as_object_mut().unwrap().remove("components");
+    let base_components = base
+        .get_mut("components")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| CustomError::ManifestFormatError {
+            reason: "components must be an array".to_owned(),
+        })?;
+    for mut comp in overlay_components {
+        let dir = comp.get("dir").and_then(Value::as_str).map(str::to_owned);
+        let dir = dir.ok_or_else(|| CustomError::ManifestFormatError {
+            reason: format!("overlay component without dir: {}", comp),
+        })?;
+        let remove = comp
+            .as_object_mut()
+            .and_then(|m| m.remove("$remove"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let pos = base_components
+            .iter()
+            .position(|c| c.get("dir").and_then(Value::as_str) == Some(dir.as_str()));
+        match (pos, remove) {
+            (Some(i), true) => {
+                base_components.remove(i);
+            }
+            (Some(i), false) => deep_merge(&mut base_components[i], comp),
+            (None, false) => base_components.push(comp),
+            (None, true) => log::warn!("Overlay removes unknown component {}", dir),
+        }
+    }
+    // the overlay's version has served its purpose in upgrading it
+    overlay.as_object_mut().unwrap().remove("version");
+    deep_merge(base, overlay);
+    Ok(())
+}
+
+/// Concatenate the components of several manifests, upgraded to the
+/// current version, with each file's `defaults` applied to its own
+/// components. Components sharing a dir are deep-merged when `deep`,
+/// otherwise they are an error, as are different components naming the
+/// same image. Other sections are combined, entry by entry for `groups`
+/// and `vars`, and it is an error for two files to give them different
+/// values.
+pub fn merge_manifest_files(
+    files: &[&Path],
+    deep: bool,
+    strict: bool,
+) -> Result<Value, CustomError> {
+    let mut merged = migrate_v1(Value::Array(Vec::new()));
+    let mut problems = Vec::new();
+    let mut conflicts = Vec::new();
+    for file in files {
+        let mut raw = upgrade(read_manifest_file(file)?)?;
+        // each file's defaults are for its own components only
+        apply_defaults(&mut raw)?;
+        let components = match raw.as_object_mut().unwrap().remove("components") {
+            Some(Value::Array(a)) => a,
+            _ => {
+                return Err(CustomError::ManifestFormatError {
+                    reason: format!("components in {:?} must be an array", file),
+                })
+            }
+        };
+        let existing = merged["components"].as_array_mut().unwrap();
+        for comp in components {
+            let dir = comp.get("dir").and_then(Value::as_str).map(normalize_dir);
+            let image = comp.get("image").and_then(Value::as_str);
+            let same_dir = existing.iter().position(|c| {
+                dir.is_some() && c.get("dir").and_then(Value::as_str).map(normalize_dir) == dir
+            });
+            if let Some(other) = existing.iter().find(|c| {
+                image.is_some()
+                    && c.get("image").and_then(Value::as_str) == image
+                    && c.get("dir").and_then(Value::as_str).map(normalize_dir) != dir
+            }) {
+                problems.push(format!(
+                    "image {:?} of {} also in {}",
+                    image.unwrap(),
+                    comp["dir"],
+                    other["dir"]
+                ));
+            }
+            match same_dir {
+                Some(i) if deep => deep_merge(&mut existing[i], comp),
+                Some(_) => problems.push(format!("{} in {:?}", comp["dir"], file)),
+                None => existing.push(comp),
+            }
+        }
+        let sections = raw.as_object_mut().unwrap();
+        sections.remove("version");
+        sections.remove("defaults");
+        merge_sections(
+            merged.as_object_mut().unwrap(),
+            sections,
+            file,
+            &mut conflicts,
+        );
+    }
+    if !problems.is_empty() {
+        return Err(CustomError::DuplicateComponentsError(problems));
+    }
+    if !conflicts.is_empty() {
+        return Err(CustomError::ManifestFormatError {
+            reason: format!("conflicting sections: {}", conflicts.join(", ")),
+        });
+    }
+    resolve(merged.clone(), strict)?;
+    Ok(merged)
+}
+
+/// Add `sections` of `file` to `merged`, noting keys (or entries of
+/// `groups` and `vars`) already there with a different value.
+fn merge_sections(
+    merged: &mut Map<String, Value>,
+    sections: &mut Map<String, Value>,
+    file: &Path,
+    conflicts: &mut Vec<String>,
+) {
+    for (key, value) in std::mem::take(sections) {
+        match (merged.get_mut(&key), value) {
+            (None, value) => {
+                merged.insert(key, value);
+            }
+            (Some(Value::Object(existing)), Value::Object(entries))
+                if key == "groups" || key == "vars" =>
+            {
+                for (name, v) in entries {
+                    match existing.get(&name) {
+                        Some(other) if *other != v => {
+                            conflicts.push(format!("{}.{} in {:?}", key, name, file))
+                        }
+                        _ => {
+                            existing.insert(name, v);
+                        }
+                    }
+                }
+            }
+            (Some(existing), value) => {
+                if *existing != value {
+                    conflicts.push(format!("{} in {:?}", key, file));
+                }
+            }
+        }
+    }
+}
+
+fn resolve(mut raw: Value, strict: bool) -> Result<Manifest, CustomError> {
+    apply_defaults(&mut raw)?;
+    if strict {
+        check_unknown_keys(&raw)?;
+    }
+    substitute_vars(&mut raw)?;
+    normalize_dependencies(&mut raw)?;
+    normalize_separators(&mut raw);
+    let manifest: Manifest = from_value(raw).map_err(|e| CustomError::ManifestFormatError {
+        reason: e.to_string(),
+    })?;
+    check_dirs(&manifest.components)?;
+    check_ignores(&manifest.components)?;
+    Ok(manifest)
+}
+
+fn normalize_separators(raw: &mut Value) {
+    let components = raw.get_mut("components").and_then(Value::as_array_mut);
+    for comp in components
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut)
+    {
+        for key in ["dir", "dockerfile"].iter() {
+            if let Some(Value::String(s)) = comp.get_mut(*key) {
+                *s = to_slash(s);
+            }
+        }
+        for key in ["dependencies", "extra_paths"].iter() {
+            if let Some(Value::Array(paths)) = comp.get_mut(*key) {
+                for path in paths.iter_mut() {
+                    if let Value::String(s) = path {
+                        *s = to_slash(s);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn check_dirs(components: &[Component]) -> Result<(), CustomError> {
+    let mut problems = Vec::new();
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    for comp in components {
+        if let Some(p) = dir_problem(&comp.dir) {
+            problems.push(format!("{:?} {}", comp.dir, p));
+        }
+        if let Some(other) = seen.insert(normalize_dir(&comp.dir), &comp.dir) {
+            problems.push(format!("{:?} duplicates {:?}", comp.dir, other));
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(CustomError::InvalidDirError(problems))
+    }
+}
+
+/// Git pathspecs can only exclude, so `!` can't re-include what an earlier
+/// pattern left out; refuse it rather than match it literally.
+fn check_ignores(components: &[Component]) -> Result<(), CustomError> {
+    for comp in components {
+        if let Some(p) = comp.ignore.iter().find(|p| p.starts_with('!')) {
+            return Err(CustomError::ManifestFormatError {
+                reason: format!(
+                    "{:?} ignores {:?}, but negated patterns aren't supported",
+                    comp.dir, p
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn manifest_version(raw: &Value) -> Result<u64, CustomError> {
+    match raw {
+        Value::Array(_) => Ok(1),
+        Value::Object(m) => match m.get("version") {
+            Some(v) => v.as_u64().ok_or_else(|| CustomError::ManifestFormatError {
+                reason: format!("version must be a positive integer, found {}", v),
+            }),
+            None => Err(CustomError::ManifestFormatError {
+                reason: "missing version key".to_owned(),
+            }),
+        },
+        _ => Err(CustomError::ManifestFormatError {
+            reason: "expected an array of components or an object".to_owned(),
+        }),
+    }
+}
+
+/// Upgrade any supported layout to the current one, one version at a time.
+fn upgrade(mut raw: Value) -> Result<Value, CustomError> {
+    let mut version = manifest_version(&raw)?;
+    if version > MANIFEST_VERSION {
+        return Err(CustomError::UnsupportedManifestVersion {
+            found: version,
+            supported: MANIFEST_VERSION,
+        });
+    }
+    while version < MANIFEST_VERSION {
+        log::debug!("Migrating components.json from version {}", version);
+        raw = match version {
+            1 => migrate_v1(raw),
+            _ => unreachable!(),
+        };
+        version += 1;
+    }
+    Ok(raw)
+}
+
+/// Recursively merge `overlay` into `base`: objects are merged key by key,
+/// anything else in `overlay` replaces what was in `base`.
+pub fn deep_merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(b), Value::Object(o)) => {
+            for (k, v) in o {
+                match b.get_mut(&k) {
+                    Some(existing) => deep_merge(existing, v),
+                    None => {
+                        b.insert(k, v);
+                    }
+                }
+            }
+        }
+        (b, o) => *b = o,
+    }
+}
+
+/// Merge `defaults`, then the component's `group` defaults, under each
+/// component's own fields.
+fn apply_defaults(raw: &mut Value) -> Result<(), CustomError> {
+    let defaults = raw
+        .get("defaults")
+        .cloned()
+        .unwrap_or_else(|| Value::Object(Map::new()));
+    let groups = raw
+        .get("groups")
+        .cloned()
+        .unwrap_or_else(|| Value::Object(Map::new()));
+    let components = match raw.get_mut("components").and_then(Value::as_array_mut) {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    for comp in components.iter_mut() {
+        let mut merged = defaults.clone();
+        if let Some(group) = comp.get("group").and_then(Value::as_str) {
+            let group_defaults =
+                groups
+                    .get(group)
+                    .ok_or_else(|| CustomError::ManifestFormatError {
+                        reason: format!("unknown group {:?}", group),
+                    })?;
+            deep_merge(&mut merged, group_defaults.clone());
+        }
+        deep_merge(&mut merged, comp.take());
+        *comp = merged;
+    }
+    Ok(())
+}
+
+fn migrate_v1(raw: Value) -> Value {
+    let mut m = Map::new();
+    m.insert("version".to_owned(), Value::from(2));
+    m.insert("components".to_owned(), raw);
+    Value::Object(m)
+}
+
+/// Catch typos like `dependancies` that would otherwise silently end up as
+/// metadata in `Component::rem`.
+fn check_unknown_keys(raw: &Value) -> Result<(), CustomError> {
+    let mut unknown = Vec::new();
+    let top = raw.as_object().unwrap();
+    unknown.extend(
+        top.keys()
+            .filter(|k| !Manifest::FIELDS.contains(&k.as_str()))
+            .map(|k| format!("top level: {}", k)),
+    );
+    let allowed: Vec<&str> = top
+        .get("metadata_keys")
+        .and_then(Value::as_array)
+        .map(|a| a.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    for comp in top
+        .get("components")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object)
+    {
+        let dir = comp.get("dir").and_then(Value::as_str).unwrap_or("?");
+        unknown.extend(
+            comp.keys()
+                .filter(|k| {
+                    !Component::FIELDS.contains(&k.as_str()) && !allowed.contains(&k.as_str())
+                })
+                .map(|k| format!("{}: {}", dir, k)),
+        );
+    }
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(CustomError::UnknownKeysError(unknown))
+    }
+}
+
+/// Replace `${VAR}` (or `${VAR:-default}`) in every string within the
+/// components; `$${` produces a literal `${`. Only manifests declaring
+/// `vars` (if empty) opt in, so others keep any `${...}` as written.
+fn substitute_vars(raw: &mut Value) -> Result<(), CustomError> {
+    let vars = match raw.get("vars") {
+        Some(vars) => vars.clone(),
+        None => return Ok(()),
+    };
+    let lookup = |name: &str| -> Option<String> {
+        std::env::var(name).ok().or_else(|| match vars.get(name)? {
+            Value::String(s) => Some(s.clone()),
+            v => Some(v.to_string()),
+        })
+    };
+    let re = Regex::new(r"\$\$\{|\$\{([A-Za-z_][A-Za-z0-9_]*)(?::-([^}]*))?\}").unwrap();
+    let mut undefined = Vec::new();
+    let mut substitute = |s: &str| -> String {
+        re.replace_all(s, |caps: &Captures| match caps.get(1) {
+            None => "${".to_owned(),
+            Some(name) => lookup(name.as_str())
+                .or_else(|| caps.get(2).map(|d| d.as_str().to_owned()))
+                .unwrap_or_else(|| {
+                    undefined.push(name.as_str().to_owned());
+                    String::new()
+                }),
+        })
+        .into_owned()
+    };
+    if let Some(components) = raw.get_mut("components") {
+        map_strings(components, &mut substitute);
+    }
+    if !undefined.is_empty() {
+        undefined.sort();
+        undefined.dedup();
+        return Err(CustomError::ManifestFormatError {
+            reason: format!("undefined variables: {}", undefined.join(", ")),
+        });
+    }
+    Ok(())
+}
+
+fn map_strings<F: FnMut(&str) -> String>(value: &mut Value, f: &mut F) {
+    match value {
+        Value::String(s) => *s = f(s),
+        Value::Array(a) => a.iter_mut().for_each(|v| map_strings(v, f)),
+        Value::Object(m) => m.values_mut().for_each(|v| map_strings(v, f)),
+        _ => (),
+    }
+}
+
+/// Reduce dependency objects to the dir strings `Component` holds, moving
+/// those in other repositories to `remote_dependencies`.
+fn normalize_dependencies(raw: &mut Value) -> Result<(), CustomError> {
+    let components = raw
+        .get_mut("components")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| CustomError::ManifestFormatError {
+            reason: "components must be an array".to_owned(),
+        })?;
+    for comp in components.iter_mut() {
+        let deps = match comp.get_mut("dependencies").and_then(Value::as_array_mut) {
+            Some(d) => d,
+            None => continue,
+        };
+        let (remote, local): (Vec<Value>, Vec<Value>) =
+            deps.drain(..).partition(|d| d.get("git").is_some());
+        *deps = local;
+        for dep in deps.iter_mut() {
+            if let Some(dir) = dep.get("component").and_then(Value::as_str) {
+                *dep = Value::from(dir);
+            } else if !dep.is_string() {
+                return Err(CustomError::ManifestFormatError {
+                    reason: format!("invalid dependency {}", dep),
+                });
+            }
+        }
+        if remote.is_empty() {
+            continue;
+        }
+        for dep in remote.iter() {
+            let valid = ["git", "component"]
+                .iter()
+                .all(|k| dep.get(k).is_some_and(Value::is_string))
+                && dep.get("ref").is_none_or(Value::is_string);
+            if !valid {
+                return Err(CustomError::ManifestFormatError {
+                    reason: format!("invalid dependency {}", dep),
+                });
+            }
+        }
+        let comp = comp.as_object_mut().unwrap();
+        match comp
+            .entry("remote_dependencies")
+            .or_insert_with(|| Value::Array(Vec::new()))
+        {
+            Value::Array(existing) => existing.extend(remote),
+            _ => {
+                return Err(CustomError::ManifestFormatError {
+                    reason: "remote_dependencies must be an array".to_owned(),
+                })
+            }
+        }
+    }
+    Ok(())
+}
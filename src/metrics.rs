@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+pub struct Metric {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub help: &'static str,
+}
+
+pub const COMPONENTS_HASHED: Metric = Metric {
+    name: "avocado_components_hashed_total",
+    kind: "counter",
+    help: "Number of components hashed",
+};
+pub const HASH_DURATION: Metric = Metric {
+    name: "avocado_hash_duration_seconds",
+    kind: "gauge",
+    help: "Time spent calculating hashes, per component",
+};
+pub const PROP_DURATION: Metric = Metric {
+    name: "avocado_prop_duration_seconds",
+    kind: "gauge",
+    help: "Time spent evaluating a property, per component",
+};
+pub const PROP_FAILURES: Metric = Metric {
+    name: "avocado_prop_failures_total",
+    kind: "counter",
+    help: "Number of property evaluations that failed",
+};
+pub const PHASE_DURATION: Metric = Metric {
+    name: "avocado_phase_duration_seconds",
+    kind: "gauge",
+    help: "Time spent in each phase, summed across threads",
+};
+pub const RUN_DURATION: Metric = Metric {
+    name: "avocado_run_duration_seconds",
+    kind: "gauge",
+    help: "Total time taken by the subcommand",
+};
+
+/// Phases reported by `--timings`, in the order they usually happen.
+pub const PHASES: &[&str] = &["load", "sort", "hash", "exec", "write"];
+
+type Key = (&'static str, Vec<(&'static str, String)>);
+
+/// Process-wide store of samples, only written out when requested.
+#[derive(Default)]
+pub struct Metrics {
+    samples: Mutex<BTreeMap<Key, (&'static Metric, f64)>>,
+}
+
+pub fn global() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+    pub fn add(&self, metric: &'static Metric, labels: &[(&'static str, &str)], value: f64) {
+        let key = (
+            metric.name,
+            labels.iter().map(|(k, v)| (*k, (*v).to_owned())).collect(),
+        );
+        let mut samples = self.samples.lock().unwrap();
+        samples.entry(key).or_insert((metric, 0.0)).1 += value;
+    }
+
+    pub fn add_duration(
+        &self,
+        metric: &'static Metric,
+        labels: &[(&'static str, &str)],
+        duration: Duration,
+    ) {
+        self.add(metric, labels, duration.as_secs_f64());
+    }
+
+    /// Time `f` as part of `phase`, one of `PHASES`.
+    pub fn time_phase<T>(&self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = f();
+        self.add_duration(&PHASE_DURATION, &[("phase", phase)], start.elapsed());
+        res
+    }
+
+    /// One line per phase with the seconds spent in it, for `--timings`.
+    pub fn render_phases(&self) -> String {
+        let samples = self.samples.lock().unwrap();
+        let mut out = String::new();
+        for phase in PHASES {
+            let key = (PHASE_DURATION.name, vec![("phase", (*phase).to_owned())]);
+            let secs = samples.get(&key).map_or(0.0, |(_, v)| *v);
+            out.push_str(&format!("{:<6} {:>9.3}s\n", phase, secs));
+        }
+        out
+    }
+
+    /// Render in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let samples = self.samples.lock().unwrap();
+        let mut out = String::new();
+        let mut last = "";
+        for ((name, labels), (metric, value)) in samples.iter() {
+            if *name != last {
+                out.push_str(&format!("# HELP {} {}\n", name, metric.help));
+                out.push_str(&format!("# TYPE {} {}\n", name, metric.kind));
+                last = name;
+            }
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+                .collect();
+            if labels.is_empty() {
+                out.push_str(&format!("{} {}\n", name, value));
+            } else {
+                out.push_str(&format!("{}{{{}}} {}\n", name, labels.join(","), value));
+            }
+        }
+        out
+    }
+
+    /// Write atomically, as the textfile collector may read at any time.
+    pub fn write_to(&self, path: &Path) -> std::io::Result<()> {
+        let tmp = path.with_extension("prom.tmp");
+        std::fs::write(&tmp, self.render())?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
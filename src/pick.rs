@@ -0,0 +1,117 @@
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use crate::manifest::LoadOptions;
+
+/// Interactively narrow the component list with fuzzy filters typed on
+/// stdin, then print the chosen dirs on stdout. The list and prompts go to
+/// stderr so the selection can be captured with `$(...)`.
+pub fn run_pick(
+    path: &Path,
+    load: &LoadOptions,
+    multi: bool,
+    query: Option<&str>,
+) -> anyhow::Result<()> {
+    let components = load.load_components(path)?;
+    let dirs: Vec<&str> = components.iter().map(|c| c.dir.as_str()).collect();
+    let mut query = query.unwrap_or("").to_owned();
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        let matches = fuzzy_filter(&dirs, &query);
+        if matches.is_empty() {
+            eprintln!("No components match {:?}", query);
+        }
+        for (i, dir) in matches.iter().enumerate() {
+            eprintln!("{:>4}  {}", i + 1, dir);
+        }
+        eprint!(
+            "{} [{}]> ",
+            if multi {
+                "Numbers to pick, or a new filter"
+            } else {
+                "Number to pick, or a new filter"
+            },
+            query
+        );
+        std::io::stderr().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => anyhow::bail!("no component picked"),
+        };
+        let line = line.trim();
+        if line.is_empty() && matches.len() == 1 {
+            println!("{}", matches[0]);
+            return Ok(());
+        }
+        if let Some(picked) = parse_picks(line, matches.len()) {
+            if multi || picked.len() == 1 {
+                for i in picked {
+                    println!("{}", matches[i]);
+                }
+                return Ok(());
+            }
+            eprintln!("Pick a single component, or use --multi");
+            continue;
+        }
+        query = line.to_owned();
+    }
+}
+
+/// Zero-based indexes from space or comma separated one-based numbers,
+/// or None if `line` is not entirely valid numbers.
+fn parse_picks(line: &str, count: usize) -> Option<Vec<usize>> {
+    let picked: Option<Vec<usize>> = line
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<usize>()
+                .ok()
+                .filter(|n| (1..=count).contains(n))
+                .map(|n| n - 1)
+        })
+        .collect();
+    picked.filter(|p| !p.is_empty())
+}
+
+/// Dirs containing the characters of `query` in order, case-insensitively,
+/// best matches first: tighter matches, then shorter dirs.
+fn fuzzy_filter<'a>(dirs: &[&'a str], query: &str) -> Vec<&'a str> {
+    let query: Vec<char> = query
+        .to_lowercase()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let mut scored: Vec<(usize, usize, &str)> = dirs
+        .iter()
+        .filter_map(|dir| {
+            match_span(&dir.to_lowercase(), &query).map(|span| (span, dir.len(), *dir))
+        })
+        .collect();
+    scored.sort();
+    scored.into_iter().map(|(_, _, dir)| dir).collect()
+}
+
+/// Length of the shortest stretch of `text` containing `query` as a
+/// subsequence, if it does at all.
+fn match_span(text: &str, query: &[char]) -> Option<usize> {
+    let chars: Vec<char> = text.chars().collect();
+    if query.is_empty() {
+        return Some(0);
+    }
+    (0..chars.len())
+        .filter(|start| chars[*start] == query[0])
+        .filter_map(|start| {
+            let mut q = 0;
+            for (i, c) in chars[start..].iter().enumerate() {
+                if *c == query[q] {
+                    q += 1;
+                    if q == query.len() {
+                        return Some(i + 1);
+                    }
+                }
+            }
+            None
+        })
+        .min()
+}
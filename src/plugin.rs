@@ -0,0 +1,53 @@
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::executor::{check_hermetic, hermetic_policy, restrict_env};
+use crate::manifest::LoadOptions;
+
+/// Plugins are executables named `avocado-build-helper-<name>` on PATH.
+pub const PLUGIN_PREFIX: &str = "avocado-build-helper-";
+
+/// Run the plugin for an unknown subcommand `name` with `args`, giving it
+/// the resolved manifest of `path` as JSON on stdin (`null` where there is
+/// no components.json) and `AVOCADO_MANIFEST_DIR`, returning its exit
+/// status. With `hermetic` the plugin's full program name must be
+/// allowlisted and it sees only the allowlisted environment.
+pub fn run_plugin(
+    path: &Path,
+    load: &LoadOptions,
+    hermetic: bool,
+    name: &str,
+    args: &[&str],
+) -> anyhow::Result<i32> {
+    let manifest = if load.manifest_file(path).is_file() {
+        serde_json::to_vec(&load.load_manifest(path)?)?
+    } else {
+        b"null".to_vec()
+    };
+    let program = format!("{}{}", PLUGIN_PREFIX, name);
+    let hermetic = hermetic_policy(path, load, hermetic)?;
+    check_hermetic(hermetic.as_ref(), &program)?;
+    let mut com = Command::new(&program);
+    restrict_env(hermetic.as_ref(), &mut com);
+    let mut child = match com
+        .args(args)
+        .env("AVOCADO_MANIFEST_DIR", path)
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            anyhow::bail!("No subcommand {:?}, and no {} on PATH", name, program)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || {
+        // plugins needn't read the manifest
+        let _ = stdin.write_all(&manifest);
+    });
+    let status = child.wait()?;
+    writer.join().unwrap();
+    Ok(status.code().unwrap_or(1))
+}
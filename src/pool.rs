@@ -0,0 +1,36 @@
+use std::sync::Mutex;
+
+/// Apply `f` to every item using up to `jobs` threads. All items are
+/// attempted; the first error in item order is returned.
+pub fn for_each_parallel<T, F>(items: &mut [T], jobs: usize, f: F) -> anyhow::Result<()>
+where
+    T: Send,
+    F: Fn(&mut T) -> anyhow::Result<()> + Sync,
+{
+    if jobs <= 1 || items.len() <= 1 {
+        return items.iter_mut().try_for_each(f);
+    }
+    let queue = Mutex::new(items.iter_mut().enumerate());
+    let errors = Mutex::new(Vec::new());
+    std::thread::scope(|s| {
+        for _ in 0..jobs.min(queue.lock().unwrap().len()) {
+            s.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some((i, item)) => {
+                        if let Err(e) = f(item) {
+                            errors.lock().unwrap().push((i, e));
+                        }
+                    }
+                    None => break,
+                }
+            });
+        }
+    });
+    let mut errors = errors.into_inner().unwrap();
+    errors.sort_by_key(|(i, _)| *i);
+    match errors.into_iter().next() {
+        Some((_, e)) => Err(e),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,14 @@
+use std::path::Path;
+
+use crate::hasher::{hash_components_with_proofs, HashOptions};
+use crate::types::*;
+
+/// Print the exact inputs to `component`'s tree hash as JSON.
+pub fn run_prove(path: &Path, component: &str, options: &HashOptions) -> anyhow::Result<()> {
+    let (_, proofs) = hash_components_with_proofs(path, options, |_| Ok(()))?;
+    let proof = proofs
+        .get(component)
+        .ok_or_else(|| CustomError::MissingComponentError(vec![component.to_owned()]))?;
+    println!("{}", serde_json::to_string_pretty(proof)?);
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{stdout, Write};
+use std::path::Path;
+
+use crate::hasher::{hash_components, HashOptions};
+use crate::types::{check_components_exist, expand_patterns, Component};
+use crate::vcs::Vcs;
+
+pub fn run_provenance(
+    path: &Path,
+    components: &[&str],
+    remote: &str,
+    pretty_print: bool,
+    options: &HashOptions,
+) -> Result<(), anyhow::Error> {
+    let vcs = Vcs::discover(path)?;
+    let hashed = hash_components(path, options, |_| Ok(()))?;
+    let dirs = expand_patterns(&hashed, components)?;
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+    check_components_exist(&hashed, &dirs)?;
+    let by_dir: HashMap<&str, &Component> = hashed.iter().map(|c| (c.dir.as_str(), c)).collect();
+    let invocation: Vec<String> = std::env::args().collect();
+    let docs: Vec<Value> = hashed
+        .iter()
+        .filter(|c| dirs.is_empty() || dirs.contains(&c.dir.as_str()))
+        .map(|comp| {
+            let dir = path.join(&comp.dir);
+            let commit = comp.commit_sha.as_ref().unwrap();
+            let source = vcs
+                .remote_url(&dir, remote)
+                .unwrap_or_else(|| vcs.repo_for(&dir).root.to_string_lossy().into_owned());
+            let mut materials = vec![json!({
+                "uri": format!("git+{}@{}", source, commit),
+                "digest": { "sha1": commit },
+            })];
+            materials.extend(comp.depsorted().iter().map(|d| {
+                json!({
+                    "uri": format!("component:{}", d),
+                    "digest": { "sha256": by_dir[d.as_str()].tree_sha },
+                })
+            }));
+            json!({
+                "_type": "https://in-toto.io/Statement/v0.1",
+                "predicateType": "https://slsa.dev/provenance/v0.2",
+                "subject": [{
+                    "name": comp.dir,
+                    "digest": { "sha256": comp.tree_sha },
+                }],
+                "predicate": {
+                    "builder": {
+                        "id": format!("avocado-build-helper@{}", env!("CARGO_PKG_VERSION")),
+                    },
+                    "invocation": {
+                        "configSource": {
+                            "uri": format!("git+{}@{}", source, commit),
+                            "entryPoint": comp.dir,
+                        },
+                        "parameters": { "argv": invocation },
+                    },
+                    "materials": materials,
+                },
+            })
+        })
+        .collect();
+    let json = if pretty_print {
+        serde_json::to_string_pretty(&docs)?
+    } else {
+        serde_json::to_string(&docs)?
+    };
+    stdout().write_all(json.as_ref())?;
+    Ok(())
+}
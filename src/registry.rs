@@ -0,0 +1,439 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use regex::Regex;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use crate::hasher::{hash_components, HashOptions};
+
+/// Manifest types we can make sense of, indexes first so multi-platform
+/// images are looked up as a whole.
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+     application/vnd.docker.distribution.manifest.list.v2+json, \
+     application/vnd.oci.image.manifest.v1+json, \
+     application/vnd.docker.distribution.manifest.v2+json";
+
+/// An image reference like `ghcr.io/org/app:tag`, with Docker Hub's
+/// defaults filled in.
+#[derive(Debug, Clone)]
+pub struct ImageRef {
+    pub registry: String,
+    pub repository: String,
+    /// A tag or a `sha256:` digest.
+    pub reference: String,
+}
+
+impl ImageRef {
+    pub fn parse(s: &str) -> anyhow::Result<ImageRef> {
+        let (name, reference) = match s.split_once('@') {
+            Some((name, digest)) => (name, digest.to_owned()),
+            None => match s.rfind(':') {
+                Some(i) if !s[i..].contains('/') => (&s[..i], s[i + 1..].to_owned()),
+                _ => (s, "latest".to_owned()),
+            },
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, rest))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_owned(), rest.to_owned())
+            }
+            Some(_) => ("docker.io".to_owned(), name.to_owned()),
+            None => ("docker.io".to_owned(), format!("library/{}", name)),
+        };
+        if repository.is_empty() || reference.is_empty() {
+            anyhow::bail!("invalid image reference {:?}", s);
+        }
+        Ok(ImageRef {
+            registry,
+            repository,
+            reference,
+        })
+    }
+
+    /// This image pinned to `digest`, as `registry/repository@digest`.
+    pub fn with_digest(&self, digest: &str) -> String {
+        format!("{}/{}@{}", self.registry, self.repository, digest)
+    }
+
+    fn api_base(&self) -> String {
+        let host = if self.registry == "docker.io" {
+            "registry-1.docker.io"
+        } else {
+            &self.registry
+        };
+        // local test registries rarely have certificates
+        let scheme = if host.starts_with("localhost") || host.starts_with("127.0.0.1") {
+            "http"
+        } else {
+            "https"
+        };
+        format!("{}://{}/v2/{}", scheme, host, self.repository)
+    }
+}
+
+/// What the registry says about a manifest, enough to reference it by digest.
+#[derive(Debug, Clone)]
+pub struct ManifestInfo {
+    pub digest: String,
+    pub media_type: String,
+    pub size: u64,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Registry API client using credentials from the Docker config (plain
+/// `auths` or credential helpers), over ureq with rustls and any proxy set
+/// in the environment. Tokens are cached per registry and scope, and it can
+/// be shared between threads.
+pub struct Registry {
+    agent: ureq::Agent,
+    tokens: Mutex<HashMap<(String, String), String>>,
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry {
+            agent: ureq::AgentBuilder::new().try_proxy_from_env(true).build(),
+            tokens: Mutex::default(),
+        }
+    }
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// The manifest `image` refers to, or None if the registry doesn't
+    /// have it. Registries needn't send the digest or size with a HEAD, so
+    /// without them the manifest is fetched and they are worked out from
+    /// its bytes.
+    pub fn manifest(&self, image: &ImageRef) -> anyhow::Result<Option<ManifestInfo>> {
+        let url = format!("{}/manifests/{}", image.api_base(), image.reference);
+        let res = self.request(image, "HEAD", &url, Some(MANIFEST_TYPES))?;
+        match res.status {
+            200 => (),
+            404 => return Ok(None),
+            status => anyhow::bail!("{} returned HTTP {}", url, status),
+        }
+        let digest = res.header("docker-content-digest");
+        let size = res.header("content-length").and_then(|l| l.parse().ok());
+        if let (Some(digest), Some(size)) = (digest, size) {
+            return Ok(Some(ManifestInfo {
+                digest: digest.to_owned(),
+                media_type: res.header("content-type").unwrap_or_default().to_owned(),
+                size,
+            }));
+        }
+        let res = self.request(image, "GET", &url, Some(MANIFEST_TYPES))?;
+        match res.status {
+            200 => Ok(Some(ManifestInfo {
+                digest: format!("sha256:{}", hex::encode(Sha256::digest(&res.body))),
+                media_type: res.header("content-type").unwrap_or_default().to_owned(),
+                size: res.body.len() as u64,
+            })),
+            404 => Ok(None),
+            status => anyhow::bail!("{} returned HTTP {}", url, status),
+        }
+    }
+
+    /// Whether the registry has a manifest for `image`.
+    pub fn exists(&self, image: &ImageRef) -> anyhow::Result<bool> {
+        let url = format!("{}/manifests/{}", image.api_base(), image.reference);
+        match self
+            .request(image, "HEAD", &url, Some(MANIFEST_TYPES))?
+            .status
+        {
+            200 => Ok(true),
+            404 => Ok(false),
+            status => anyhow::bail!("{} returned HTTP {}", url, status),
+        }
+    }
+
+    /// Every tag in the repository of `image`, following pagination.
+    pub fn tags(&self, image: &ImageRef) -> anyhow::Result<Vec<String>> {
+        let base = image.api_base();
+        let mut url = format!("{}/tags/list", base);
+        let mut tags = Vec::new();
+        let next_link = Regex::new(r"<([^>]+)>\s*;\s*rel=.?next").unwrap();
+        loop {
+            let res = self.request(image, "GET", &url, None)?;
+            match res.status {
+                200 => (),
+                404 => return Ok(tags),
+                status => anyhow::bail!("{} returned HTTP {}", url, status),
+            }
+            let page: Value = serde_json::from_slice(&res.body)?;
+            if let Some(page) = page["tags"].as_array() {
+                tags.extend(page.iter().filter_map(Value::as_str).map(str::to_owned));
+            }
+            match res.header("link").and_then(|l| next_link.captures(l)) {
+                // the link is relative to the registry host
+                Some(c) => url = format!("{}{}", &base[..base.find("/v2/").unwrap()], &c[1]),
+                None => return Ok(tags),
+            }
+        }
+    }
+
+    fn request(
+        &self,
+        image: &ImageRef,
+        method: &str,
+        url: &str,
+        accept: Option<&str>,
+    ) -> anyhow::Result<Response> {
+        let scope = format!("repository:{}:pull", image.repository);
+        let key = (image.registry.clone(), scope.clone());
+        let cached = self.tokens.lock().unwrap().get(&key).cloned();
+        let headers: Vec<(&str, &str)> = accept.iter().map(|a| ("Accept", *a)).collect();
+        let with_auth = |auth| {
+            let mut h = headers.clone();
+            h.push(("Authorization", auth));
+            h
+        };
+        let res = match cached.as_ref() {
+            Some(auth) => send(&self.agent, method, url, &with_auth(auth))?,
+            None => send(&self.agent, method, url, &headers)?,
+        };
+        if res.status != 401 {
+            return Ok(res);
+        }
+        let challenge = res
+            .header("www-authenticate")
+            .unwrap_or_default()
+            .to_owned();
+        let auth = authorize(&self.agent, &image.registry, &challenge, &scope)?;
+        let res = send(&self.agent, method, url, &with_auth(&auth))?;
+        if res.status != 401 {
+            self.tokens.lock().unwrap().insert(key, auth);
+        }
+        Ok(res)
+    }
+}
+
+/// Whether every one of `images` exists in its registry.
+pub fn run_image_exists(images: &[&str]) -> anyhow::Result<bool> {
+    let registry = Registry::new();
+    for image in images {
+        if !registry.exists(&ImageRef::parse(image)?)? {
+            log::info!("{} does not exist", image);
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// List the tags of `repo` ending in a tree hash (full or short), saying
+/// whether each is the current hash of some component (`live`) or of none
+/// (`stale`, so safe to delete). Tags that aren't hashes are left out.
+pub fn run_registry_report(path: &Path, repo: &str, options: &HashOptions) -> anyhow::Result<()> {
+    let components = hash_components(path, options, |_| Ok(()))?;
+    let mut live: HashMap<&str, Vec<&str>> = HashMap::new();
+    for comp in components.iter() {
+        for sha in [&comp.tree_sha, &comp.tree_sha_short]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            live.entry(sha.as_str()).or_default().push(&comp.dir);
+        }
+    }
+    let hash_tag = Regex::new(r"(?:^|[-_.])([0-9a-f]{16}|[0-9a-f]{64})$").unwrap();
+    let mut tags = Registry::new().tags(&ImageRef::parse(repo)?)?;
+    tags.sort();
+    for tag in tags {
+        let hash = match hash_tag.captures(&tag) {
+            Some(c) => c[1].to_owned(),
+            None => continue,
+        };
+        match live.get(hash.as_str()) {
+            Some(dirs) => println!("live\t{}\t{}", tag, dirs.join(" ")),
+            None => println!("stale\t{}", tag),
+        }
+    }
+    Ok(())
+}
+
+/// An Authorization header value answering `challenge`.
+fn authorize(
+    agent: &ureq::Agent,
+    registry: &str,
+    challenge: &str,
+    scope: &str,
+) -> anyhow::Result<String> {
+    let basic = credentials(registry)?
+        .map(|(user, secret)| format!("Basic {}", BASE64.encode(format!("{}:{}", user, secret))));
+    let (kind, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+    if kind.eq_ignore_ascii_case("basic") {
+        return basic.ok_or_else(|| anyhow::anyhow!("no credentials for {}", registry));
+    }
+    if !kind.eq_ignore_ascii_case("bearer") {
+        anyhow::bail!(
+            "unsupported auth challenge from {}: {:?}",
+            registry,
+            challenge
+        );
+    }
+    let params: HashMap<String, String> = Regex::new(r#"(\w+)="([^"]*)""#)
+        .unwrap()
+        .captures_iter(params)
+        .map(|c| (c[1].to_owned(), c[2].to_owned()))
+        .collect();
+    let realm = params
+        .get("realm")
+        .ok_or_else(|| anyhow::anyhow!("no realm in auth challenge from {}", registry))?;
+    let mut url = format!("{}?scope={}", realm, query_escape(scope));
+    if let Some(service) = params.get("service") {
+        url.push_str(&format!("&service={}", query_escape(service)));
+    }
+    let headers: Vec<(&str, &str)> = basic
+        .iter()
+        .map(|b| ("Authorization", b.as_str()))
+        .collect();
+    let res = send(agent, "GET", &url, &headers)?;
+    if res.status != 200 {
+        anyhow::bail!("token request to {} returned HTTP {}", realm, res.status);
+    }
+    let body: Value = serde_json::from_slice(&res.body)?;
+    let token = body["token"]
+        .as_str()
+        .or_else(|| body["access_token"].as_str())
+        .ok_or_else(|| anyhow::anyhow!("no token from {}", realm))?;
+    Ok(format!("Bearer {}", token))
+}
+
+/// Username and secret for `registry` from the Docker config, asking a
+/// credential helper when one is configured.
+fn credentials(registry: &str) -> anyhow::Result<Option<(String, String)>> {
+    let dir = match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => PathBuf::from(dir),
+        None => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".docker"),
+            None => return Ok(None),
+        },
+    };
+    let config: Value = match std::fs::read(dir.join("config.json")) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    // Docker Hub credentials are stored under its old index URL
+    let server = if registry == "docker.io" {
+        "https://index.docker.io/v1/"
+    } else {
+        registry
+    };
+    let helper = config["credHelpers"][server]
+        .as_str()
+        .or_else(|| config["credsStore"].as_str());
+    if let Some(helper) = helper {
+        return credential_helper(helper, server);
+    }
+    let host = |k: &str| {
+        k.trim_start_matches("https://")
+            .trim_start_matches("http://")
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let wanted = host(server);
+    let auth = config["auths"].as_object().and_then(|auths| {
+        auths
+            .iter()
+            .find(|(k, _)| host(k) == wanted)
+            .and_then(|(_, v)| v["auth"].as_str())
+    });
+    let decoded = match auth {
+        Some(auth) => BASE64.decode(auth.trim())?,
+        None => return Ok(None),
+    };
+    match String::from_utf8(decoded)?.split_once(':') {
+        Some((user, secret)) => Ok(Some((user.to_owned(), secret.to_owned()))),
+        None => anyhow::bail!("malformed auth for {} in docker config", registry),
+    }
+}
+
+fn credential_helper(helper: &str, server: &str) -> anyhow::Result<Option<(String, String)>> {
+    let mut child = Command::new(format!("docker-credential-{}", helper))
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(server.as_bytes())?;
+    let out = child.wait_with_output()?;
+    if !out.status.success() {
+        // helpers exit non-zero when they have nothing for the server
+        return Ok(None);
+    }
+    let creds: Value = serde_json::from_slice(&out.stdout)?;
+    Ok(creds["Username"]
+        .as_str()
+        .zip(creds["Secret"].as_str())
+        .map(|(u, s)| (u.to_owned(), s.to_owned())))
+}
+
+/// Send a request, taking error statuses as responses for the caller to
+/// handle rather than failures.
+fn send(
+    agent: &ureq::Agent,
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+) -> anyhow::Result<Response> {
+    let mut req = agent.request(method, url);
+    for (name, value) in headers {
+        req = req.set(name, value);
+    }
+    let res = match req.call() {
+        Ok(res) | Err(ureq::Error::Status(_, res)) => res,
+        Err(e) => anyhow::bail!("{} {}", method, e),
+    };
+    let headers = res
+        .headers_names()
+        .into_iter()
+        .map(|k| {
+            let v = res.header(&k).unwrap_or_default().to_owned();
+            (k, v)
+        })
+        .collect();
+    let status = res.status();
+    let mut body = Vec::new();
+    res.into_reader().read_to_end(&mut body)?;
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+fn query_escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
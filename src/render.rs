@@ -0,0 +1,127 @@
+use handlebars::Handlebars;
+use std::fs::{create_dir_all, read_to_string, write};
+use std::path::Path;
+
+use crate::hasher::{hash_components, HashOptions};
+use crate::helpers::register_helpers;
+use crate::manifest::LoadOptions;
+use crate::types::*;
+
+/// Components named by `dirs`, or all of them when empty, in topological
+/// order and hashed when `hash` is given.
+fn select_components<F>(
+    path: &Path,
+    load: &LoadOptions,
+    dirs: &[&str],
+    hash: Option<&HashOptions>,
+    post_process: F,
+) -> anyhow::Result<Vec<Component>>
+where
+    F: Fn(&mut Component) -> anyhow::Result<()> + Sync,
+{
+    let mut components = match hash {
+        Some(options) => hash_components(path, options, post_process)?,
+        None => toposort_components(load.load_components(path)?)?,
+    };
+    check_components_exist(&components, dirs)?;
+    if !dirs.is_empty() {
+        components.retain(|c| dirs.contains(&c.dir.as_str()));
+    }
+    Ok(components)
+}
+
+/// Print `template` rendered once for each selected component.
+pub fn run_render(
+    path: &Path,
+    load: &LoadOptions,
+    template: &str,
+    dirs: &[&str],
+    hash: Option<&HashOptions>,
+) -> anyhow::Result<()> {
+    let mut reg = Handlebars::new();
+    reg.register_escape_fn(handlebars::no_escape);
+    register_helpers(&mut reg);
+    reg.register_template_string("template", template)
+        .map_err(|e| CustomError::TemplateError {
+            prop_name: "template".to_owned(),
+            error: Box::new(e),
+        })?;
+    for comp in select_components(path, load, dirs, hash, |_| Ok(()))? {
+        let rendered =
+            reg.render("template", &comp)
+                .map_err(|e| CustomError::TemplateRenderError {
+                    cmd_name: format!("template for {}", comp.dir),
+                    error: Box::new(e),
+                })?;
+        println!("{}", rendered);
+    }
+    Ok(())
+}
+
+/// Render the file `template` for each selected component, hashed and with
+/// its props, writing it to the path rendered from `out`, relative to
+/// components.json.
+pub fn run_template_files<F>(
+    path: &Path,
+    template: &Path,
+    out: &str,
+    dirs: &[&str],
+    options: &HashOptions,
+    post_process: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(&mut Component) -> anyhow::Result<()> + Sync,
+{
+    let mut reg = Handlebars::new();
+    reg.register_escape_fn(handlebars::no_escape);
+    register_helpers(&mut reg);
+    for (name, source) in [
+        ("template", read_to_string(template)?),
+        ("out", out.to_owned()),
+    ] {
+        reg.register_template_string(name, source)
+            .map_err(|e| CustomError::TemplateError {
+                prop_name: name.to_owned(),
+                error: Box::new(e),
+            })?;
+    }
+    for comp in select_components(path, &options.load, dirs, Some(options), post_process)? {
+        let render = |name: &str| {
+            reg.render(name, &comp)
+                .map_err(|e| CustomError::TemplateRenderError {
+                    cmd_name: format!("{} for {}", name, comp.dir),
+                    error: Box::new(e),
+                })
+        };
+        let file = path.join(render("out")?);
+        if let Some(parent) = file.parent() {
+            create_dir_all(parent)?;
+        }
+        write(&file, render("template")?)?;
+        println!("{}", file.strip_prefix(path).unwrap_or(&file).display());
+    }
+    Ok(())
+}
+
+/// Write each selected component, hashed and with its props, to
+/// `<out_dir>/<slug>.json` so per-component jobs need only their own file.
+pub fn run_split<F>(
+    path: &Path,
+    out_dir: &Path,
+    dirs: &[&str],
+    options: &HashOptions,
+    post_process: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(&mut Component) -> anyhow::Result<()> + Sync,
+{
+    let components = select_components(path, &options.load, dirs, Some(options), post_process)?;
+    check_slugs(components.iter().map(|c| c.dir.as_str()))?;
+    create_dir_all(out_dir)?;
+    for comp in components {
+        let file = out_dir.join(format!("{}.json", slug(&comp.dir)));
+        write(&file, serde_json::to_string_pretty(&comp)? + "\n")?;
+        println!("{}", file.display());
+    }
+    Ok(())
+}
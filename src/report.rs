@@ -0,0 +1,102 @@
+use handlebars::Handlebars;
+use serde_json::json;
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::affected::affected_components;
+use crate::graph::render_svg;
+use crate::hasher::{hash_components, HashOptions};
+use crate::state::BuildState;
+use crate::types::*;
+
+const TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Build impact: {{root}}</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; }
+td.num { text-align: right; }
+tr.affected { background: #fdd; }
+code { font-size: 0.9em; }
+</style>
+</head>
+<body>
+<h1>Build impact: {{root}}</h1>
+<p>{{affected_count}} of {{count}} components affected by changes since <code>{{since}}</code>{{#if total}}; {{total}}s of build time recorded{{/if}}.</p>
+<div style="overflow: auto">
+{{{svg}}}
+</div>
+<table>
+<tr><th>Component</th><th>Affected</th><th>Tree sha</th><th>Commit</th><th>Dependencies</th><th>Owners</th><th>Last build (s)</th></tr>
+{{#each components}}
+<tr{{#if affected}} class="affected"{{/if}}>
+<td>{{dir}}</td>
+<td>{{#if affected}}yes{{/if}}</td>
+<td><code title="{{tree_sha}}">{{tree_sha_short}}</code></td>
+<td><code title="{{commit_sha}}">{{commit_sha_short}}</code></td>
+<td>{{#each dependencies}}{{this}}{{#unless @last}}, {{/unless}}{{/each}}</td>
+<td>{{#each owners}}{{this}}{{#unless @last}}, {{/unless}}{{/each}}</td>
+<td class="num">{{duration}}</td>
+</tr>
+{{/each}}
+</table>
+</body>
+</html>
+"#;
+
+/// Write a self-contained HTML page with the dependency graph, each
+/// component's hashes, what changes since `since` affect and, from a build
+/// `state` file, how long components last took to build.
+pub fn run_report(
+    path: &Path,
+    out: &Path,
+    since: &str,
+    state: Option<&Path>,
+    options: &HashOptions,
+) -> anyhow::Result<()> {
+    let components = hash_components(path, options, |_| Ok(()))?;
+    let affected: HashSet<String> =
+        affected_components(path, &options.load, &[], Some(since), false)?
+            .into_iter()
+            .map(|c| c.dir)
+            .collect();
+    let state = match state {
+        Some(file) => BuildState::load(file)?,
+        None => BuildState::default(),
+    };
+    let svg = render_svg(&toposort_levels(components.clone())?, &affected);
+    let rows: Vec<_> = components
+        .iter()
+        .map(|c| {
+            json!({
+                "dir": c.dir,
+                "affected": affected.contains(&c.dir),
+                "tree_sha": c.tree_sha,
+                "tree_sha_short": c.tree_sha_short,
+                "commit_sha": c.commit_sha,
+                "commit_sha_short": c.commit_sha_short,
+                "dependencies": c.depsorted(),
+                "owners": c.owners,
+                "duration": state.durations.get(&c.dir).map(|d| format!("{:.1}", d)),
+            })
+        })
+        .collect();
+    let total: f64 = state.durations.values().sum();
+    let data = json!({
+        "root": path.display().to_string(),
+        "since": since,
+        "affected_count": affected.len(),
+        "count": rows.len(),
+        "components": rows,
+        "svg": svg,
+        "total": if state.durations.is_empty() { None } else { Some(format!("{:.1}", total)) },
+    });
+    let html = Handlebars::new()
+        .render_template(TEMPLATE, &data)
+        .expect("report template renders");
+    std::fs::write(out, html)?;
+    Ok(())
+}
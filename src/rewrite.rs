@@ -0,0 +1,449 @@
+use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use serde_json::ser::PrettyFormatter;
+use serde_json::{Number, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use crate::lock::lock;
+use crate::manifest::{
+    has_comments, is_yaml, merge_manifest_files, parse_manifest, read_manifest_file, strip_jsonc,
+    LoadOptions,
+};
+use crate::metrics;
+use crate::types::{normalize_dir, to_slash, Component, CustomError};
+use crate::yaml;
+
+/// A JSON document that keeps object keys in the order they were written,
+/// which `serde_json::Value` (always sorted) loses.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+    Array(Vec<Node>),
+    Object(Vec<(String, Node)>),
+}
+
+impl Node {
+    pub fn parse(text: &str) -> serde_json::Result<Node> {
+        serde_json::from_str(&strip_jsonc(text))
+    }
+
+    fn get(&self, key: &str) -> Option<&Node> {
+        match self {
+            Node::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Convert `value`, ordering keys the way they appear in `old` with any
+    /// new keys after them. Array elements are matched up by `dir` where
+    /// they have one, otherwise by position.
+    pub fn reorder(value: Value, old: Option<&Node>) -> Node {
+        match value {
+            Value::Null => Node::Null,
+            Value::Bool(b) => Node::Bool(b),
+            Value::Number(n) => Node::Number(n),
+            Value::String(s) => Node::String(s),
+            Value::Array(items) => {
+                let olds: &[Node] = match old {
+                    Some(Node::Array(olds)) => olds,
+                    _ => &[],
+                };
+                let by_dir = |item: &Value| {
+                    let dir = item.get("dir").and_then(Value::as_str)?;
+                    olds.iter()
+                        .find(|o| matches!(o.get("dir"), Some(Node::String(d)) if d == dir))
+                };
+                let items = items.into_iter().enumerate().map(|(i, item)| {
+                    let old = by_dir(&item).or_else(|| olds.get(i));
+                    Node::reorder(item, old)
+                });
+                Node::Array(items.collect())
+            }
+            Value::Object(mut map) => {
+                let mut entries = Vec::with_capacity(map.len());
+                if let Some(Node::Object(olds)) = old {
+                    for (k, o) in olds {
+                        if let Some(v) = map.remove(k) {
+                            entries.push((k.clone(), Node::reorder(v, Some(o))));
+                        }
+                    }
+                }
+                entries.extend(map.into_iter().map(|(k, v)| (k, Node::reorder(v, None))));
+                Node::Object(entries)
+            }
+        }
+    }
+}
+
+impl Serialize for Node {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Node::Null => serializer.serialize_unit(),
+            Node::Bool(b) => serializer.serialize_bool(*b),
+            Node::Number(n) => n.serialize(serializer),
+            Node::String(s) => serializer.serialize_str(s),
+            Node::Array(items) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+            Node::Object(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (k, v) in entries {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Node, D::Error> {
+        deserializer.deserialize_any(NodeVisitor)
+    }
+}
+
+struct NodeVisitor;
+
+impl<'de> Visitor<'de> for NodeVisitor {
+    type Value = Node;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Node, E> {
+        Ok(Node::Null)
+    }
+
+    fn visit_bool<E>(self, b: bool) -> Result<Node, E> {
+        Ok(Node::Bool(b))
+    }
+
+    fn visit_i64<E>(self, n: i64) -> Result<Node, E> {
+        Ok(Node::Number(n.into()))
+    }
+
+    fn visit_u64<E>(self, n: u64) -> Result<Node, E> {
+        Ok(Node::Number(n.into()))
+    }
+
+    fn visit_f64<E>(self, n: f64) -> Result<Node, E> {
+        Ok(Number::from_f64(n).map_or(Node::Null, Node::Number))
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Node, E> {
+        Ok(Node::String(s.to_owned()))
+    }
+
+    fn visit_string<E>(self, s: String) -> Result<Node, E> {
+        Ok(Node::String(s))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Node, A::Error> {
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Node::Array(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Node, A::Error> {
+        let mut entries: Vec<(String, Node)> = Vec::new();
+        while let Some((k, v)) = map.next_entry::<String, Node>()? {
+            // last one wins, as with serde_json::Value
+            entries.retain(|(e, _)| *e != k);
+            entries.push((k, v));
+        }
+        Ok(Node::Object(entries))
+    }
+}
+
+/// How an existing file is laid out, so rewrites can match it.
+#[derive(Debug, Clone)]
+pub struct Style {
+    /// `None` for single-line (compact) documents.
+    indent: Option<String>,
+    trailing_newline: bool,
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style {
+            indent: Some("  ".to_owned()),
+            trailing_newline: true,
+        }
+    }
+}
+
+impl Style {
+    /// Take the indent unit from the first indented line: a tab or a run of
+    /// spaces (typically 2 or 4).
+    pub fn detect(text: &str) -> Style {
+        let trimmed = text.trim();
+        if !trimmed.contains('\n') {
+            return Style {
+                indent: None,
+                trailing_newline: text.ends_with('\n'),
+            };
+        }
+        let indent = trimmed
+            .lines()
+            .skip(1)
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| {
+                if l.starts_with('\t') {
+                    "\t".to_owned()
+                } else {
+                    " ".repeat(l.len() - l.trim_start_matches(' ').len())
+                }
+            })
+            .find(|i| !i.is_empty())
+            .unwrap_or_else(|| "  ".to_owned());
+        Style {
+            indent: Some(indent),
+            trailing_newline: text.ends_with('\n'),
+        }
+    }
+
+    pub fn render(&self, node: &Node) -> String {
+        let mut out = Vec::new();
+        match &self.indent {
+            Some(indent) => {
+                let formatter = PrettyFormatter::with_indent(indent.as_bytes());
+                let mut ser = serde_json::Serializer::with_formatter(&mut out, formatter);
+                node.serialize(&mut ser).unwrap();
+            }
+            None => {
+                let mut ser = serde_json::Serializer::new(&mut out);
+                node.serialize(&mut ser).unwrap();
+            }
+        }
+        let mut out = String::from_utf8(out).unwrap();
+        if self.trailing_newline {
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Render `value` for writing to the manifest at `file`, keeping the key
+/// order and indentation of what is there. YAML is written in block style
+/// with keys sorted. Comments can't be kept, so a manifest with any is
+/// refused rather than rewritten without them.
+pub fn render_manifest(file: &Path, value: Value) -> anyhow::Result<String> {
+    let text = match read_to_string(file) {
+        Ok(text) => Some(text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(text) = &text {
+        let commented = if is_yaml(file) {
+            yaml::has_comments(text)
+        } else {
+            has_comments(text)
+        };
+        if commented {
+            anyhow::bail!(
+                "{:?} has comments, which rewriting it would lose; remove them first",
+                file
+            );
+        }
+    }
+    if is_yaml(file) {
+        return Ok(yaml::to_string(&value));
+    }
+    let (style, old) = match &text {
+        Some(text) => (Style::detect(text), Some(Node::parse(text)?)),
+        None => (Style::default(), None),
+    };
+    Ok(style.render(&Node::reorder(value, old.as_ref())))
+}
+
+/// Atomically replace the manifest at `file`, see `render_manifest`.
+pub fn write_manifest(file: &Path, value: Value) -> anyhow::Result<()> {
+    let out = render_manifest(file, value)?;
+    metrics::global().time_phase("write", || {
+        let mut tmp = file.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, out)?;
+        std::fs::rename(&tmp, file)
+    })?;
+    Ok(())
+}
+
+/// The manifest in `path`, which must exist so no lock is left beside a
+/// file that isn't there.
+pub fn existing_manifest(path: &Path, load: &LoadOptions) -> Result<PathBuf, CustomError> {
+    let file = load.manifest_file(path);
+    if file.is_file() {
+        Ok(file)
+    } else {
+        Err(CustomError::ManifestNotFoundError(file))
+    }
+}
+
+/// Rewrite the manifest in its own style, or with `check` only report
+/// whether that would change anything.
+pub fn run_fmt(path: &Path, load: &LoadOptions, wait: bool, check: bool) -> anyhow::Result<()> {
+    let file = existing_manifest(path, load)?;
+    let _lock = lock(&file, wait)?;
+    let text = read_to_string(&file)?;
+    let value = parse_manifest(&file, &text, is_yaml(&file))?;
+    if check {
+        if render_manifest(&file, value)? != text {
+            anyhow::bail!("{:?} is not formatted", file);
+        }
+        Ok(())
+    } else {
+        write_manifest(&file, value)
+    }
+}
+
+/// Merge manifests into `out`, or print the result, see
+/// `merge_manifest_files`.
+pub fn run_merge(
+    files: &[&Path],
+    deep: bool,
+    strict: bool,
+    out: Option<&Path>,
+    wait: bool,
+) -> anyhow::Result<()> {
+    output_manifest(merge_manifest_files(files, deep, strict)?, out, wait)
+}
+
+/// Write a generated manifest to `out`, or print it.
+pub fn output_manifest(value: Value, out: Option<&Path>, wait: bool) -> anyhow::Result<()> {
+    match out {
+        Some(out) => {
+            let _lock = lock(out, wait)?;
+            write_manifest(out, value)
+        }
+        None => {
+            // version and components first, as they'd be written by hand
+            let layout = Node::Object(vec![
+                ("version".to_owned(), Node::Null),
+                ("components".to_owned(), Node::Null),
+            ]);
+            print!(
+                "{}",
+                Style::default().render(&Node::reorder(value, Some(&layout)))
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Fields hash-components writes, besides the props in `generated_props`.
+const GENERATED_FIELDS: &[&str] = &[
+    "commit_sha",
+    "commit_sha_short",
+    "tree_sha",
+    "tree_sha_short",
+    "dirty",
+];
+
+fn manifest_components<'a>(
+    value: &'a mut Value,
+    file: &Path,
+) -> anyhow::Result<&'a mut Vec<Value>> {
+    match value {
+        Value::Array(components) => Ok(components),
+        Value::Object(m) => match m.get_mut("components") {
+            Some(Value::Array(components)) => Ok(components),
+            _ => anyhow::bail!("{:?} has no components array", file),
+        },
+        _ => anyhow::bail!("{:?} is not a manifest", file),
+    }
+}
+
+/// Write the hashes and generated props of `hashed` into the manifest at
+/// `file`, leaving everything written by hand as it is. The caller holds
+/// the lock on `file`, from before `hashed` was read from it.
+pub fn write_hashes(file: &Path, hashed: &[Component]) -> anyhow::Result<()> {
+    let mut value = read_manifest_file(file)?;
+    let by_dir: HashMap<String, &Component> =
+        hashed.iter().map(|c| (normalize_dir(&c.dir), c)).collect();
+    for comp in manifest_components(&mut value, file)?
+        .iter_mut()
+        .filter_map(Value::as_object_mut)
+    {
+        let dir = comp
+            .get("dir")
+            .and_then(Value::as_str)
+            .map(|d| normalize_dir(&to_slash(d)));
+        let hashed = match dir.and_then(|d| by_dir.get(&d)) {
+            Some(c) => serde_json::to_value(c)?,
+            None => continue,
+        };
+        // props generated last time may not be any more
+        let props = |c: Option<&Value>| -> Vec<String> {
+            c.and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|p| p.as_str().map(str::to_owned))
+                .collect()
+        };
+        let mut fields = props(comp.get("generated_props"));
+        fields.extend(props(hashed.get("generated_props")));
+        fields.extend(GENERATED_FIELDS.iter().map(|f| (*f).to_owned()));
+        fields.push("generated_props".to_owned());
+        for field in fields {
+            match hashed.get(&field) {
+                Some(v) => comp.insert(field, v.clone()),
+                None => comp.remove(&field),
+            };
+        }
+    }
+    write_manifest(file, value)
+}
+
+/// Strip hashes and generated props from the manifest, leaving what was
+/// written by hand. With `only` just those fields go, and fields in `keep`
+/// always stay.
+pub fn run_prune(
+    path: &Path,
+    load: &LoadOptions,
+    wait: bool,
+    keep: &[&str],
+    only: &[&str],
+) -> anyhow::Result<()> {
+    let file = existing_manifest(path, load)?;
+    let _lock = lock(&file, wait)?;
+    let mut value = read_manifest_file(&file)?;
+    let components = manifest_components(&mut value, &file)?;
+    let prunable = |k: &str| !keep.contains(&k) && (only.is_empty() || only.contains(&k));
+    for comp in components.iter_mut().filter_map(Value::as_object_mut) {
+        let props: Vec<String> = match comp.remove("generated_props") {
+            Some(Value::Array(props)) => props
+                .into_iter()
+                .filter_map(|p| p.as_str().map(str::to_owned))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut remaining = Vec::new();
+        for prop in props {
+            if prunable(&prop) {
+                comp.remove(&prop);
+            } else {
+                remaining.push(Value::from(prop));
+            }
+        }
+        if !remaining.is_empty() {
+            comp.insert("generated_props".to_owned(), Value::Array(remaining));
+        }
+        for field in GENERATED_FIELDS.iter().filter(|f| prunable(f)) {
+            comp.remove(*field);
+        }
+    }
+    write_manifest(&file, value)
+}
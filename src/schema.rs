@@ -0,0 +1,159 @@
+use serde_json::{json, Value};
+
+use crate::manifest::MANIFEST_VERSION;
+
+/// JSON Schema for components.json. Keep in step with `types::Component`.
+pub fn components_schema() -> Value {
+    let remote_dependency = json!({
+        "type": "object",
+        "required": ["git", "component"],
+        "properties": {
+            "git": { "type": "string", "description": "Repository URL" },
+            "ref": { "type": "string", "description": "Branch, tag or sha, defaults to HEAD" },
+            "component": { "type": "string" },
+        },
+    });
+    let component = json!({
+        "type": "object",
+        "required": ["dir"],
+        "properties": {
+            "dir": {
+                "type": "string",
+                "description": "Component directory, relative to components.json",
+            },
+            "dependencies": {
+                "type": "array",
+                "description": "Dirs of the components this one depends on",
+                "items": {
+                    "oneOf": [
+                        { "type": "string" },
+                        {
+                            "type": "object",
+                            "required": ["component"],
+                            "properties": { "component": { "type": "string" } },
+                        },
+                        remote_dependency.clone(),
+                    ],
+                },
+            },
+            "owners": string_list("Owning teams or users, as used in CODEOWNERS"),
+            "group": {
+                "type": "string",
+                "description": "Name of the groups entry whose defaults apply",
+            },
+            "dockerfile": {
+                "type": "string",
+                "description": "Dockerfile relative to components.json, defaults to <dir>/Dockerfile",
+            },
+            "image": { "type": "string", "description": "Image repository name" },
+            "extra_paths": {
+                "type": "array",
+                "items": { "type": "string" },
+                "description": "Files or dirs outside dir, relative to components.json, also covered by the hash",
+            },
+            "ignore": {
+                "type": "array",
+                "items": { "type": "string", "pattern": "^[^!]" },
+                "description": "Gitignore-style patterns, relative to dir, of paths left out of the hash; ! negation isn't supported",
+            },
+            "build_args": {
+                "type": "object",
+                "additionalProperties": { "type": "string" },
+            },
+            "target": { "type": "string", "description": "Dockerfile build stage" },
+            "pre_build": {
+                "type": "string",
+                "description": "Template run by build before the build command",
+            },
+            "post_build": {
+                "type": "string",
+                "description": "Template run by build after a successful build command",
+            },
+            "hash_override": {
+                "type": "string",
+                "pattern": "^[0-9a-f]{64}$",
+                "description": "tree_sha to use instead of the computed one",
+            },
+            "commit_sha": { "type": "string" },
+            "commit_sha_short": { "type": "string" },
+            "tree_sha": { "type": "string" },
+            "tree_sha_short": { "type": "string" },
+            "remote_dependencies": {
+                "type": "array",
+                "description": "Components in other repositories, hashed by the commit ref points to",
+                "items": remote_dependency,
+            },
+            "dirty": {
+                "type": "boolean",
+                "description": "Uncommitted changes went into tree_sha",
+            },
+            "generated_props": string_list("Props added by hash-components, removed by prune"),
+        },
+        "additionalProperties": true,
+    });
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "avocado-build-helper components.json",
+        "definitions": { "component": component },
+        "oneOf": [
+            {
+                "description": "Version 1: a bare list of components",
+                "type": "array",
+                "items": { "$ref": "#/definitions/component" },
+            },
+            {
+                "type": "object",
+                "required": ["version", "components"],
+                "properties": {
+                    "version": { "type": "integer", "minimum": 2, "maximum": MANIFEST_VERSION },
+                    "components": {
+                        "type": "array",
+                        "items": { "$ref": "#/definitions/component" },
+                    },
+                    "defaults": {
+                        "type": "object",
+                        "description": "Fields merged into every component",
+                    },
+                    "metadata_keys": {
+                        "type": "array",
+                        "items": { "type": "string" },
+                        "description": "Extra component keys allowed by --strict",
+                    },
+                    "always_exclude": string_list(
+                        "Patterns appended to every generated .dockerignore",
+                    ),
+                    "vars": {
+                        "type": "object",
+                        "description": "Values for ${VAR} substitution, overridden by the environment",
+                    },
+                    "groups": {
+                        "type": "object",
+                        "description": "Named defaults applied to components by their group field",
+                        "additionalProperties": { "type": "object" },
+                    },
+                    "hermetic": {
+                        "type": "object",
+                        "description": "Allowlists applied with --hermetic",
+                        "properties": {
+                            "commands": string_list("Programs commands may run"),
+                            "env": string_list("Environment variables passed to commands"),
+                        },
+                    },
+                },
+            },
+        ],
+    })
+}
+
+fn string_list(description: &str) -> Value {
+    json!({
+        "type": "array",
+        "items": { "type": "string" },
+        "description": description,
+    })
+}
+
+pub fn run_generate_schema() -> Result<(), anyhow::Error> {
+    println!("{}", serde_json::to_string_pretty(&components_schema())?);
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::affected::changed_components;
+use crate::graph::render_dot;
+use crate::manifest::LoadOptions;
+use crate::types::*;
+
+/// Connections handled at once; others wait for a free worker.
+const WORKERS: usize = 8;
+/// How long a client has to send its request, or to take the response.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Cloned for each request, so only reloading holds the lock.
+#[derive(Clone)]
+struct State {
+    path: PathBuf,
+    load: LoadOptions,
+    watch: bool,
+    loaded_at: Option<SystemTime>,
+    components: Arc<Vec<Component>>,
+}
+
+impl State {
+    fn manifest_mtime(&self) -> Option<SystemTime> {
+        std::fs::metadata(self.load.manifest_file(&self.path))
+            .and_then(|m| m.modified())
+            .ok()
+    }
+
+    /// Reload components.json if watching and it has changed since last load.
+    fn refresh(&mut self) {
+        if !self.watch {
+            return;
+        }
+        let mtime = self.manifest_mtime();
+        if mtime != self.loaded_at {
+            log::info!("components.json changed, reloading");
+            match self.load.load_components(&self.path) {
+                Ok(c) => self.components = Arc::new(c),
+                Err(e) => log::warn!("Keeping previous components: {}", e),
+            }
+            self.loaded_at = mtime;
+        }
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: String) -> Response {
+        Response {
+            status: "200 OK",
+            content_type,
+            body,
+        }
+    }
+
+    fn json<T: serde::Serialize>(value: &T) -> anyhow::Result<Response> {
+        Ok(Response::ok(
+            "application/json",
+            serde_json::to_string(value)?,
+        ))
+    }
+
+    fn error(status: &'static str, message: String) -> Response {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: message + "\n",
+        }
+    }
+}
+
+pub fn run_server(
+    path: &Path,
+    load: &LoadOptions,
+    listen: &str,
+    watch: bool,
+) -> Result<(), anyhow::Error> {
+    let mut state = State {
+        path: path.to_owned(),
+        load: load.clone(),
+        watch,
+        loaded_at: None,
+        components: Arc::new(load.load_components(path)?),
+    };
+    state.loaded_at = state.manifest_mtime();
+    let state = Mutex::new(state);
+    let listener = TcpListener::bind(listen)?;
+    log::info!("Listening on {}", listener.local_addr()?);
+    let (tx, rx) = mpsc::channel::<TcpStream>();
+    let rx = Mutex::new(rx);
+    std::thread::scope(|s| {
+        for _ in 0..WORKERS {
+            s.spawn(|| loop {
+                let stream = match rx.lock().unwrap().recv() {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                if let Err(e) = handle_connection(&state, stream) {
+                    log::warn!("Error handling request: {}", e);
+                }
+            });
+        }
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => tx.send(s).unwrap(),
+                Err(e) => log::warn!("Error accepting connection: {}", e),
+            }
+        }
+        drop(tx);
+    });
+    Ok(())
+}
+
+fn handle_connection(state: &Mutex<State>, mut stream: TcpStream) -> anyhow::Result<()> {
+    // a client that stalls mustn't hold a worker forever
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // drain headers; we never need them or a body for read-only endpoints
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let target = parts.next().unwrap_or("/");
+    log::debug!("{} {}", method, target);
+    let resp = if method != "GET" {
+        Response::error(
+            "405 Method Not Allowed",
+            format!("{} not supported", method),
+        )
+    } else {
+        let state = {
+            let mut state = state.lock().unwrap();
+            state.refresh();
+            state.clone()
+        };
+        route(&state, target)
+            .unwrap_or_else(|e| Response::error("500 Internal Server Error", e.to_string()))
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        resp.status,
+        resp.content_type,
+        resp.body.len(),
+        resp.body
+    )?;
+    Ok(())
+}
+
+fn route(state: &State, target: &str) -> anyhow::Result<Response> {
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target, ""),
+    };
+    let path = percent_decode(path);
+    let components = (*state.components).clone();
+    match path.as_str() {
+        "/components" => Response::json(&components),
+        "/toposort" => {
+            let dirs: Vec<String> = toposort_components(components)?
+                .into_iter()
+                .map(|c| c.dir)
+                .collect();
+            Response::json(&dirs)
+        }
+        "/graph.dot" => Ok(Response::ok(
+            "text/vnd.graphviz",
+            render_dot(&components, &HashSet::new(), &HashSet::new()),
+        )),
+        "/affected" => {
+            let since = match query_param(query, "since") {
+                Some(s) => s,
+                None => {
+                    return Ok(Response::error(
+                        "400 Bad Request",
+                        "missing since parameter".to_owned(),
+                    ))
+                }
+            };
+            let merge_base = query_param(query, "exact").is_none();
+            let changed = changed_components(&state.path, &state.load, &since, merge_base)?;
+            let changed: Vec<&str> = changed.iter().map(String::as_str).collect();
+            let dirs: Vec<String> = transitive_dependents(components, &changed, true)?
+                .into_iter()
+                .map(|c| c.dir)
+                .collect();
+            Response::json(&dirs)
+        }
+        p if p.starts_with("/dependents/") => {
+            let name = &p["/dependents/".len()..];
+            match transitive_dependents(components, &[name], false) {
+                Ok(deps) => Response::json(&deps.into_iter().map(|c| c.dir).collect::<Vec<_>>()),
+                Err(e @ CustomError::MissingComponentError(_)) => {
+                    Ok(Response::error("404 Not Found", e.to_string()))
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+        _ => Ok(Response::error(
+            "404 Not Found",
+            format!("no such endpoint {}", path),
+        )),
+    }
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|kv| {
+            let mut it = kv.splitn(2, '=');
+            Some((it.next()?, it.next().unwrap_or("")))
+        })
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| percent_decode(&v.replace('+', " ")))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
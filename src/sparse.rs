@@ -0,0 +1,41 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::manifest::LoadOptions;
+use crate::types::*;
+use crate::vcs::Vcs;
+
+pub fn run_sparse_paths(
+    path: &Path,
+    load: &LoadOptions,
+    components: &[&str],
+    shared_paths: &[&str],
+) -> Result<(), anyhow::Error> {
+    let vcs = Vcs::discover(path)?;
+    let deps = transitive_dependencies(load.load_components(path)?, components, true, false)?;
+    let dirs = deps
+        .iter()
+        .map(|c| c.dir.as_str())
+        .chain(shared_paths.iter().copied())
+        .map(|d| {
+            vcs.checkout_path(&path.join(d))
+                .map(|p| path_to_slash(&p).trim_end_matches('/').to_owned())
+        })
+        .collect::<Result<BTreeSet<String>, _>>()?;
+    for dir in collapse_nested(dirs) {
+        println!("{}", dir);
+    }
+    Ok(())
+}
+
+/// In cone mode a directory already brings in everything beneath it, so
+/// entries under another entry are redundant.
+fn collapse_nested(dirs: BTreeSet<String>) -> Vec<String> {
+    dirs.iter()
+        .filter(|dir| {
+            !dir.match_indices('/')
+                .any(|(i, _)| dirs.contains(&dir[..i]))
+        })
+        .cloned()
+        .collect()
+}
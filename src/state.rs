@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// What build remembers between runs, kept in the `--state` file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BuildState {
+    /// Seconds each component's last successful build took.
+    #[serde(default)]
+    pub durations: BTreeMap<String, f64>,
+}
+
+impl BuildState {
+    /// An empty state if `file` doesn't exist yet.
+    pub fn load(file: &Path) -> anyhow::Result<BuildState> {
+        match std::fs::read(file) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BuildState::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Atomically replace `file`, so an interrupted write can't lose history.
+    pub fn save(&self, file: &Path) -> anyhow::Result<()> {
+        let mut tmp = file.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)? + "\n")?;
+        std::fs::rename(&tmp, file)?;
+        Ok(())
+    }
+}
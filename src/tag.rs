@@ -0,0 +1,57 @@
+use handlebars::Handlebars;
+use std::path::Path;
+
+use crate::hasher::{hash_components, HashOptions};
+use crate::helpers::register_helpers;
+use crate::types::{check_components_exist, expand_patterns, CustomError};
+use crate::vcs::Vcs;
+
+pub fn run_tag_components(
+    path: &Path,
+    template: &str,
+    message: &str,
+    components: &[&str],
+    push: Option<&str>,
+    options: &HashOptions,
+) -> Result<(), anyhow::Error> {
+    let vcs = Vcs::discover(path)?;
+    let mut reg = Handlebars::new();
+    reg.register_escape_fn(handlebars::no_escape);
+    register_helpers(&mut reg);
+    for (name, tpl) in [("tag", template), ("message", message)].iter() {
+        reg.register_template_string(name, tpl)
+            .map_err(|e| CustomError::TemplateError {
+                prop_name: (*name).to_owned(),
+                error: Box::new(e),
+            })?;
+    }
+    let hashed = hash_components(path, options, |_| Ok(()))?;
+    let dirs = expand_patterns(&hashed, components)?;
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+    check_components_exist(&hashed, &dirs)?;
+    for comp in hashed
+        .iter()
+        .filter(|c| dirs.is_empty() || dirs.contains(&c.dir.as_str()))
+    {
+        let render = |name: &str| {
+            reg.render(name, comp)
+                .map_err(|e| CustomError::TemplateRenderError {
+                    cmd_name: name.to_owned(),
+                    error: Box::new(e),
+                })
+        };
+        let tag = render("tag")?;
+        let msg = render("message")?;
+        let dir = path.join(&comp.dir);
+        if vcs.create_tag(&dir, &tag, comp.commit_sha.as_ref().unwrap(), &msg)? {
+            log::info!("Created tag {} for {}", tag, comp.dir);
+        } else {
+            log::info!("Tag {} already exists, skipping", tag);
+        }
+        if let Some(remote) = push {
+            vcs.push_tag(&dir, remote, &tag)?;
+        }
+        println!("{}", tag);
+    }
+    Ok(())
+}
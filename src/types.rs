@@ -1,18 +1,29 @@
 use serde::{Deserialize, Serialize};
-use serde_json::{from_reader, Value};
-use std::collections::{BinaryHeap, HashSet};
-use std::fs::File;
+use serde_json::Value;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 use std::hash::Hash;
-use std::path::Path;
+use std::path::{Component as PathComponent, Path};
 use std::vec::Vec;
 use thiserror::Error;
 
+use crate::glob::glob_regex;
+use crate::manifest::load_manifest;
+use crate::metrics;
+
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum CustomError {
     #[error("Component spec issue: Missing transitive dependencies: {0:?}")]
     MissingDepError(Vec<String>),
     #[error("Component spec issue: Missing components named: {0:?}")]
     MissingComponentError(Vec<String>),
+    #[error("Component spec issue: No component named {name:?}{}", did_you_mean(.suggestions))]
+    UnknownComponentError {
+        name: String,
+        suggestions: Vec<String>,
+    },
+    #[error("Component spec issue: No components match {0:?}")]
+    UnmatchedPatternError(String),
     #[error("Component spec issue: Cycle found with or unfound dependencies for:\n {0}")]
     CycleError(String),
     #[error("Duplicate property name: {name}")]
@@ -20,10 +31,32 @@ pub enum CustomError {
     #[error("Error in template for property {prop_name}:\n{error}")]
     TemplateError {
         prop_name: String,
-        error: handlebars::TemplateError,
+        error: Box<handlebars::TemplateError>,
+    },
+    #[error("Error in script for property {prop_name}:\n{error}")]
+    ScriptError {
+        prop_name: String,
+        error: Box<rhai::ParseError>,
+    },
+    #[error("Error running script for property {prop_name}:\n{error}")]
+    ScriptRunError {
+        prop_name: String,
+        error: Box<rhai::EvalAltResult>,
     },
     #[error("Invalid argument format {argument}, requires an '='")]
     PropMissingEqualsError { argument: String },
+    #[error("Invalid exit codes in {argument}, expected a comma separated list of integers")]
+    InvalidExitCodesError { argument: String },
+    #[error("Build failed for {failed:?}, skipped their dependents {skipped:?}")]
+    BuildFailedError {
+        failed: Vec<String>,
+        skipped: Vec<String>,
+    },
+    #[error("Command failed in {failed:?}, skipped their dependents {skipped:?}")]
+    ExecFailedError {
+        failed: Vec<String>,
+        skipped: Vec<String>,
+    },
     #[error("Command {cmd:?} was not successful: {reason}")]
     UnsuccessfulCommandError { cmd: String, reason: String },
     #[error("Error parsing command {cmd:?}:\n{error}")]
@@ -34,8 +67,44 @@ pub enum CustomError {
     #[error("Error rendering template for {cmd_name}:\n{error}")]
     TemplateRenderError {
         cmd_name: String,
-        error: handlebars::RenderError,
+        error: Box<handlebars::RenderError>,
     },
+    #[error("components.json is version {found} but this tool only supports up to version {supported}, please upgrade")]
+    UnsupportedManifestVersion { found: u64, supported: u64 },
+    #[error("Unknown keys in components.json (list metadata in metadata_keys to allow them):\n {}", .0.join(",\n "))]
+    UnknownKeysError(Vec<String>),
+    #[error("Invalid component dirs:\n {}", .0.join(",\n "))]
+    InvalidDirError(Vec<String>),
+    #[error("Component dirs with the same slug:\n {}", .0.join(",\n "))]
+    SlugCollisionError(Vec<String>),
+    #[error("Hashing was not reproducible:\n {}", .0.join(",\n "))]
+    UnreproducibleError(Vec<String>),
+    #[error("Components defined in more than one manifest:\n {}", .0.join(",\n "))]
+    DuplicateComponentsError(Vec<String>),
+    #[error("{0:?} is locked by another process")]
+    LockedError(std::path::PathBuf),
+    #[error("Not allowed in hermetic mode: {0}")]
+    HermeticError(String),
+    #[error("Invalid workspace: {0}")]
+    WorkspaceError(String),
+    #[error("Invalid components.json: {reason}")]
+    ManifestFormatError { reason: String },
+    #[error("No manifest found at {0:?}")]
+    ManifestNotFoundError(std::path::PathBuf),
+    #[error("Error reading {path:?}: {error}")]
+    ManifestReadError {
+        path: std::path::PathBuf,
+        error: std::io::Error,
+    },
+    #[error("Error parsing {path:?} at line {line}{}: {reason}", column_suffix(.column))]
+    ManifestParseError {
+        path: std::path::PathBuf,
+        line: usize,
+        column: Option<usize>,
+        reason: String,
+    },
+    #[error("Hash override for {component} must be a 64 character hex sha256, got {value:?}")]
+    InvalidHashOverrideError { component: String, value: String },
     #[error("Error attempting to execute command for {cmd_name}:\n{error}")]
     CommandExecutionError {
         cmd_name: String,
@@ -48,6 +117,38 @@ pub struct Component {
     pub dir: String,
     #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
     pub dependencies: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub owners: Vec<String>,
+    /// Name of the manifest `groups` entry whose defaults apply.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Dockerfile relative to components.json, defaults to `<dir>/Dockerfile`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dockerfile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// Files or dirs outside `dir`, relative to components.json, that the
+    /// component is built from too, such as shared protos or a lockfile.
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub extra_paths: Vec<String>,
+    /// Gitignore-style patterns, relative to `dir`, of paths whose changes
+    /// don't affect the hash, such as docs or test fixtures. `!` negation
+    /// isn't supported.
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub ignore: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub build_args: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Templates the build subcommand runs around the build command.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_build: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_build: Option<String>,
+    /// Fixed tree_sha for components built elsewhere, whose dir content
+    /// doesn't identify the build.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash_override: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub commit_sha: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -56,14 +157,105 @@ pub struct Component {
     pub tree_sha: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tree_sha_short: Option<String>,
+    /// Components in other repositories, from `{"git": ...}` dependencies.
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub remote_dependencies: Vec<RemoteDependency>,
+    /// Set by hash-components `--include-dirty` when uncommitted changes
+    /// went into tree_sha.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dirty: Option<bool>,
+    /// Props added by hash-components, so prune can remove them again.
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub generated_props: Vec<String>,
     #[serde(flatten)]
     pub rem: Value,
 }
 
+/// A component in another repository, pinned to whatever `ref` points to
+/// there when hashing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteDependency {
+    pub git: String,
+    #[serde(rename = "ref", default = "default_ref")]
+    pub reference: String,
+    pub component: String,
+}
+
+fn default_ref() -> String {
+    "HEAD".to_owned()
+}
+
 impl Component {
+    /// Keys with a dedicated field; anything else is metadata kept in `rem`.
+    pub const FIELDS: &'static [&'static str] = &[
+        "dir",
+        "dependencies",
+        "owners",
+        "group",
+        "dockerfile",
+        "image",
+        "extra_paths",
+        "ignore",
+        "build_args",
+        "target",
+        "pre_build",
+        "post_build",
+        "hash_override",
+        "commit_sha",
+        "commit_sha_short",
+        "tree_sha",
+        "tree_sha_short",
+        "remote_dependencies",
+        "dirty",
+        "generated_props",
+    ];
+
     pub fn depset(&self) -> HashSet<String> {
         self.dependencies.iter().map(|v| v.to_owned()).collect()
     }
+    pub fn dockerfile_path(&self) -> String {
+        match &self.dockerfile {
+            Some(f) => f.clone(),
+            None => format!("{}/Dockerfile", self.dir.trim_end_matches('/')),
+        }
+    }
+
+    /// The `dockerfile` field, normalized, when it lives outside `dir` and so
+    /// wouldn't otherwise be covered by the component's hash.
+    pub fn external_dockerfile(&self) -> Option<String> {
+        let file = normalize_dir(&to_slash(self.dockerfile.as_ref()?));
+        let dir = normalize_dir(&self.dir);
+        if dir == "." || Path::new(&file).starts_with(&dir) {
+            None
+        } else {
+            Some(file)
+        }
+    }
+
+    /// `extra_paths` normalized, sorted and without those inside `dir`.
+    pub fn external_paths(&self) -> Vec<String> {
+        let dir = normalize_dir(&self.dir);
+        let mut res: Vec<String> = self
+            .extra_paths
+            .iter()
+            .map(|p| normalize_dir(&to_slash(p)))
+            .filter(|p| dir != "." && !Path::new(p).starts_with(&dir))
+            .collect();
+        res.sort();
+        res.dedup();
+        res
+    }
+
+    /// Image repository: the `image` field if set, otherwise a slug of the dir.
+    pub fn image_name(&self) -> String {
+        self.image.clone().unwrap_or_else(|| slug(&self.dir))
+    }
+
+    /// Whether any of `owners` owns this component; true when none are given.
+    pub fn is_owned_by(&self, owners: &[&str]) -> bool {
+        owners.is_empty() || self.owners.iter().any(|o| owners.contains(&o.as_str()))
+    }
+
     pub fn depsorted(&self) -> Vec<String> {
         self.dependencies
             .iter()
@@ -73,9 +265,188 @@ impl Component {
     }
 }
 
-pub fn load_components(path: &Path) -> Vec<Component> {
-    let f = File::open(path.join("components.json")).unwrap();
-    from_reader(f).unwrap()
+/// Forward slashes whichever way the manifest was written; git pathspecs,
+/// .dockerignore and CODEOWNERS patterns all want them.
+pub fn to_slash(s: &str) -> String {
+    s.replace('\\', "/")
+}
+
+/// Render a path with forward slashes on every platform.
+pub fn path_to_slash(path: &Path) -> String {
+    let parts: Vec<String> = path
+        .components()
+        .map(|c| match c {
+            PathComponent::RootDir => String::new(),
+            c => c.as_os_str().to_string_lossy().into_owned(),
+        })
+        .collect();
+    match parts.as_slice() {
+        [root] if root.is_empty() => "/".to_owned(),
+        _ => parts.join("/"),
+    }
+}
+
+/// `path_to_slash` keeping the raw bytes of names that aren't UTF-8, for
+/// hashing, where a lossy rendering would let distinct paths collide.
+#[cfg(unix)]
+pub fn path_to_slash_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    let parts: Vec<&[u8]> = path
+        .components()
+        .map(|c| match c {
+            PathComponent::RootDir => &b""[..],
+            c => c.as_os_str().as_bytes(),
+        })
+        .collect();
+    match parts.as_slice() {
+        [[]] => b"/".to_vec(),
+        _ => parts.join(&b'/'),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn path_to_slash_bytes(path: &Path) -> Vec<u8> {
+    path_to_slash(path).into_bytes()
+}
+
+/// Canonical spelling of a component dir: no `.` segments, repeated or
+/// trailing slashes.
+pub fn normalize_dir(dir: &str) -> String {
+    let parts: Vec<&str> = dir
+        .split('/')
+        .filter(|p| !p.is_empty() && *p != ".")
+        .collect();
+    if parts.is_empty() {
+        ".".to_owned()
+    } else {
+        parts.join("/")
+    }
+}
+
+/// Problems with a dir that can be spotted without touching the filesystem.
+pub fn dir_problem(dir: &str) -> Option<&'static str> {
+    if dir.is_empty() {
+        Some("is empty")
+    } else if dir.starts_with('/') || dir.chars().nth(1) == Some(':') {
+        Some("must be relative to components.json")
+    } else if dir.split('/').any(|p| p == "..") {
+        Some("must not contain '..'")
+    } else {
+        None
+    }
+}
+
+/// A name safe for derivations, image names and the like: `svc/api` -> `svc-api`.
+pub fn slug(dir: &str) -> String {
+    dir.trim_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+/// Error if two dirs share a slug, as `svc/api` and `svc-api` do, where
+/// the slug is used to name files or jobs that must be distinct.
+pub fn check_slugs<'a, I>(dirs: I) -> Result<(), CustomError>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    let mut problems = Vec::new();
+    for dir in dirs {
+        if let Some(other) = seen.insert(slug(dir), dir) {
+            problems.push(format!(
+                "{:?} and {:?} are both {:?}",
+                other,
+                dir,
+                slug(dir)
+            ));
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(CustomError::SlugCollisionError(problems))
+    }
+}
+
+pub fn load_components(path: &Path) -> Result<Vec<Component>, CustomError> {
+    Ok(load_manifest(path)?.components)
+}
+
+fn column_suffix(column: &Option<usize>) -> String {
+    column
+        .map(|c| format!(", column {}", c))
+        .unwrap_or_default()
+}
+
+fn did_you_mean(suggestions: &[String]) -> String {
+    match suggestions {
+        [] => String::new(),
+        [one] => format!(", did you mean {:?}?", one),
+        _ => format!(", did you mean one of {:?}?", suggestions),
+    }
+}
+
+/// Fail on the first of `dirs` that isn't a component, suggesting the dirs
+/// closest to it by edit distance over either the whole dir or its last
+/// path segment.
+pub fn check_components_exist(inp: &[Component], dirs: &[&str]) -> Result<(), CustomError> {
+    let name = match dirs.iter().find(|d| !inp.iter().any(|c| c.dir == **d)) {
+        Some(name) => *name,
+        None => return Ok(()),
+    };
+    let limit = std::cmp::max(2, name.chars().count() / 3);
+    let mut scored: Vec<(usize, &str)> = inp
+        .iter()
+        .map(|c| {
+            let last = c.dir.rsplit('/').next().unwrap_or(&c.dir);
+            let distance = std::cmp::min(
+                strsim::levenshtein(name, &c.dir),
+                strsim::levenshtein(name, last),
+            );
+            (distance, c.dir.as_str())
+        })
+        .filter(|(distance, _)| *distance <= limit)
+        .collect();
+    scored.sort();
+    Err(CustomError::UnknownComponentError {
+        name: name.to_owned(),
+        suggestions: scored
+            .iter()
+            .take(3)
+            .map(|(_, d)| (*d).to_owned())
+            .collect(),
+    })
+}
+
+/// Replace patterns using `*`, `**` or `?` with the dirs they match, in
+/// manifest order; other names are kept as given. A pattern matching
+/// nothing is an error.
+pub fn expand_patterns(inp: &[Component], patterns: &[&str]) -> Result<Vec<String>, CustomError> {
+    let mut res: Vec<String> = Vec::new();
+    for pattern in patterns {
+        if !pattern.contains(['*', '?']) {
+            if !res.iter().any(|d| d == pattern) {
+                res.push((*pattern).to_owned());
+            }
+            continue;
+        }
+        let re = glob_regex(&normalize_dir(pattern));
+        let matched: Vec<&str> = inp
+            .iter()
+            .map(|c| c.dir.as_str())
+            .filter(|d| re.is_match(&normalize_dir(d)))
+            .collect();
+        if matched.is_empty() {
+            return Err(CustomError::UnmatchedPatternError((*pattern).to_owned()));
+        }
+        for dir in matched {
+            if !res.iter().any(|d| d == dir) {
+                res.push(dir.to_owned());
+            }
+        }
+    }
+    Ok(res)
 }
 
 pub fn toposort_components(inp: Vec<Component>) -> Result<Vec<Component>, CustomError> {
@@ -150,7 +521,101 @@ pub fn transitive_dependents(
     Ok(result)
 }
 
+/// Group components into levels, each depending only on components in
+/// earlier levels. Dependencies on components not in `inp` are ignored, so
+/// this can be used on a subset of the graph.
+pub fn toposort_levels(inp: Vec<Component>) -> Result<Vec<Vec<Component>>, CustomError> {
+    let dirs: HashSet<String> = inp.iter().map(|c| c.dir.clone()).collect();
+    let sorted = toposort(
+        inp,
+        |a| a.dir.to_owned(),
+        |a| a.depset().intersection(&dirs).cloned().collect(),
+    )?;
+    let mut levels: Vec<Vec<Component>> = Vec::new();
+    let mut level_of: HashMap<String, usize> = HashMap::new();
+    for item in sorted {
+        let level = item
+            .dependencies
+            .iter()
+            .filter_map(|d| level_of.get(d))
+            .map(|l| l + 1)
+            .max()
+            .unwrap_or(0);
+        level_of.insert(item.dir.clone(), level);
+        if levels.len() <= level {
+            levels.push(Vec::new());
+        }
+        levels[level].push(item);
+    }
+    Ok(levels)
+}
+
+/// Map from each component dir to the dirs of all of its transitive dependencies.
+pub fn dependency_closures(
+    inp: &[Component],
+) -> Result<HashMap<String, HashSet<String>>, CustomError> {
+    let mut res: HashMap<String, HashSet<String>> = HashMap::new();
+    for item in toposort_components(inp.to_vec())? {
+        let mut closure = item.depset();
+        for dep in item.dependencies.iter() {
+            closure.extend(res[dep].iter().cloned());
+        }
+        res.insert(item.dir, closure);
+    }
+    Ok(res)
+}
+
+/// Map from each component dir to the dirs of all of its transitive dependents.
+pub fn dependent_closures(
+    inp: &[Component],
+) -> Result<HashMap<String, HashSet<String>>, CustomError> {
+    let mut res: HashMap<String, HashSet<String>> = inp
+        .iter()
+        .map(|c| (c.dir.clone(), HashSet::new()))
+        .collect();
+    for (dir, deps) in dependency_closures(inp)? {
+        for dep in deps {
+            res.get_mut(&dep).unwrap().insert(dir.clone());
+        }
+    }
+    Ok(res)
+}
+
+/// Components that every one of `dirs` transitively depends on, in
+/// topological order.
+pub fn common_dependencies(
+    inp: Vec<Component>,
+    dirs: &[&str],
+) -> Result<Vec<Component>, CustomError> {
+    let closures = dependency_closures(&inp)?;
+    let missing: Vec<String> = dirs
+        .iter()
+        .filter(|d| !closures.contains_key(**d))
+        .map(|d| (*d).to_owned())
+        .collect();
+    if !missing.is_empty() {
+        return Err(CustomError::MissingComponentError(missing));
+    }
+    let mut common = closures[dirs[0]].clone();
+    for dir in &dirs[1..] {
+        common.retain(|d| closures[*dir].contains(d));
+    }
+    Ok(toposort_components(inp)?
+        .into_iter()
+        .filter(|c| common.contains(&c.dir))
+        .collect())
+}
+
 fn toposort<A, K, F, G>(inp: Vec<A>, key: F, fdep: G) -> Result<Vec<A>, CustomError>
+where
+    K: Eq + Hash + std::fmt::Debug,
+    F: Fn(&A) -> K,
+    G: Fn(&A) -> HashSet<K>,
+{
+    metrics::global().time_phase("sort", || toposort_untimed(inp, key, fdep))
+}
+
+fn toposort_untimed<A, K, F, G>(inp: Vec<A>, key: F, fdep: G) -> Result<Vec<A>, CustomError>
 where
     K: Eq + Hash + std::fmt::Debug,
     F: Fn(&A) -> K,
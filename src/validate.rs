@@ -0,0 +1,51 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::Path;
+
+use crate::manifest::LoadOptions;
+use crate::types::*;
+
+pub fn run_validate(path: &Path, load: &LoadOptions) -> Result<(), anyhow::Error> {
+    let components = load.load_components(path)?;
+    let mut problems = Vec::new();
+    for comp in components.iter() {
+        let dir = path.join(&comp.dir);
+        if !dir.exists() {
+            problems.push(format!("{:?} does not exist", comp.dir));
+        } else if !dir.is_dir() {
+            problems.push(format!("{:?} is not a directory", comp.dir));
+        } else if !dir.canonicalize()?.starts_with(path) {
+            problems.push(format!("{:?} resolves outside of {:?}", comp.dir, path));
+        }
+    }
+    if !problems.is_empty() {
+        return Err(CustomError::InvalidDirError(problems).into());
+    }
+    toposort_components(components)?;
+    Ok(())
+}
+
+/// Print every dependency that doesn't name a component, followed by the
+/// components referring to it, then fail if there were any.
+pub fn run_validate_dangling(path: &Path, load: &LoadOptions) -> Result<(), anyhow::Error> {
+    let components = load.load_components(path)?;
+    let dirs: HashSet<&str> = components.iter().map(|c| c.dir.as_str()).collect();
+    let mut dangling: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for comp in components.iter() {
+        for dep in comp.dependencies.iter() {
+            if !dirs.contains(dep.as_str()) {
+                dangling.entry(dep).or_default().insert(&comp.dir);
+            }
+        }
+    }
+    for (dep, referrers) in dangling.iter() {
+        let referrers: Vec<&str> = referrers.iter().copied().collect();
+        println!("{}\t{}", dep, referrers.join(" "));
+    }
+    if !dangling.is_empty() {
+        return Err(CustomError::MissingDepError(
+            dangling.keys().map(|d| (*d).to_owned()).collect(),
+        )
+        .into());
+    }
+    Ok(())
+}
@@ -0,0 +1,621 @@
+use anyhow::{anyhow, Result};
+use git2::{Oid, Repository, Sort, Tree};
+use regex::Regex;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::from_utf8;
+
+use crate::glob::glob_regex;
+use crate::types::path_to_slash;
+
+/// The superproject plus any initialized submodules, so that paths can be
+/// resolved against whichever repository actually tracks them.
+///
+/// Discovery and the history hashing relies on go through libgit2, so
+/// hashing doesn't need a `git` binary; following renames, diffing against
+/// a ref, remote refs and tagging still run `git`.
+#[derive(Debug, Clone)]
+pub struct Vcs {
+    repos: Vec<Repo>,
+}
+
+impl Vcs {
+    pub fn discover(path: &Path) -> Result<Vcs> {
+        let top = Repo::discover(path)?;
+        let mut repos = vec![top.clone()];
+        let mut pending = vec![top];
+        while let Some(repo) = pending.pop() {
+            for sub in repo.submodules()? {
+                pending.push(sub.clone());
+                repos.push(sub);
+            }
+        }
+        // deepest roots first so that nested submodules win over their parents
+        repos.sort_by_key(|r| std::cmp::Reverse(r.root.components().count()));
+        Ok(Vcs { repos })
+    }
+
+    /// The superproject everything else lives within.
+    pub fn top(&self) -> &Repo {
+        self.repos.last().unwrap()
+    }
+
+    /// The innermost repository containing `path`.
+    pub fn repo_for(&self, path: &Path) -> &Repo {
+        self.repos
+            .iter()
+            .find(|r| path.starts_with(&r.root))
+            .unwrap_or_else(|| self.top())
+    }
+
+    /// The last commit touching `path`, other than through paths matching
+    /// the gitignore-style `ignore` patterns relative to it.
+    pub fn last_commit(
+        &self,
+        path: &Path,
+        ignore: &[String],
+        follow_renames: bool,
+    ) -> Result<String> {
+        let repo = self.repo_for(path);
+        if follow_renames {
+            repo.last_content_commit(path, ignore)
+        } else {
+            repo.last_commit(path, ignore)
+        }
+    }
+
+    /// The last commits of many `(path, ignore)` pairs at once, as
+    /// `last_commit` without renames would find them, walking the history
+    /// of each repository involved only once. Paths never committed get an
+    /// empty string.
+    pub fn last_commits(&self, paths: &[(PathBuf, Vec<String>)]) -> Result<Vec<String>> {
+        let mut res = vec![String::new(); paths.len()];
+        let mut by_repo: Vec<(&Repo, Vec<usize>)> = Vec::new();
+        for (i, (path, _)) in paths.iter().enumerate() {
+            let repo = self.repo_for(path);
+            match by_repo.iter_mut().find(|(r, _)| r.root == repo.root) {
+                Some((_, idx)) => idx.push(i),
+                None => by_repo.push((repo, vec![i])),
+            }
+        }
+        for (repo, idx) in by_repo {
+            let queries: Vec<(&Path, &[String])> = idx
+                .iter()
+                .map(|i| (paths[*i].0.as_path(), paths[*i].1.as_slice()))
+                .collect();
+            for (i, commit) in idx.into_iter().zip(repo.last_commits(&queries)?) {
+                res[i] = commit;
+            }
+        }
+        Ok(res)
+    }
+
+    /// Whichever of `commits`, all in the repository containing `path`, was
+    /// committed last.
+    pub fn newest_commit(&self, path: &Path, commits: &[&str]) -> Result<String> {
+        let repo = self.repo_for(path).open()?;
+        let mut newest: Option<(i64, &str)> = None;
+        for commit in commits {
+            let time = repo.find_commit(Oid::from_str(commit)?)?.time().seconds();
+            if newest.is_none_or(|(t, _)| time > t) {
+                newest = Some((time, commit));
+            }
+        }
+        Ok(newest.map(|(_, c)| c.to_owned()).unwrap_or_default())
+    }
+
+    /// Absolute paths changed between `since` (or its merge-base with
+    /// `head` when `merge_base` is set) and `head`. A changed submodule
+    /// shows up as its root path.
+    pub fn changed_paths(&self, since: &str, head: &str, merge_base: bool) -> Result<Vec<PathBuf>> {
+        let top = self.top();
+        let since = top.resolve_commit(since)?;
+        let head = &top.resolve_commit(head)?;
+        let base = if merge_base {
+            run_git(&top.root, &["merge-base", &since, head])?
+        } else {
+            since
+        };
+        log::debug!("Computing changes from {} to {}", base, head);
+        let out = run_git_bytes(&top.root, &["diff", "-z", "--name-only", &base, head])?;
+        Ok(split_nul(&out)
+            .map(|p| top.root.join(bytes_to_path(p)))
+            .collect())
+    }
+
+    /// Absolute paths with uncommitted changes, untracked files included,
+    /// across the superproject and its submodules.
+    pub fn dirty_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut res = Vec::new();
+        for repo in self.repos.iter() {
+            let git = repo.open()?;
+            let mut opts = git2::StatusOptions::new();
+            opts.include_untracked(true)
+                .recurse_untracked_dirs(true)
+                .renames_head_to_index(true);
+            for entry in git.statuses(Some(&mut opts))?.iter() {
+                res.push(repo.root.join(bytes_to_path(entry.path_bytes())));
+                // renames also dirty the path they came from
+                let deltas = entry
+                    .head_to_index()
+                    .into_iter()
+                    .chain(entry.index_to_workdir());
+                for delta in deltas {
+                    if let Some(old) = delta.old_file().path_bytes() {
+                        res.push(repo.root.join(bytes_to_path(old)));
+                    }
+                }
+            }
+        }
+        res.sort();
+        res.dedup();
+        Ok(res)
+    }
+
+    /// Create an annotated tag on `commit` in the repository tracking `path`.
+    /// Returns false without touching anything if the tag already exists.
+    pub fn create_tag(&self, path: &Path, name: &str, commit: &str, message: &str) -> Result<bool> {
+        let repo = self.repo_for(path);
+        let existing = Command::new("git")
+            .arg("-C")
+            .arg(&repo.root)
+            .args(["rev-parse", "-q", "--verify"])
+            .arg(format!("refs/tags/{}", name))
+            .output()?;
+        if existing.status.success() {
+            return Ok(false);
+        }
+        run_git(&repo.root, &["tag", "-a", "-m", message, name, commit])?;
+        Ok(true)
+    }
+
+    pub fn push_tag(&self, path: &Path, remote: &str, name: &str) -> Result<()> {
+        let repo = self.repo_for(path);
+        run_git(
+            &repo.root,
+            &["push", remote, &format!("refs/tags/{}", name)],
+        )?;
+        Ok(())
+    }
+
+    /// URL of `remote` for the repository tracking `path`, if configured.
+    pub fn remote_url(&self, path: &Path, remote: &str) -> Option<String> {
+        let repo = self.repo_for(path);
+        run_git(&repo.root, &["remote", "get-url", remote]).ok()
+    }
+
+    /// Commit `reference` currently points to in the repository at `url`,
+    /// with annotated tags peeled. `reference` is `HEAD`, a full ref name,
+    /// or a branch or tag name, which must not be both. A full sha is taken
+    /// as it is.
+    pub fn remote_commit(&self, url: &str, reference: &str) -> Result<String> {
+        if reference.len() == 40 && reference.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(reference.to_lowercase());
+        }
+        if url.starts_with('-') || reference.starts_with('-') {
+            anyhow::bail!("invalid remote {:?} or ref {:?}", url, reference);
+        }
+        log::debug!("Resolving {} in {}", reference, url);
+        // ls-remote patterns match any ref ending in them, so ask for
+        // full names and keep only exact matches
+        let names: Vec<String> = if reference == "HEAD" || reference.starts_with("refs/") {
+            vec![reference.to_owned()]
+        } else {
+            vec![
+                format!("refs/heads/{}", reference),
+                format!("refs/tags/{}", reference),
+            ]
+        };
+        let mut args = vec!["ls-remote".to_owned(), "--".to_owned(), url.to_owned()];
+        for name in names.iter() {
+            args.push(name.clone());
+            args.push(format!("{}^{{}}", name));
+        }
+        let out = run_git(&self.top().root, &args)?;
+        let refs: Vec<(&str, &str)> = out
+            .lines()
+            .filter_map(|l| l.split_once('\t'))
+            .filter(|(_, name)| names.iter().any(|n| name.trim_end_matches("^{}") == n))
+            .collect();
+        let matched: Vec<&str> = names
+            .iter()
+            .map(String::as_str)
+            .filter(|n| refs.iter().any(|(_, name)| name == n))
+            .collect();
+        if matched.len() > 1 {
+            anyhow::bail!("{} is ambiguous in {}: {:?}", reference, url, matched);
+        }
+        refs.iter()
+            .find(|(_, name)| name.ends_with("^{}"))
+            .or_else(|| refs.first())
+            .map(|(sha, _)| (*sha).to_owned())
+            .ok_or_else(|| anyhow!("{} not found in {}", reference, url))
+    }
+
+    /// Path relative to the superproject that must be checked out for `path`
+    /// to be present; paths inside a submodule need the whole submodule.
+    pub fn checkout_path(&self, path: &Path) -> Result<PathBuf> {
+        let repo = self.repo_for(path);
+        self.top().relative_path(if repo.root == self.top().root {
+            path
+        } else {
+            &repo.root
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Repo {
+    pub root: PathBuf,
+}
+
+impl Repo {
+    /// Find the repository (or linked worktree) containing `path`.
+    pub fn discover(path: &Path) -> Result<Repo> {
+        let git = Repository::discover(path)?;
+        let root = git
+            .workdir()
+            .ok_or_else(|| anyhow!("git repository at {:?} has no work tree", git.path()))?
+            .canonicalize()?;
+        log::debug!("Discovered git root {:?} for {:?}", root, path);
+        Ok(Repo { root })
+    }
+
+    fn open(&self) -> Result<Repository> {
+        Ok(Repository::open(&self.root)?)
+    }
+
+    /// Initialized submodules directly within this repository.
+    fn submodules(&self) -> Result<Vec<Repo>> {
+        let index = self.open()?.index()?;
+        let mut res = Vec::new();
+        for entry in index.iter() {
+            // gitlinks, whatever .gitmodules says
+            if entry.mode != 0o160000 {
+                continue;
+            }
+            let sub_path = self.root.join(bytes_to_path(&entry.path));
+            if !sub_path.join(".git").exists() {
+                log::warn!(
+                    "Submodule {:?} is not initialized, resolving against superproject",
+                    sub_path
+                );
+                continue;
+            }
+            let root = sub_path.canonicalize()?;
+            log::debug!("Found submodule at {:?}", root);
+            res.push(Repo { root });
+        }
+        Ok(res)
+    }
+
+    /// Translate an absolute path into one relative to the repository root.
+    pub fn relative_path(&self, path: &Path) -> Result<PathBuf> {
+        let rel = path.strip_prefix(&self.root).map_err(|_| {
+            anyhow!(
+                "path {:?} is outside of the git repository at {:?}",
+                path,
+                self.root
+            )
+        })?;
+        if rel.as_os_str().is_empty() {
+            Ok(PathBuf::from("."))
+        } else {
+            Ok(rel.to_owned())
+        }
+    }
+
+    /// The commit sha `reference` names. It comes from the command line or
+    /// a request, so it's never passed on to git as anything but a sha.
+    pub fn resolve_commit(&self, reference: &str) -> Result<String> {
+        if reference.starts_with('-') {
+            anyhow::bail!("invalid ref {:?}", reference);
+        }
+        let spec = format!("{}^{{commit}}", reference);
+        run_git(
+            &self.root,
+            &["rev-parse", "--verify", "--end-of-options", &spec],
+        )
+        .map_err(|e| anyhow!("unknown ref {:?}: {}", reference, e))
+    }
+
+    pub fn last_commit(&self, path: &Path, ignore: &[String]) -> Result<String> {
+        Ok(self.last_commits(&[(path, ignore)])?.remove(0))
+    }
+
+    /// What `git log -1 -- <path> <excludes>` gives for each query, from one
+    /// revwalk. Like git's default history simplification, a query only
+    /// follows a merge through a parent it's unchanged from, preferring the
+    /// first, and stops at the first commit it differs from every parent of.
+    fn last_commits(&self, queries: &[(&Path, &[String])]) -> Result<Vec<String>> {
+        let git = self.open()?;
+        let mut res = vec![String::new(); queries.len()];
+        let head = match git.head() {
+            Ok(head) => head.peel_to_commit()?.id(),
+            Err(e) if e.code() == git2::ErrorCode::UnbornBranch => return Ok(res),
+            Err(e) => return Err(e.into()),
+        };
+        let mut targets = Vec::new();
+        for (path, ignore) in queries {
+            targets.push((self.relative_path(path)?, ignore_regexes(ignore)));
+        }
+        // the commit each unresolved query is waiting for
+        let mut waiting: HashMap<Oid, Vec<usize>> = HashMap::new();
+        waiting.insert(head, (0..queries.len()).collect());
+        let mut walk = git.revwalk()?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+        walk.push(head)?;
+        for oid in walk {
+            if waiting.is_empty() {
+                break;
+            }
+            let oid = oid?;
+            let idx = match waiting.remove(&oid) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let commit = git.find_commit(oid)?;
+            let tree = commit.tree()?;
+            let parents = commit
+                .parents()
+                .map(|p| p.tree())
+                .collect::<Result<Vec<_>, _>>()?;
+            for i in idx {
+                let (rel, ignore) = &targets[i];
+                let mut same = None;
+                for (n, parent) in parents.iter().enumerate() {
+                    if unchanged(&git, &tree, Some(parent), rel, ignore)? {
+                        same = Some(n);
+                        break;
+                    }
+                }
+                match same {
+                    Some(n) => waiting.entry(commit.parent_id(n)?).or_default().push(i),
+                    // a root commit only counts if it adds the path
+                    None if parents.is_empty() && unchanged(&git, &tree, None, rel, ignore)? => {}
+                    None => res[i] = oid.to_string(),
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Like `last_commit`, but looks through commits that only moved files
+    /// into `path` (e.g. `git mv`) to the commit that last changed content.
+    pub fn last_content_commit(&self, path: &Path, ignore: &[String]) -> Result<String> {
+        let rel = self.relative_path(path)?;
+        let mut pathspecs = vec![rel.clone()];
+        let mut rev = "HEAD".to_owned();
+        loop {
+            let mut args = git_args(&["log", "-1", "--pretty=format:%H", &rev, "--"]);
+            args.extend(pathspecs.iter().map(|p| pathspec(p)));
+            args.extend(exclude_pathspecs(&rel, ignore));
+            let commit = run_git(&self.root, &args)?;
+            if commit.is_empty() {
+                anyhow::bail!("no commits found for path {:?}", rel);
+            }
+            let is_root = run_git(&self.root, &["rev-list", "--parents", "-1", &commit])?
+                .split(' ')
+                .count()
+                == 1;
+            if is_root {
+                return Ok(commit);
+            }
+            match pure_renames(&self.root, &commit, &pathspecs)? {
+                Some(sources) => {
+                    log::debug!(
+                        "Commit {} only renames into {:?}, following {:?}",
+                        commit,
+                        rel,
+                        sources
+                    );
+                    pathspecs.extend(sources);
+                    rev = format!("{}^", commit);
+                }
+                None => return Ok(commit),
+            }
+        }
+    }
+}
+
+/// Sources of the renames in `commit` if every change it makes under
+/// `pathspecs` is a pure (100% similarity) rename, otherwise `None`.
+fn pure_renames(root: &Path, commit: &str, pathspecs: &[PathBuf]) -> Result<Option<Vec<PathBuf>>> {
+    let parent = format!("{}^", commit);
+    let out = run_git_bytes(
+        root,
+        &[
+            "diff-tree",
+            "-z",
+            "-r",
+            "-M",
+            "--no-commit-id",
+            "--name-status",
+            &parent,
+            commit,
+        ],
+    )?;
+    let touches = |p: &Path| {
+        pathspecs
+            .iter()
+            .any(|s| s.as_os_str() == "." || p.starts_with(s))
+    };
+    let mut sources = Vec::new();
+    let mut fields = split_nul(&out);
+    while let Some(status) = fields.next() {
+        // renames and copies are followed by two paths, everything else one
+        let first = bytes_to_path(fields.next().unwrap_or_default());
+        let second = if status.starts_with(b"R") || status.starts_with(b"C") {
+            Some(bytes_to_path(fields.next().unwrap_or_default()))
+        } else {
+            None
+        };
+        match second {
+            Some(to) if status == b"R100" && touches(&to) => sources.push(first),
+            Some(to) if touches(&first) || touches(&to) => return Ok(None),
+            None if touches(&first) => return Ok(None),
+            _ => (),
+        }
+    }
+    Ok(if sources.is_empty() {
+        None
+    } else {
+        Some(sources)
+    })
+}
+
+/// Whether `rel` is the same in `new` as in `old` (or absent from both),
+/// ignoring changes beneath it to paths the `ignore` regexes match.
+fn unchanged(
+    git: &Repository,
+    new: &Tree,
+    old: Option<&Tree>,
+    rel: &Path,
+    ignore: &[Regex],
+) -> Result<bool> {
+    let id = |tree: &Tree| {
+        if rel.as_os_str() == "." {
+            Some(tree.id())
+        } else {
+            tree.get_path(rel).ok().map(|e| e.id())
+        }
+    };
+    let (new_id, old_id) = (id(new), old.and_then(id));
+    if new_id == old_id {
+        return Ok(true);
+    }
+    if ignore.is_empty() {
+        return Ok(false);
+    }
+    let subtree = |id: Option<Oid>| id.map(|id| git.find_tree(id).ok());
+    let (new_tree, old_tree) = match (subtree(new_id), subtree(old_id)) {
+        // a file changed, which nothing below it can explain
+        (Some(None), _) | (_, Some(None)) => return Ok(false),
+        (new_tree, old_tree) => (new_tree.flatten(), old_tree.flatten()),
+    };
+    let diff = git.diff_tree_to_tree(old_tree.as_ref(), new_tree.as_ref(), None)?;
+    let ignored = |p: Option<&Path>| {
+        p.is_none_or(|p| {
+            let p = path_to_slash(p);
+            ignore.iter().any(|re| re.is_match(&p))
+        })
+    };
+    Ok(diff
+        .deltas()
+        .all(|d| ignored(d.old_file().path()) && ignored(d.new_file().path())))
+}
+
+/// Regexes over paths relative to a component matching what
+/// `exclude_pathspecs` leaves out for the same patterns.
+fn ignore_regexes(patterns: &[String]) -> Vec<Regex> {
+    let mut res = Vec::new();
+    for pattern in patterns {
+        let dir_only = pattern.ends_with('/');
+        let p = pattern.trim_end_matches('/');
+        let anchored = p.contains('/');
+        let p = p.trim_start_matches('/');
+        let spec = if anchored {
+            p.to_owned()
+        } else {
+            format!("**/{}", p)
+        };
+        if !dir_only {
+            res.push(glob_regex(&spec));
+        }
+        res.push(glob_regex(&format!("{}/**", spec)));
+    }
+    res
+}
+
+fn git_args(args: &[&str]) -> Vec<OsString> {
+    args.iter().map(OsString::from).collect()
+}
+
+/// A repository-relative path as git expects it on the command line.
+fn pathspec(rel: &Path) -> OsString {
+    if cfg!(windows) {
+        OsString::from(path_to_slash(rel))
+    } else {
+        rel.as_os_str().to_owned()
+    }
+}
+
+/// Pathspecs leaving out what gitignore-style `patterns` match below `rel`:
+/// patterns without a `/` match a name at any depth, and a match also
+/// covers everything beneath it.
+fn exclude_pathspecs(rel: &Path, patterns: &[String]) -> Vec<OsString> {
+    let base = path_to_slash(rel);
+    let prefix = if base.is_empty() || base == "." {
+        String::new()
+    } else {
+        format!("{}/", base)
+    };
+    let mut res = Vec::new();
+    for pattern in patterns {
+        let dir_only = pattern.ends_with('/');
+        let p = pattern.trim_end_matches('/');
+        let anchored = p.contains('/');
+        let p = p.trim_start_matches('/');
+        let spec = if anchored {
+            format!("{}{}", prefix, p)
+        } else {
+            format!("{}**/{}", prefix, p)
+        };
+        if !dir_only {
+            res.push(OsString::from(format!(":(exclude,glob){}", spec)));
+        }
+        res.push(OsString::from(format!(":(exclude,glob){}/**", spec)));
+    }
+    res
+}
+
+/// Where git keeps `name` for the repository containing `dir`, as with
+/// `git rev-parse --git-path`, or None outside a repository.
+pub fn git_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    let out = run_git_bytes(dir, &["rev-parse", "--git-path", name]).ok()?;
+    Some(dir.join(bytes_to_path(out.strip_suffix(b"\n").unwrap_or(&out))))
+}
+
+/// Paths from git are raw bytes; only lossily convert where the platform
+/// can't represent them.
+#[cfg(unix)]
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn split_nul(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bytes.split(|b| *b == 0).filter(|p| !p.is_empty())
+}
+
+fn run_git<S: AsRef<OsStr>>(dir: &Path, args: &[S]) -> Result<String> {
+    Ok(String::from(from_utf8(&run_git_bytes(dir, args)?)?.trim()))
+}
+
+fn run_git_bytes<S: AsRef<OsStr>>(dir: &Path, args: &[S]) -> Result<Vec<u8>> {
+    let out = match Command::new("git").arg("-C").arg(dir).args(args).output() {
+        Ok(out) => out,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(anyhow!("git not found on PATH")),
+        Err(e) => return Err(e.into()),
+    };
+    if out.status.success() {
+        Ok(out.stdout)
+    } else {
+        match out.status.code() {
+            Some(c) => Err(anyhow!(
+                "git command exited with error code: {}\n{}",
+                c,
+                String::from_utf8_lossy(&out.stderr)
+            )),
+            None => Err(anyhow!("git command exited with signal")),
+        }
+    }
+}
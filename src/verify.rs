@@ -0,0 +1,71 @@
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::hasher::{hash_components, HashOptions};
+use crate::types::*;
+
+pub const BACKENDS: &[&str] = &["git", "content", "both"];
+
+/// Hash everything twice with each selected backend and fail listing every
+/// component field that came out differently, e.g. from a prop command or
+/// template that depends on time or the environment.
+pub fn run_verify_reproducibility<F>(
+    path: &Path,
+    backend: &str,
+    options: &HashOptions,
+    post_process: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(&mut Component) -> anyhow::Result<()> + Sync,
+{
+    let content_modes: &[bool] = match backend {
+        "git" => &[false],
+        "content" => &[true],
+        "both" => &[false, true],
+        _ => panic!("unknown backend {}", backend),
+    };
+    let mut problems = Vec::new();
+    for content_hash in content_modes.iter().copied() {
+        let options = HashOptions {
+            content_hash,
+            ..options.clone()
+        };
+        let name = if content_hash { "content" } else { "git" };
+        log::debug!("Hashing twice with the {} backend", name);
+        let first = hash_components(path, &options, &post_process)?;
+        let second = hash_components(path, &options, &post_process)?;
+        for (a, b) in first.iter().zip(second.iter()) {
+            for (key, x, y) in differences(&serde_json::to_value(a)?, &serde_json::to_value(b)?) {
+                problems.push(format!(
+                    "{} ({}): {} was {} then {}",
+                    a.dir, name, key, x, y
+                ));
+            }
+        }
+    }
+    if !problems.is_empty() {
+        return Err(CustomError::UnreproducibleError(problems).into());
+    }
+    Ok(())
+}
+
+fn differences(a: &Value, b: &Value) -> Vec<(String, Value, Value)> {
+    let keys: BTreeSet<&String> = a
+        .as_object()
+        .into_iter()
+        .chain(b.as_object())
+        .flat_map(|m| m.keys())
+        .collect();
+    keys.into_iter()
+        .filter_map(|k| {
+            let x = a.get(k).cloned().unwrap_or(Value::Null);
+            let y = b.get(k).cloned().unwrap_or(Value::Null);
+            if x == y {
+                None
+            } else {
+                Some((k.clone(), x, y))
+            }
+        })
+        .collect()
+}
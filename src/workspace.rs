@@ -0,0 +1,101 @@
+use serde::Deserialize;
+use serde_json::from_str;
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use crate::affected::changed_components;
+use crate::manifest::{strip_jsonc, LoadOptions};
+use crate::types::*;
+
+/// A workspace file naming several component roots, each a dir with its own
+/// components.json and usually its own git repository, relative to the file:
+///
+/// `{"roots": {"core": "core", "apps": "../apps"}}`
+///
+/// Components are then addressed as `root:dir`. Dependencies without a
+/// root refer to the component's own root.
+#[derive(Debug, Deserialize)]
+struct WorkspaceFile {
+    roots: BTreeMap<String, PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    /// Absolute paths of the roots, by name.
+    pub roots: BTreeMap<String, PathBuf>,
+}
+
+impl Workspace {
+    pub fn load(file: &Path) -> anyhow::Result<Workspace> {
+        let text = read_to_string(file).map_err(|e| anyhow::anyhow!("{:?}: {}", file, e))?;
+        let raw: WorkspaceFile = from_str(&strip_jsonc(&text))
+            .map_err(|e| CustomError::WorkspaceError(format!("{:?}: {}", file, e)))?;
+        let base = file.parent().unwrap_or_else(|| Path::new("."));
+        let mut roots = BTreeMap::new();
+        for (name, dir) in raw.roots {
+            if name.is_empty() || name.contains([':', '/']) {
+                return Err(
+                    CustomError::WorkspaceError(format!("invalid root name {:?}", name)).into(),
+                );
+            }
+            let path = base.join(&dir).canonicalize().map_err(|e| {
+                CustomError::WorkspaceError(format!("root {} at {:?}: {}", name, dir, e))
+            })?;
+            roots.insert(name, path);
+        }
+        if roots.is_empty() {
+            return Err(CustomError::WorkspaceError(format!("{:?} lists no roots", file)).into());
+        }
+        Ok(Workspace { roots })
+    }
+
+    /// Every root's components with dirs, dependencies, dockerfiles and
+    /// extra paths qualified by their root.
+    pub fn load_components(&self, load: &LoadOptions) -> Result<Vec<Component>, CustomError> {
+        let mut res = Vec::new();
+        for (name, path) in self.roots.iter() {
+            for mut comp in load.load_components(path)? {
+                comp.dir = qualify(name, &comp.dir);
+                comp.dependencies = comp.dependencies.iter().map(|d| qualify(name, d)).collect();
+                comp.dockerfile = comp.dockerfile.map(|f| qualify(name, &f));
+                comp.extra_paths = comp.extra_paths.iter().map(|p| qualify(name, p)).collect();
+                res.push(comp);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Qualified dirs of components changed since `since` in any root.
+    pub fn changed_components(
+        &self,
+        load: &LoadOptions,
+        since: &str,
+        merge_base: bool,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut res = Vec::new();
+        for (name, path) in self.roots.iter() {
+            res.extend(
+                changed_components(path, load, since, merge_base)?
+                    .iter()
+                    .map(|d| qualify(name, d)),
+            );
+        }
+        Ok(res)
+    }
+}
+
+/// `dir` addressed from within `root`, unless it already names a root.
+fn qualify(root: &str, dir: &str) -> String {
+    if dir.contains(':') {
+        dir.to_owned()
+    } else {
+        format!("{}:{}", root, dir)
+    }
+}
+
+/// The root an address names, empty outside a workspace, and the path
+/// within it.
+pub fn split_root(address: &str) -> (&str, &str) {
+    address.split_once(':').unwrap_or(("", address))
+}
@@ -0,0 +1,518 @@
+use serde_json::{Map, Number, Value};
+
+/// Parse the YAML a hand-written manifest uses: block mappings and
+/// sequences, flow `[...]` and `{...}` collections, plain, quoted and
+/// `|`/`>` block scalars, and comments. Anchors, tags and multiple
+/// documents aren't supported.
+pub fn parse(text: &str) -> Result<Value, String> {
+    let lines = logical_lines(text)?;
+    if lines.is_empty() {
+        return Ok(Value::Null);
+    }
+    let mut i = 0;
+    let value = parse_block(&lines, &mut i, lines[0].indent)?;
+    match lines.get(i) {
+        Some(line) => Err(format!("line {}: unexpected indentation", line.number)),
+        None => Ok(value),
+    }
+}
+
+/// Render `value` as block-style YAML that `parse` reads back unchanged.
+pub fn to_string(value: &Value) -> String {
+    let mut out = String::new();
+    match value {
+        Value::Object(m) if !m.is_empty() => write_mapping(&mut out, m, 0),
+        Value::Array(a) if !a.is_empty() => write_sequence(&mut out, a, 0),
+        v => {
+            out.push_str(&scalar(v));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone)]
+struct Line {
+    number: usize,
+    indent: usize,
+    text: String,
+}
+
+/// Whether `text` has comments, which `to_string` can't write back.
+pub fn has_comments(text: &str) -> bool {
+    scan_lines(text).is_ok_and(|(_, comments)| comments)
+}
+
+fn logical_lines(text: &str) -> Result<Vec<Line>, String> {
+    scan_lines(text).map(|(lines, _)| lines)
+}
+
+/// Non-empty lines without comments, flow collections spanning several
+/// lines joined into one, and whether any comments were dropped. Block
+/// scalars keep their raw lines.
+fn scan_lines(text: &str) -> Result<(Vec<Line>, bool), String> {
+    let mut res: Vec<Line> = Vec::new();
+    let mut comments = false;
+    let mut raw = text.lines().enumerate();
+    let mut block_indent: Option<usize> = None;
+    while let Some((n, line)) = raw.next() {
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        if line[indent..].starts_with('\t') {
+            return Err(format!("line {}: tabs can't indent YAML", n + 1));
+        }
+        if let Some(parent) = block_indent {
+            if line.trim().is_empty() || indent > parent {
+                res.push(Line {
+                    number: n + 1,
+                    indent,
+                    text: line.to_owned(),
+                });
+                continue;
+            }
+            block_indent = None;
+        }
+        let uncommented = strip_comment(&line[indent..]);
+        comments |= uncommented.len() < line.len() - indent;
+        let mut content = uncommented.trim_end().to_owned();
+        if content.is_empty() || content == "---" || content == "..." {
+            continue;
+        }
+        while flow_depth(&content) > 0 {
+            match raw.next() {
+                Some((_, next)) => {
+                    let uncommented = strip_comment(next);
+                    comments |= uncommented.len() < next.len();
+                    content.push(' ');
+                    content.push_str(uncommented.trim());
+                }
+                None => return Err(format!("line {}: unclosed flow collection", n + 1)),
+            }
+        }
+        // `- key: value` and `- - value` open a collection indented to
+        // where the item's content starts
+        let mut indent = indent;
+        while is_sequence_item(&content) {
+            let rest = content[1..].trim_start();
+            let nested = is_sequence_item(rest)
+                || (!rest.starts_with(['[', '{']) && split_key(rest).is_some());
+            if !nested {
+                break;
+            }
+            let offset = content.len() - rest.len();
+            res.push(Line {
+                number: n + 1,
+                indent,
+                text: "-".to_owned(),
+            });
+            indent += offset;
+            content = rest.to_owned();
+        }
+        if ["|", "|-", "|+", ">", ">-", ">+"].iter().any(|b| {
+            content
+                .strip_suffix(b)
+                .is_some_and(|head| head.ends_with([' ', ':', '-']) || head.is_empty())
+        }) {
+            block_indent = Some(indent);
+        }
+        res.push(Line {
+            number: n + 1,
+            indent,
+            text: content,
+        });
+    }
+    Ok((res, comments))
+}
+
+/// `text` up to a `#` starting a comment, outside quotes.
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut prev = ' ';
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' && prev.is_whitespace() => return &text[..i],
+            None => (),
+        }
+        prev = c;
+    }
+    text
+}
+
+fn flow_depth(text: &str) -> i32 {
+    let text = text.trim_start_matches("- ");
+    let value = match split_key(text) {
+        Some((_, v)) => v,
+        None => text,
+    };
+    if !value.starts_with(['[', '{']) {
+        return 0;
+    }
+    let mut depth = 0;
+    let mut quote = None;
+    for c in value.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '[' || c == '{' => depth += 1,
+            None if c == ']' || c == '}' => depth -= 1,
+            None => (),
+        }
+    }
+    depth
+}
+
+fn is_sequence_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+/// Split `key: value` at the first `:` followed by a space or the end of
+/// the line, outside quotes and flow collections.
+fn split_key(text: &str) -> Option<(&str, &str)> {
+    let mut quote = None;
+    let mut depth = 0;
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    for (j, &(i, c)) in chars.iter().enumerate() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if (c == '"' || c == '\'') && i == 0 => quote = Some(c),
+            None if c == '[' || c == '{' => depth += 1,
+            None if c == ']' || c == '}' => depth -= 1,
+            None if c == ':' && depth == 0 && chars.get(j + 1).is_none_or(|(_, n)| *n == ' ') => {
+                return Some((text[..i].trim_end(), text[i + 1..].trim()));
+            }
+            None => (),
+        }
+    }
+    None
+}
+
+fn parse_block(lines: &[Line], i: &mut usize, indent: usize) -> Result<Value, String> {
+    if is_sequence_item(&lines[*i].text) {
+        parse_sequence(lines, i, indent)
+    } else {
+        parse_mapping(lines, i, indent)
+    }
+}
+
+fn parse_sequence(lines: &[Line], i: &mut usize, indent: usize) -> Result<Value, String> {
+    let mut res = Vec::new();
+    while let Some(line) = lines.get(*i) {
+        if line.indent != indent || !is_sequence_item(&line.text) {
+            break;
+        }
+        let rest = line.text[1..].trim_start();
+        *i += 1;
+        res.push(if rest.is_empty() {
+            parse_nested(lines, i, indent)?
+        } else {
+            parse_value(rest, lines, i, indent, line.number)?
+        });
+    }
+    Ok(Value::Array(res))
+}
+
+fn parse_mapping(lines: &[Line], i: &mut usize, indent: usize) -> Result<Value, String> {
+    let mut res = Map::new();
+    while let Some(line) = lines.get(*i) {
+        if line.indent != indent || is_sequence_item(&line.text) {
+            break;
+        }
+        let (key, rest) = split_key(&line.text)
+            .ok_or_else(|| format!("line {}: expected `key: value`", line.number))?;
+        let key = match parse_scalar(key)? {
+            Value::String(s) => s,
+            v => v.to_string(),
+        };
+        *i += 1;
+        let value = if rest.is_empty() {
+            match lines.get(*i) {
+                // a sequence may sit at the same indent as its key
+                Some(next) if next.indent == indent && is_sequence_item(&next.text) => {
+                    parse_sequence(lines, i, indent)?
+                }
+                _ => parse_nested(lines, i, indent)?,
+            }
+        } else {
+            parse_value(rest, lines, i, indent, line.number)?
+        };
+        if res.insert(key.clone(), value).is_some() {
+            return Err(format!("line {}: duplicate key {:?}", line.number, key));
+        }
+    }
+    Ok(Value::Object(res))
+}
+
+/// The block under a line ending in `:` or `-`, or null if there is none.
+fn parse_nested(lines: &[Line], i: &mut usize, indent: usize) -> Result<Value, String> {
+    match lines.get(*i) {
+        Some(next) if next.indent > indent => parse_block(lines, i, next.indent),
+        _ => Ok(Value::Null),
+    }
+}
+
+fn parse_value(
+    text: &str,
+    lines: &[Line],
+    i: &mut usize,
+    indent: usize,
+    number: usize,
+) -> Result<Value, String> {
+    let err = |e: String| format!("line {}: {}", number, e);
+    if let Some(style) = text.strip_prefix(['|', '>']) {
+        let folded = text.starts_with('>');
+        let mut raw = Vec::new();
+        while let Some(line) = lines.get(*i) {
+            if line.indent <= indent && !line.text.trim().is_empty() {
+                break;
+            }
+            raw.push(line.text.as_str());
+            *i += 1;
+        }
+        return Ok(Value::String(block_scalar(&raw, folded, style)));
+    }
+    if text.starts_with(['[', '{']) {
+        let chars: Vec<char> = text.chars().collect();
+        let mut pos = 0;
+        let value = parse_flow(&chars, &mut pos).map_err(err)?;
+        skip_spaces(&chars, &mut pos);
+        if pos != chars.len() {
+            return Err(err("unexpected text after flow collection".to_owned()));
+        }
+        return Ok(value);
+    }
+    parse_scalar(text).map_err(err)
+}
+
+fn block_scalar(raw: &[&str], folded: bool, chomp: &str) -> String {
+    let indent = raw
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let lines: Vec<&str> = raw
+        .iter()
+        .map(|l| if l.len() >= indent { &l[indent..] } else { "" })
+        .collect();
+    let mut text = if folded {
+        let mut res = String::new();
+        for (n, line) in lines.iter().enumerate() {
+            if n > 0 {
+                let joined = !line.is_empty() && !lines[n - 1].is_empty();
+                res.push(if joined { ' ' } else { '\n' });
+            }
+            res.push_str(line);
+        }
+        res
+    } else {
+        lines.join("\n")
+    };
+    match chomp {
+        "-" => text.truncate(text.trim_end_matches('\n').len()),
+        "+" => text.push('\n'),
+        _ => {
+            text.truncate(text.trim_end_matches('\n').len());
+            text.push('\n');
+        }
+    }
+    text
+}
+
+fn skip_spaces(chars: &[char], pos: &mut usize) {
+    while chars.get(*pos).is_some_and(|c| c.is_whitespace()) {
+        *pos += 1;
+    }
+}
+
+fn parse_flow(chars: &[char], pos: &mut usize) -> Result<Value, String> {
+    skip_spaces(chars, pos);
+    let close = match chars.get(*pos) {
+        Some('[') => ']',
+        Some('{') => '}',
+        _ => return parse_flow_scalar(chars, pos),
+    };
+    *pos += 1;
+    let mut items = Vec::new();
+    let mut entries = Map::new();
+    loop {
+        skip_spaces(chars, pos);
+        if chars.get(*pos) == Some(&close) {
+            *pos += 1;
+            break;
+        }
+        let item = parse_flow(chars, pos)?;
+        skip_spaces(chars, pos);
+        if close == '}' {
+            if chars.get(*pos) != Some(&':') {
+                return Err("expected ':' in flow mapping".to_owned());
+            }
+            *pos += 1;
+            let key = match item {
+                Value::String(s) => s,
+                v => v.to_string(),
+            };
+            entries.insert(key, parse_flow(chars, pos)?);
+            skip_spaces(chars, pos);
+        } else {
+            items.push(item);
+        }
+        match chars.get(*pos) {
+            Some(',') => *pos += 1,
+            Some(c) if *c == close => (),
+            _ => return Err(format!("expected ',' or '{}'", close)),
+        }
+    }
+    Ok(if close == ']' {
+        Value::Array(items)
+    } else {
+        Value::Object(entries)
+    })
+}
+
+fn parse_flow_scalar(chars: &[char], pos: &mut usize) -> Result<Value, String> {
+    let start = *pos;
+    if let Some(q) = chars.get(*pos).filter(|c| **c == '"' || **c == '\'') {
+        *pos += 1;
+        while *pos < chars.len() {
+            match chars[*pos] {
+                '\\' if *q == '"' => *pos += 2,
+                c if c == *q && *q == '\'' && chars.get(*pos + 1) == Some(q) => *pos += 2,
+                c if c == *q => {
+                    *pos += 1;
+                    let text: String = chars[start..*pos].iter().collect();
+                    return parse_scalar(&text);
+                }
+                _ => *pos += 1,
+            }
+        }
+        return Err("unterminated string".to_owned());
+    }
+    while *pos < chars.len()
+        && !matches!(chars[*pos], ',' | ']' | '}')
+        && !(chars[*pos] == ':' && chars.get(*pos + 1).is_none_or(|c| *c == ' '))
+    {
+        *pos += 1;
+    }
+    let text: String = chars[start..*pos].iter().collect();
+    parse_scalar(text.trim())
+}
+
+fn parse_scalar(text: &str) -> Result<Value, String> {
+    if let Some(inner) = text.strip_prefix('"') {
+        let inner = inner
+            .strip_suffix('"')
+            .ok_or_else(|| format!("unterminated string {}", text))?;
+        // YAML's double quoted escapes are a superset of JSON's
+        return serde_json::from_str(&format!("\"{}\"", inner))
+            .map_err(|e| format!("invalid string {}: {}", text, e));
+    }
+    if let Some(inner) = text.strip_prefix('\'') {
+        let inner = inner
+            .strip_suffix('\'')
+            .ok_or_else(|| format!("unterminated string {}", text))?;
+        return Ok(Value::String(inner.replace("''", "'")));
+    }
+    Ok(match text {
+        "" | "~" | "null" | "Null" | "NULL" => Value::Null,
+        "true" | "True" | "TRUE" => Value::Bool(true),
+        "false" | "False" | "FALSE" => Value::Bool(false),
+        _ => match number(text) {
+            Some(n) => Value::Number(n),
+            None => Value::String(text.to_owned()),
+        },
+    })
+}
+
+fn number(text: &str) -> Option<Number> {
+    let digits = text.strip_prefix(['-', '+']).unwrap_or(text);
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    if let Ok(i) = text.parse::<i64>() {
+        return Some(i.into());
+    }
+    if let Ok(u) = text.parse::<u64>() {
+        return Some(u.into());
+    }
+    text.parse::<f64>().ok().and_then(Number::from_f64)
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => string(s),
+        Value::Object(_) => "{}".to_owned(),
+        Value::Array(_) => "[]".to_owned(),
+        v => v.to_string(),
+    }
+}
+
+/// `s` plain where it would read back as the same string, otherwise
+/// double quoted.
+fn string(s: &str) -> String {
+    let plain = !s.is_empty()
+        && s.trim() == s
+        && !s.starts_with([
+            '-', '?', ':', ',', '[', ']', '{', '}', '#', '&', '*', '!', '|', '>', '\'', '"', '%',
+            '@', '`',
+        ])
+        && !s.contains(": ")
+        && !s.contains(" #")
+        && !s.ends_with(':')
+        && !s.contains(|c: char| c.is_control())
+        && parse_scalar(s).ok() == Some(Value::String(s.to_owned()));
+    if plain {
+        s.to_owned()
+    } else {
+        Value::String(s.to_owned()).to_string()
+    }
+}
+
+fn write_mapping(out: &mut String, m: &Map<String, Value>, indent: usize) {
+    for (k, v) in m {
+        out.push_str(&" ".repeat(indent));
+        out.push_str(&string(k));
+        out.push(':');
+        write_nested(out, v, indent);
+    }
+}
+
+fn write_sequence(out: &mut String, a: &[Value], indent: usize) {
+    for v in a {
+        out.push_str(&" ".repeat(indent));
+        out.push('-');
+        match v {
+            Value::Object(m) if !m.is_empty() => {
+                // first entry on the dash's line, the rest lined up with it
+                let mut entries = String::new();
+                write_mapping(&mut entries, m, indent + 2);
+                out.push(' ');
+                out.push_str(&entries[indent + 2..]);
+            }
+            v => write_nested(out, v, indent),
+        }
+    }
+}
+
+/// The value after `key:` or `-`: inline for scalars, on the following
+/// lines for collections.
+fn write_nested(out: &mut String, value: &Value, indent: usize) {
+    match value {
+        Value::Object(m) if !m.is_empty() => {
+            out.push('\n');
+            write_mapping(out, m, indent + 2);
+        }
+        Value::Array(a) if !a.is_empty() => {
+            out.push('\n');
+            write_sequence(out, a, indent + 2);
+        }
+        v => {
+            out.push(' ');
+            out.push_str(&scalar(v));
+            out.push('\n');
+        }
+    }
+}